@@ -0,0 +1,382 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Result};
+use nix::{errno::Errno, libc};
+use serde::{Deserialize, Serialize};
+
+/// Capability names in the order of their bit positions, as defined in `linux/capability.h`.
+static CAPABILITY_NAMES: &[&str] = &[
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/// A single Linux capability, like `CAP_NET_ADMIN`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Capability(u8);
+
+impl Capability {
+    pub fn all() -> impl Iterator<Item = Capability> {
+        (0..CAPABILITY_NAMES.len() as u8).map(Capability)
+    }
+
+    pub fn bit(&self) -> u8 {
+        self.0
+    }
+
+    pub fn name(&self) -> &'static str {
+        CAPABILITY_NAMES[self.0 as usize]
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Capability {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let upper = s.to_ascii_uppercase();
+        let name = if upper.starts_with("CAP_") {
+            upper
+        } else {
+            format!("CAP_{upper}")
+        };
+        let Some(index) = CAPABILITY_NAMES.iter().position(|c| *c == name) else {
+            bail!("Unknown capability: {s}");
+        };
+        Ok(Capability(index as u8))
+    }
+}
+
+/// A set of capabilities, stored as a bit mask like the kernel does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapabilitySet(u64);
+
+impl CapabilitySet {
+    pub fn all() -> Self {
+        Capability::all().collect()
+    }
+
+    pub fn contains(&self, cap: Capability) -> bool {
+        self.0 & (1 << cap.bit()) != 0
+    }
+
+    pub fn insert(&mut self, cap: Capability) {
+        self.0 |= 1 << cap.bit();
+    }
+
+    pub fn remove(&mut self, cap: Capability) {
+        self.0 &= !(1 << cap.bit());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Capability> + '_ {
+        Capability::all().filter(|cap| self.contains(*cap))
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn from_bits(bits: u64) -> Self {
+        CapabilitySet(bits)
+    }
+}
+
+impl FromIterator<Capability> for CapabilitySet {
+    fn from_iter<T: IntoIterator<Item = Capability>>(iter: T) -> Self {
+        let mut set = CapabilitySet::default();
+        for cap in iter {
+            set.insert(cap);
+        }
+        set
+    }
+}
+
+impl fmt::Display for CapabilitySet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.iter().map(|cap| cap.name()).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// A `--cap-add`/`--cap-drop` argument: either a single capability or `ALL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapabilityArg {
+    All,
+    Single(Capability),
+}
+
+impl CapabilityArg {
+    pub fn to_set(self) -> CapabilitySet {
+        match self {
+            CapabilityArg::All => CapabilitySet::all(),
+            CapabilityArg::Single(cap) => [cap].into_iter().collect(),
+        }
+    }
+}
+
+impl FromStr for CapabilityArg {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("all") {
+            return Ok(CapabilityArg::All);
+        }
+        Ok(CapabilityArg::Single(s.parse()?))
+    }
+}
+
+/// Computes the capabilities to remove, given `--cap-drop` and `--cap-add` arguments.
+/// Added capabilities take precedence, so `--cap-drop=ALL --cap-add=NET_BIND_SERVICE` keeps only
+/// `CAP_NET_BIND_SERVICE`.
+pub fn capabilities_to_drop(
+    drop: impl IntoIterator<Item = CapabilityArg>,
+    add: impl IntoIterator<Item = CapabilityArg>,
+) -> CapabilitySet {
+    let mut set = CapabilitySet::default();
+    for arg in drop {
+        set.0 |= arg.to_set().0;
+    }
+    for arg in add {
+        set.0 &= !arg.to_set().0;
+    }
+    set
+}
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+/// The highest capability both the running kernel and containix know.
+///
+/// `/proc/sys/kernel/cap_last_cap` isn’t available once the container’s root is entered, so
+/// this probes with `PR_CAPBSET_READ` instead, which fails with EINVAL past the last one.
+fn last_cap() -> u8 {
+    let known = (0..CAPABILITY_NAMES.len() as u8)
+        .take_while(|&bit| {
+            // SAFETY: PR_CAPBSET_READ only takes integer arguments.
+            unsafe { libc::prctl(libc::PR_CAPBSET_READ, bit as libc::c_ulong, 0, 0, 0) >= 0 }
+        })
+        .count();
+    (known as u8).saturating_sub(1)
+}
+
+/// Removes `caps` from the bounding set as well as the effective, permitted and inheritable
+/// sets of the calling process. Capabilities the running kernel doesn’t know are skipped.
+///
+/// Dropping from the bounding set ensures that the capabilities don’t come back when the
+/// process (running as root in its user namespace) calls `exec`.
+pub fn drop_capabilities(caps: CapabilitySet) -> nix::Result<()> {
+    let last_cap = last_cap();
+    // Bounding set first, as this requires CAP_SETPCAP, which might be dropped below.
+    for cap in caps.iter().filter(|cap| cap.bit() <= last_cap) {
+        // SAFETY: PR_CAPBSET_DROP only takes integer arguments.
+        let res =
+            unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap.bit() as libc::c_ulong, 0, 0, 0) };
+        Errno::result(res)?;
+    }
+
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapUserData::default(); 2];
+    // SAFETY: header and data have the layout the kernel expects for version 3.
+    let res = unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) };
+    Errno::result(res)?;
+
+    let keep = !caps.bits();
+    for (i, data) in data.iter_mut().enumerate() {
+        let keep = (keep >> (32 * i)) as u32;
+        data.effective &= keep;
+        data.permitted &= keep;
+        data.inheritable &= keep;
+    }
+    // SAFETY: see above.
+    let res = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) };
+    Errno::result(res)?;
+    Ok(())
+}
+
+/// The capability sets of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessCapabilities {
+    pub effective: CapabilitySet,
+    pub permitted: CapabilitySet,
+    pub inheritable: CapabilitySet,
+    pub bounding: CapabilitySet,
+    pub ambient: CapabilitySet,
+}
+
+impl ProcessCapabilities {
+    /// Reads the calling process’s capabilities. Unlike `/proc/self/status`, this doesn’t
+    /// need `/proc`, which the container might not have mounted.
+    pub fn current() -> nix::Result<Self> {
+        let mut header = CapUserHeader {
+            version: LINUX_CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let mut data = [CapUserData::default(); 2];
+        // SAFETY: header and data have the layout the kernel expects for version 3.
+        let res = unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) };
+        Errno::result(res)?;
+        let set = |field: fn(&CapUserData) -> u32| {
+            CapabilitySet(u64::from(field(&data[0])) | u64::from(field(&data[1])) << 32)
+        };
+
+        let last_cap = last_cap();
+        let mut bounding = CapabilitySet::default();
+        let mut ambient = CapabilitySet::default();
+        for cap in Capability::all().filter(|cap| cap.bit() <= last_cap) {
+            let bit = cap.bit() as libc::c_ulong;
+            // SAFETY: PR_CAPBSET_READ and PR_CAP_AMBIENT only take integer arguments.
+            match Errno::result(unsafe { libc::prctl(libc::PR_CAPBSET_READ, bit, 0, 0, 0) }) {
+                Ok(1) => bounding.insert(cap),
+                Ok(_) => {}
+                // The kernel doesn’t know this capability or any after it.
+                Err(Errno::EINVAL) => break,
+                Err(e) => return Err(e),
+            }
+            let res = unsafe {
+                libc::prctl(
+                    libc::PR_CAP_AMBIENT,
+                    libc::PR_CAP_AMBIENT_IS_SET as libc::c_ulong,
+                    bit,
+                    0,
+                    0,
+                )
+            };
+            // EINVAL if the kernel has no ambient capabilities, so none are set.
+            match Errno::result(res) {
+                Ok(1) => ambient.insert(cap),
+                Ok(_) | Err(Errno::EINVAL) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(ProcessCapabilities {
+            effective: set(|data| data.effective),
+            permitted: set(|data| data.permitted),
+            inheritable: set(|data| data.inheritable),
+            bounding,
+            ambient,
+        })
+    }
+
+    /// The capabilities after `exec`ing a binary without file capabilities or setuid bit,
+    /// like those in the Nix store, following `capabilities(7)`. Root gets everything in
+    /// the bounding and inheritable sets back, other users only keep their ambient set.
+    pub fn after_exec(&self, root: bool) -> Self {
+        let permitted = if root {
+            CapabilitySet(self.inheritable.0 | self.bounding.0)
+        } else {
+            self.ambient
+        };
+        ProcessCapabilities {
+            effective: permitted,
+            permitted,
+            ..*self
+        }
+    }
+}
+
+impl fmt::Display for ProcessCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sets = [
+            ("effective", self.effective),
+            ("permitted", self.permitted),
+            ("inheritable", self.inheritable),
+            ("bounding", self.bounding),
+            ("ambient", self.ambient),
+        ];
+        for (i, (name, set)) in sets.into_iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            if set.is_empty() {
+                write!(f, "{name}: none")?;
+            } else {
+                write!(f, "{name}: {set}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_cap_matches_kernel() {
+        let kernel: u8 = std::fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        assert_eq!(last_cap(), kernel.min(CAPABILITY_NAMES.len() as u8 - 1));
+    }
+
+    #[test]
+    fn reads_current_capabilities() {
+        let caps = ProcessCapabilities::current().unwrap();
+        // The effective and ambient sets are always subsets of the permitted set.
+        assert_eq!(caps.effective.bits() & !caps.permitted.bits(), 0);
+        assert_eq!(caps.ambient.bits() & !caps.permitted.bits(), 0);
+    }
+}
@@ -0,0 +1,90 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use tracing::{debug, instrument, Level};
+
+/// A cgroup v2 below the one containix runs in, to limit the container’s resources.
+///
+/// Creating it needs that cgroup to be delegated to the user, e.g. by systemd with
+/// `Delegate=yes`, and its controllers to be enabled for children. The cgroup is removed
+/// again when dropped, which only works once its processes have exited.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Creates a child cgroup called `name` with the `pids` controller enabled.
+    #[instrument(level = "trace", err(level = Level::TRACE))]
+    pub fn create(name: &str) -> Result<Self> {
+        let parent = own_cgroup()?;
+        let controllers = parent.join("cgroup.controllers");
+        let available = std::fs::read_to_string(&controllers)
+            .with_context(|| format!("Reading {}", controllers.display()))?;
+        if !available
+            .split_whitespace()
+            .any(|controller| controller == "pids")
+        {
+            bail!(
+                "The pids controller isn’t available in {}",
+                parent.display()
+            );
+        }
+        let subtree_control = parent.join("cgroup.subtree_control");
+        let enabled = std::fs::read_to_string(&subtree_control)
+            .with_context(|| format!("Reading {}", subtree_control.display()))?;
+        if !enabled
+            .split_whitespace()
+            .any(|controller| controller == "pids")
+        {
+            // Fails if the cgroup isn’t delegated or has processes of its own.
+            std::fs::write(&subtree_control, "+pids")
+                .with_context(|| format!("Enabling the pids controller in {}", parent.display()))?;
+        }
+        let path = parent.join(name);
+        std::fs::create_dir(&path).with_context(|| format!("Creating {}", path.display()))?;
+        debug!("Created cgroup {}", path.display());
+        Ok(Self { path })
+    }
+
+    /// Caps the number of processes in the cgroup.
+    pub fn set_pids_max(&self, max: u64) -> Result<()> {
+        let path = self.path.join("pids.max");
+        std::fs::write(&path, max.to_string())
+            .with_context(|| format!("Writing {}", path.display()))
+    }
+
+    /// The file a process is moved into the cgroup with, by writing its PID to it.
+    pub fn procs(&self) -> PathBuf {
+        self.path.join("cgroup.procs")
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir(&self.path) {
+            debug!("Could not remove cgroup {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// The directory of the cgroup v2 the current process is in.
+fn own_cgroup() -> Result<PathBuf> {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup").context("Reading own cgroup")?;
+    let Some(cgroup) = cgroups.lines().find_map(|line| line.strip_prefix("0::")) else {
+        bail!("containix doesn’t run in a cgroup v2 hierarchy");
+    };
+    let mounts = std::fs::read_to_string("/proc/self/mounts").context("Reading mounts")?;
+    let Some(root) = mounts.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let mount_point = fields.nth(1)?;
+        (fields.next()? == "cgroup2").then_some(mount_point)
+    }) else {
+        bail!("No cgroup v2 filesystem is mounted");
+    };
+    let cgroup = cgroup.trim_start_matches('/');
+    if cgroup.is_empty() {
+        return Ok(PathBuf::from(root));
+    }
+    Ok(Path::new(root).join(cgroup))
+}
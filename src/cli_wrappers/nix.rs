@@ -1,11 +1,65 @@
-use std::{path::PathBuf, process::Command};
+use std::{
+    path::PathBuf,
+    process::{Command, Output},
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
 
 use anyhow::{Context, Result};
 use derive_builder::Builder;
 use derive_more::derive::From;
 use serde::de::DeserializeOwned;
+use tracing::warn;
 
-use crate::command::run_command;
+use crate::{
+    command::{run_command, CommandFailed},
+    spinner::Spinner,
+};
+
+static RETRIES: AtomicU32 = AtomicU32::new(0);
+
+/// Parts of nix’s stderr that hint at a network hiccup rather than a broken flake. These are
+/// the curl and git errors nix reports when fetching fails, and the server errors it reports
+/// for downloads.
+const TRANSIENT_ERRORS: &[&str] = &[
+    "Timeout was reached",
+    "Couldn't resolve host name",
+    "Could not resolve host",
+    "Failed to connect to",
+    "Connection reset by peer",
+    "HTTP error 5",
+];
+
+/// Sets how often a nix invocation is retried when it failed to fetch something.
+pub fn set_retries(retries: u32) {
+    RETRIES.store(retries, Ordering::SeqCst);
+}
+
+/// Runs the command built by `command` and retries with backoff if it failed with what looks
+/// like a transient network error. Each attempt runs a freshly built command.
+fn run_nix(command: impl Fn() -> Command) -> Result<Output> {
+    let retries = RETRIES.load(Ordering::SeqCst);
+    let mut attempt = 0;
+    loop {
+        match run_command(command()) {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                let backoff = Duration::from_secs(1 << attempt.min(5));
+                attempt += 1;
+                warn!("Nix failed to fetch, retrying in {backoff:?} ({attempt}/{retries})");
+                std::thread::sleep(backoff);
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<CommandFailed>().is_some_and(|failed| {
+        TRANSIENT_ERRORS
+            .iter()
+            .any(|pattern| failed.stderr.contains(pattern))
+    })
+}
 
 #[derive(Debug, Clone, Default, From)]
 pub enum FlakeOutputSymlink {
@@ -31,6 +85,24 @@ pub struct NixBuildInvocation {
     symlink: FlakeOutputSymlink,
     #[builder(default)]
     refresh: bool,
+    #[builder(default)]
+    impure: bool,
+    /// Flake inputs to replace, as pairs of input name and flake reference.
+    #[builder(setter(custom), default)]
+    override_input: Vec<(String, String)>,
+    /// Maximum number of derivations built in parallel. Nix’s default if unset.
+    #[builder(default)]
+    max_jobs: Option<u32>,
+    /// Cores each build job may use. Nix’s default if unset.
+    #[builder(default)]
+    cores: Option<u32>,
+    /// Phase to show next to a spinner while nix is running.
+    #[builder(setter(into, strip_option), default)]
+    progress: Option<String>,
+    /// Arguments passed to nix verbatim after all of the above, for options containix
+    /// doesn’t model.
+    #[builder(setter(custom), default)]
+    extra_arg: Vec<String>,
 }
 
 impl NixBuild {
@@ -41,44 +113,90 @@ impl NixBuild {
         self
     }
 
+    pub fn override_input(&mut self, input: impl AsRef<str>, flake: impl AsRef<str>) -> &mut Self {
+        self.override_input
+            .get_or_insert_with(std::vec::Vec::new)
+            .push((input.as_ref().to_string(), flake.as_ref().to_string()));
+        self
+    }
+
+    pub fn extra_arg(&mut self, arg: impl ToString) -> &mut Self {
+        self.extra_arg
+            .get_or_insert_with(std::vec::Vec::new)
+            .push(arg.to_string());
+        self
+    }
+
+    pub fn extra_args(&mut self, args: impl IntoIterator<Item = impl ToString>) -> &mut Self {
+        for arg in args {
+            self.extra_arg(arg);
+        }
+        self
+    }
+
     pub fn run<I: DeserializeOwned>(self) -> Result<I> {
         let invocation = self.finish()?;
 
-        let mut cmd = Command::new("nix");
-        cmd.args(&invocation.arg);
+        let command = || {
+            let mut cmd = Command::new("nix");
+            cmd.args(&invocation.arg);
 
-        if invocation.json {
-            cmd.arg("--json");
-        }
+            if invocation.json {
+                cmd.arg("--json");
+            }
 
-        if invocation.refresh {
-            cmd.arg("--refresh");
-        }
+            if invocation.refresh {
+                cmd.arg("--refresh");
+            }
 
-        if let Some(lock_file) = &invocation.lock_file {
-            cmd.arg("--reference-lock-file")
-                .arg(lock_file)
-                .arg("--output-lock-file")
-                .arg(lock_file);
-        } else {
-            cmd.arg("--no-write-lock-file");
-        }
+            if invocation.impure {
+                cmd.arg("--impure");
+            }
 
-        if invocation.quiet {
-            cmd.arg("--quiet");
-        }
+            for (input, flake) in &invocation.override_input {
+                cmd.arg("--override-input").arg(input).arg(flake);
+            }
 
-        match invocation.symlink {
-            FlakeOutputSymlink::None => {
-                cmd.arg("--no-link");
+            if let Some(max_jobs) = invocation.max_jobs {
+                cmd.arg("--max-jobs").arg(max_jobs.to_string());
             }
-            FlakeOutputSymlink::Custom(symlink) => {
-                cmd.arg("--out-link").arg(symlink);
+
+            if let Some(cores) = invocation.cores {
+                cmd.arg("--cores").arg(cores.to_string());
             }
-            FlakeOutputSymlink::Default => {}
-        }
 
-        let output = run_command(cmd).context("Running nix command")?;
+            if let Some(lock_file) = &invocation.lock_file {
+                cmd.arg("--reference-lock-file")
+                    .arg(lock_file)
+                    .arg("--output-lock-file")
+                    .arg(lock_file);
+            } else {
+                cmd.arg("--no-write-lock-file");
+            }
+
+            if invocation.quiet {
+                cmd.arg("--quiet");
+            }
+
+            match &invocation.symlink {
+                FlakeOutputSymlink::None => {
+                    cmd.arg("--no-link");
+                }
+                FlakeOutputSymlink::Custom(symlink) => {
+                    cmd.arg("--out-link").arg(symlink);
+                }
+                FlakeOutputSymlink::Default => {}
+            }
+
+            // Last, so they can override containix’s own flags where nix allows it.
+            cmd.args(&invocation.extra_arg);
+            cmd
+        };
+
+        let output = {
+            let _spinner = invocation.progress.map(Spinner::start);
+            run_nix(command).context("Running nix command")?
+        };
         let output = serde_json::from_str(&String::from_utf8(output.stdout)?)
             .context("Parsing nix output")?;
         Ok(output)
@@ -93,30 +211,84 @@ pub struct NixEvalInvocation {
     impure: bool,
     #[builder(default)]
     json: bool,
-    #[builder(setter(into))]
-    expression: String,
+    /// Expression to evaluate with `--expr`.
+    #[builder(setter(into, strip_option), default)]
+    expression: Option<String>,
+    /// Flake attribute to evaluate instead of an expression, e.g. `.#packages.x86_64-linux.default`.
+    #[builder(setter(into, strip_option), default)]
+    installable: Option<String>,
+    #[builder(setter(into, strip_option), default)]
+    lock_file: Option<PathBuf>,
 }
 
 impl NixEval {
-    pub fn run<I: DeserializeOwned>(self) -> Result<I> {
-        let invocation = self.finish()?;
+    pub fn run<I: DeserializeOwned>(mut self) -> Result<I> {
+        self.json(true);
+        let output = self.output()?;
+        let output = serde_json::from_str(&output).context("Parsing nix output")?;
+        Ok(output)
+    }
 
-        let mut cmd = Command::new("nix");
-        cmd.arg("eval");
+    /// Runs the evaluation and returns what nix printed, as JSON or in nix syntax.
+    pub fn output(self) -> Result<String> {
+        let invocation = self.finish()?;
 
-        if invocation.json {
-            cmd.arg("--json");
+        if invocation.installable.is_some() == invocation.expression.is_some() {
+            anyhow::bail!("Nix eval needs either an expression or an installable");
         }
+        let command = || {
+            let mut cmd = Command::new("nix");
+            cmd.arg("eval");
 
-        if invocation.impure {
-            cmd.arg("--impure");
-        }
+            if invocation.json {
+                cmd.arg("--json");
+            }
 
-        cmd.arg("--expr").arg(&invocation.expression);
+            if invocation.impure {
+                cmd.arg("--impure");
+            }
 
-        let output = run_command(cmd).context("Running nix command")?;
-        let output = serde_json::from_str(&String::from_utf8(output.stdout)?)
-            .context("Parsing nix output")?;
-        Ok(output)
+            if let Some(lock_file) = &invocation.lock_file {
+                cmd.arg("--reference-lock-file")
+                    .arg(lock_file)
+                    .arg("--output-lock-file")
+                    .arg(lock_file);
+            }
+
+            if let Some(installable) = &invocation.installable {
+                cmd.arg(installable);
+            } else if let Some(expression) = &invocation.expression {
+                cmd.arg("--expr").arg(expression);
+            }
+            cmd
+        };
+
+        let output = run_nix(command).context("Running nix command")?;
+        Ok(String::from_utf8(output.stdout)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_only_fetch_errors() {
+        let cases = [
+            ("error: unable to download 'https://github.com/x': Timeout was reached (28)", true),
+            ("error: unable to download 'https://cache.nixos.org/x': Couldn't resolve host name (6)", true),
+            ("fatal: unable to access 'https://github.com/x/': Could not resolve host: github.com", true),
+            ("error: unable to download 'https://github.com/x': HTTP error 503", true),
+            ("error: unable to download 'https://github.com/x': HTTP error 404", false),
+            ("error: builder for '/nix/store/x.drv' failed: test timed out", false),
+            ("error: flake 'path:/src' does not provide attribute 'packages.x86_64-linux.default'", false),
+        ];
+        for (stderr, transient) in cases {
+            let err = anyhow::Error::new(CommandFailed {
+                command: "nix build".to_string(),
+                stderr: stderr.to_string(),
+            });
+            assert_eq!(is_transient(&err), transient, "{stderr}");
+        }
     }
 }
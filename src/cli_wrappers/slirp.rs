@@ -1,19 +1,34 @@
 use std::{
+    collections::VecDeque,
     fs::File,
-    io::{Read, Write},
-    os::{fd::AsRawFd, unix::net::UnixStream},
+    io::{BufRead, BufReader, Read, Write},
+    net::IpAddr,
+    os::{
+        fd::{AsRawFd, OwnedFd, RawFd},
+        unix::{fs::OpenOptionsExt, net::UnixStream, process::CommandExt},
+    },
     path::{Path, PathBuf},
-    process::{Command, Stdio},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
 };
 
 use anyhow::{Context, Result};
 use derive_builder::Builder;
-use serde::Serialize;
-use tracing::{error, instrument, trace, Level};
+use nix::fcntl::{FcntlArg, FdFlag, OFlag};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, instrument, trace, warn, Level};
 
-use crate::{command::ChildProcess, ports::PortMapping};
+use crate::{
+    command::ChildProcess,
+    ports::{PortMapping, Protocol},
+};
 
-#[derive(Debug, Builder)]
+#[derive(Debug, Clone, Builder)]
 #[builder(build_fn(name = finish, vis = ""))]
 #[builder(name = "Slirp")]
 pub struct SlirpInvocation {
@@ -27,8 +42,27 @@ pub struct SlirpInvocation {
     ports: Vec<PortMapping>,
     #[builder(default = r#""tap0".into()"#)]
     device_name: String,
+    /// Restart slirp if it exits while the container is still running.
+    #[builder(default)]
+    restart: bool,
+    /// Give the container IPv6 connectivity. Needed to forward ports from IPv6 host addresses.
+    #[builder(default)]
+    enable_ipv6: bool,
+    /// Network namespace file to join instead of the one of `pid`, along with the user
+    /// namespace that owns it. Unlike the PID, it stays valid when the process is replaced.
+    #[builder(default, setter(into, strip_option))]
+    netns_path: Option<PathBuf>,
+    #[builder(default, setter(into, strip_option))]
+    userns_path: Option<PathBuf>,
+    /// The socket’s directory, opened if the socket path is too long to bind or connect to.
+    /// The socket is then addressed through the descriptor, which slirp inherits.
+    #[builder(setter(skip))]
+    socket_dir: Option<Arc<OwnedFd>>,
 }
 
+/// Longest path a Unix socket can be bound to: `sun_path` without the terminating NUL.
+const MAX_SOCKET_PATH: usize = 107;
+
 impl Slirp {
     pub fn port(&mut self, port_mapping: PortMapping) -> &mut Self {
         self.ports.get_or_insert_with(Vec::new).push(port_mapping);
@@ -36,32 +70,291 @@ impl Slirp {
     }
 
     #[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
-    pub fn activate(&mut self) -> Result<impl ChildProcess> {
-        let invocation = self.finish()?;
-
-        let (rx, tx) = nix::unistd::pipe().context("Creating ready signal pipe for slirp")?;
-        let mut c = Command::new(invocation.binary);
-        c.arg("-c")
-            .arg(invocation.pid.to_string())
-            .arg(invocation.device_name)
+    pub fn activate(&mut self) -> Result<SlirpHandle> {
+        let mut invocation = self.finish()?;
+        invocation.shorten_socket_path()?;
+        let child = invocation.spawn()?;
+
+        let handle = SlirpHandle {
+            child: Arc::new(Mutex::new(child)),
+            state: Default::default(),
+            socket: invocation.socket.clone(),
+            _socket_dir: invocation.socket_dir.clone(),
+        };
+        let supervisor = handle.clone();
+        std::thread::spawn(move || supervisor.supervise(invocation));
+        Ok(handle)
+    }
+}
+
+impl SlirpInvocation {
+    /// Unix socket paths are limited to [`MAX_SOCKET_PATH`] bytes, which a deep `$TMPDIR` can
+    /// exceed. slirp only binds its API socket to a path, not an abstract address, so a long
+    /// path is replaced with one through `/proc/self/fd`. It resolves the same in slirp, as
+    /// the descriptor keeps its number when it is inherited.
+    fn shorten_socket_path(&mut self) -> Result<()> {
+        if self.socket.as_os_str().len() <= MAX_SOCKET_PATH {
+            return Ok(());
+        }
+        let (Some(dir), Some(name)) = (self.socket.parent(), self.socket.file_name()) else {
+            anyhow::bail!("Invalid slirp socket path {}", self.socket.display());
+        };
+        let dir = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(nix::libc::O_PATH | nix::libc::O_DIRECTORY)
+            .open(dir)
+            .with_context(|| format!("Opening {}", dir.display()))?;
+        let dir = OwnedFd::from(dir);
+        let socket = Path::new("/proc/self/fd")
+            .join(dir.as_raw_fd().to_string())
+            .join(name);
+        debug!(
+            "Slirp socket path {} is too long, using {}",
+            self.socket.display(),
+            socket.display()
+        );
+        self.socket = socket;
+        self.socket_dir = Some(Arc::new(dir));
+        Ok(())
+    }
+
+    /// The slirp command line, which signals readiness on `ready_fd`.
+    fn command(&self, ready_fd: RawFd) -> Command {
+        let mut c = Command::new(&self.binary);
+        c.arg("-c");
+        match &self.netns_path {
+            Some(netns_path) => {
+                c.arg("--netns-type").arg("path");
+                if let Some(userns_path) = &self.userns_path {
+                    c.arg("--userns-path").arg(userns_path);
+                }
+                c.arg(netns_path);
+            }
+            None => {
+                c.arg(self.pid.to_string());
+            }
+        }
+        c.arg(&self.device_name)
             .arg("--api-socket")
-            .arg(&invocation.socket)
+            .arg(&self.socket)
             .arg("--ready-fd")
-            .arg(tx.as_raw_fd().to_string())
-            .stdin(Stdio::null())
+            .arg(ready_fd.to_string())
+            .args(self.enable_ipv6.then_some("--enable-ipv6"));
+        c
+    }
+
+    /// Spawns slirp, waits for it to be ready and sets up the port forwards.
+    fn spawn(&self) -> Result<Child> {
+        let (rx, tx) =
+            nix::unistd::pipe2(OFlag::O_CLOEXEC).context("Creating ready signal pipe for slirp")?;
+        let ready_fd = tx.as_raw_fd();
+        let socket_dir_fd = self.socket_dir.as_ref().map(|dir| dir.as_raw_fd());
+        let mut c = self.command(ready_fd);
+        c.stdin(Stdio::null())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped());
+        // The write end must only be inherited by slirp. If any other process kept it open,
+        // we couldn’t notice slirp dying before it signaled readiness.
+        // SAFETY: fcntl is async-signal-safe.
+        unsafe {
+            c.pre_exec(move || {
+                nix::fcntl::fcntl(ready_fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+                if let Some(socket_dir_fd) = socket_dir_fd {
+                    nix::fcntl::fcntl(socket_dir_fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+                }
+                Ok(())
+            });
+        }
 
-        let c = c.spawn().context("Spawning slirp")?;
+        let mut c = c.spawn().context("Spawning slirp")?;
+        drop(tx);
         trace!("Slirp spawned with PID {}", c.pid());
-        std::thread::spawn(move || {
-            if let Err(e) =
-                intialize_with_ports(File::from(rx), invocation.socket, &invocation.ports)
+        // Nothing else reads these pipes, so slirp would block once they are full.
+        if let Some(stdout) = c.stdout.take() {
+            forward_output(stdout, "stdout");
+        }
+        let stderr = c
+            .stderr
+            .take()
+            .map(|stderr| forward_output(stderr, "stderr"));
+
+        if let Err(e) = wait_for_slirp_ready(File::from(rx)) {
+            _ = c.kill();
+            let status = c.wait().context("Waiting for slirp to exit")?;
+            let stderr = stderr
+                .and_then(|handle| handle.join().ok())
+                .unwrap_or_default()
+                .join("\n");
+            return Err(e).context(format!("slirp failed to start ({status}): {stderr}"));
+        }
+
+        if let Err(e) = self.expose_ports() {
+            // Otherwise slirp would keep running without anyone to stop it.
+            _ = c.kill();
+            c.wait().context("Waiting for slirp to exit")?;
+            return Err(e);
+        }
+        Ok(c)
+    }
+
+    fn expose_ports(&self) -> Result<()> {
+        for port in &self.ports {
+            for (protocol, host_port, container_port) in port.forwards() {
+                expose_port(
+                    &self.socket,
+                    protocol,
+                    port.host_ip,
+                    host_port,
+                    container_port,
+                )
+                .with_context(|| format!("Exposing port {port}"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Forwards everything slirp writes to `stream` into tracing. The returned handle yields the
+/// last few lines once the stream is closed.
+fn forward_output(
+    stream: impl Read + Send + 'static,
+    name: &'static str,
+) -> JoinHandle<Vec<String>> {
+    const KEEP_LINES: usize = 20;
+    std::thread::spawn(move || {
+        let mut recent = VecDeque::with_capacity(KEEP_LINES);
+        for line in BufReader::new(stream).lines() {
+            match line {
+                Ok(line) => {
+                    debug!(target: "slirp", stream = name, "{line}");
+                    if recent.len() == KEEP_LINES {
+                        recent.pop_front();
+                    }
+                    recent.push_back(line);
+                }
+                Err(e) => {
+                    trace!("Stopped reading slirp {name}: {e}");
+                    break;
+                }
+            }
+        }
+        recent.into()
+    })
+}
+
+#[derive(Debug, Default)]
+struct SlirpState {
+    /// Set when slirp is being shut down intentionally.
+    stopping: AtomicBool,
+    /// Set when slirp died unexpectedly and was not (or could not be) restarted.
+    unhealthy: AtomicBool,
+    exit_status: Mutex<Option<Option<i32>>>,
+}
+
+/// A slirp process that is watched by a supervisor thread.
+///
+/// The supervisor is the only place that reaps the slirp process. If slirp exits
+/// without being killed through this handle, the network is marked unhealthy or,
+/// if requested, slirp is restarted and all port forwards are registered again.
+#[derive(Debug, Clone)]
+pub struct SlirpHandle {
+    child: Arc<Mutex<Child>>,
+    state: Arc<SlirpState>,
+    socket: PathBuf,
+    /// Keeps a shortened socket path valid.
+    _socket_dir: Option<Arc<OwnedFd>>,
+}
+
+impl SlirpHandle {
+    const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+    /// Whether slirp is still providing networking for the container.
+    pub fn is_healthy(&self) -> bool {
+        !self.state.unhealthy.load(Ordering::SeqCst)
+    }
+
+    /// Stops forwarding `host_port` into the container.
+    pub fn remove_port(&self, host_port: u16, proto: Protocol) -> Result<()> {
+        remove_port(&self.socket, host_port, proto)
+    }
+
+    fn supervise(self, invocation: SlirpInvocation) {
+        loop {
+            std::thread::sleep(Self::POLL_INTERVAL);
+            let mut child = self.child.lock().expect("Slirp child lock poisoned");
+            let status = match child.try_wait() {
+                Ok(None) => continue,
+                Ok(Some(status)) => status,
+                Err(e) => {
+                    error!("Failed to query slirp status: {e}");
+                    continue;
+                }
+            };
+
+            if self.state.stopping.load(Ordering::SeqCst) {
+                self.finish(status.code());
+                return;
+            }
+
+            error!("Slirp exited unexpectedly ({status}), container networking is down");
+            if !invocation.restart {
+                self.state.unhealthy.store(true, Ordering::SeqCst);
+                self.finish(status.code());
+                return;
+            }
+
+            // slirp refuses to start if its API socket is still lying around.
+            _ = std::fs::remove_file(&invocation.socket);
+            match invocation.spawn() {
+                Ok(new_child) => {
+                    info!("Restarted slirp with PID {}", new_child.id());
+                    *child = new_child;
+                }
+                Err(e) => {
+                    error!("Failed to restart slirp: {e:#}");
+                    self.state.unhealthy.store(true, Ordering::SeqCst);
+                    self.finish(status.code());
+                    return;
+                }
+            }
+        }
+    }
+
+    fn finish(&self, code: Option<i32>) {
+        *self
+            .state
+            .exit_status
+            .lock()
+            .expect("Slirp state lock poisoned") = Some(code);
+    }
+}
+
+impl ChildProcess for SlirpHandle {
+    fn wait(&mut self) -> Result<Option<i32>> {
+        loop {
+            if let Some(code) = *self
+                .state
+                .exit_status
+                .lock()
+                .expect("Slirp state lock poisoned")
             {
-                error!("Error initializing slirp: {e}");
+                return Ok(code);
             }
-        });
-        Ok(c)
+            std::thread::sleep(Self::POLL_INTERVAL);
+        }
+    }
+
+    fn kill(&mut self) -> Result<()> {
+        self.state.stopping.store(true, Ordering::SeqCst);
+        let mut child = self.child.lock().expect("Slirp child lock poisoned");
+        if let Err(e) = child.kill() {
+            warn!("Failed to kill slirp: {e}");
+        }
+        Ok(())
+    }
+
+    fn pid(&self) -> u32 {
+        self.child.lock().expect("Slirp child lock poisoned").id()
     }
 }
 
@@ -78,40 +371,208 @@ struct SlirpExposePortCommand {
     guest_addr: String,
     guest_port: u16,
 }
-fn expose_port(socket: impl AsRef<Path>, host_port: u16, guest_port: u16) -> Result<()> {
-    let mut stream = UnixStream::connect(socket.as_ref()).context("Connecting to slirp socket")?;
+fn expose_port(
+    socket: impl AsRef<Path>,
+    proto: Protocol,
+    host_addr: Option<IpAddr>,
+    host_port: u16,
+    guest_port: u16,
+) -> Result<()> {
     let command = SlirpCommand {
         execute: "add_hostfwd".to_string(),
         arguments: SlirpExposePortCommand {
-            proto: "tcp".to_string(),
-            host_addr: "0.0.0.0".to_string(),
-            guest_addr: "10.0.2.100".to_string(),
+            proto: proto.to_string(),
+            host_addr: host_addr
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|| "0.0.0.0".to_string()),
+            guest_addr: match host_addr {
+                Some(IpAddr::V6(_)) => "fd00::100",
+                _ => "10.0.2.100",
+            }
+            .to_string(),
             host_port,
             guest_port,
         },
     };
+    // Waits for the response, so a port that can’t be bound fails here.
+    let _: serde_json::Value = execute(socket, &command)?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct SlirpRemovePortCommand {
+    id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SlirpResponse<T> {
+    #[serde(rename = "return")]
+    ret: Option<T>,
+    error: Option<SlirpErrorResponse>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SlirpErrorResponse {
+    desc: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SlirpPortList {
+    entries: Vec<SlirpPortEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SlirpPortEntry {
+    id: u64,
+    proto: String,
+    host_port: u16,
+}
+
+/// Sends a command to slirp and waits for its response.
+fn execute<T: Serialize, R: for<'de> Deserialize<'de>>(
+    socket: impl AsRef<Path>,
+    command: &SlirpCommand<T>,
+) -> Result<R> {
+    let mut stream = UnixStream::connect(socket.as_ref()).context("Connecting to slirp socket")?;
     // Commands must be sent in one packet, so do NOT use `to_writer` here.
-    let cmd = serde_json::to_string(&command).context("Serializing slirp command")?;
+    let cmd = serde_json::to_string(command).context("Serializing slirp command")?;
     stream
         .write_all(cmd.as_bytes())
         .context("Sending slirp command")?;
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .context("Reading slirp response")?;
+    let response: SlirpResponse<R> =
+        serde_json::from_str(&response).context("Parsing slirp response")?;
+    if let Some(error) = response.error {
+        anyhow::bail!("slirp `{}` failed: {}", command.execute, error.desc);
+    }
+    response
+        .ret
+        .ok_or_else(|| anyhow::anyhow!("slirp `{}` returned nothing", command.execute))
+}
+
+/// Stops forwarding `host_port` for the given protocol.
+#[instrument(level = "trace", skip_all, fields(host_port, proto), err(level = Level::TRACE))]
+pub fn remove_port(socket: impl AsRef<Path>, host_port: u16, proto: Protocol) -> Result<()> {
+    let list: SlirpPortList = execute(
+        &socket,
+        &SlirpCommand {
+            execute: "list_hostfwd".to_string(),
+            arguments: serde_json::Value::Object(Default::default()),
+        },
+    )
+    .context("Listing forwarded ports")?;
+
+    let Some(entry) = list
+        .entries
+        .iter()
+        .find(|entry| entry.host_port == host_port && entry.proto == proto.to_string())
+    else {
+        anyhow::bail!("Host port {host_port}/{proto} is not forwarded");
+    };
+    debug!(
+        "Removing slirp forward {} for {host_port}/{proto}",
+        entry.id
+    );
+
+    let _: serde_json::Value = execute(
+        &socket,
+        &SlirpCommand {
+            execute: "remove_hostfwd".to_string(),
+            arguments: SlirpRemovePortCommand { id: entry.id },
+        },
+    )
+    .context("Removing forwarded port")?;
     Ok(())
 }
 
+/// Waits for slirp to write to its ready fd. Fails if slirp closes the fd (by exiting) first.
 fn wait_for_slirp_ready(mut signal: impl Read) -> Result<()> {
     let mut buf = [0; 1];
-    while let Ok(0) = signal.read(&mut buf) {}
-    Ok(())
+    loop {
+        match signal.read(&mut buf) {
+            Ok(0) => anyhow::bail!("slirp exited before signaling readiness"),
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context("Reading slirp ready fd"),
+        }
+    }
 }
 
-fn intialize_with_ports<'a>(
-    signal: impl Read,
-    socket: impl AsRef<Path>,
-    ports: impl IntoIterator<Item = &'a PortMapping>,
-) -> Result<()> {
-    wait_for_slirp_ready(signal).context("Waiting for slirp to initialize")?;
-    for port in ports.into_iter() {
-        expose_port(&socket, port.host_port, port.container_port).context("Exposing ports")?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(slirp: &mut Slirp) -> Vec<String> {
+        slirp
+            .finish()
+            .unwrap()
+            .command(7)
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn slirp() -> Slirp {
+        let mut slirp = Slirp::default();
+        slirp
+            .binary("/bin/slirp4netns")
+            .pid(1234u32)
+            .socket("/tmp/containix/slirp.sock");
+        slirp
+    }
+
+    #[test]
+    fn joins_namespaces_of_pid() {
+        let mut slirp = slirp();
+        assert_eq!(
+            slirp.finish().unwrap().command(7).get_program(),
+            "/bin/slirp4netns"
+        );
+        assert_eq!(
+            args(&mut slirp),
+            [
+                "-c",
+                "1234",
+                "tap0",
+                "--api-socket",
+                "/tmp/containix/slirp.sock",
+                "--ready-fd",
+                "7"
+            ]
+        );
+    }
+
+    #[test]
+    fn joins_kept_namespaces_by_path() {
+        let mut slirp = slirp();
+        slirp
+            .netns_path("/tmp/containix/namespaces/net")
+            .userns_path("/tmp/containix/namespaces/user");
+        assert_eq!(
+            args(&mut slirp),
+            [
+                "-c",
+                "--netns-type",
+                "path",
+                "--userns-path",
+                "/tmp/containix/namespaces/user",
+                "/tmp/containix/namespaces/net",
+                "tap0",
+                "--api-socket",
+                "/tmp/containix/slirp.sock",
+                "--ready-fd",
+                "7"
+            ]
+        );
+    }
+
+    #[test]
+    fn enables_ipv6() {
+        let mut slirp = slirp();
+        slirp.enable_ipv6(true);
+        assert_eq!(args(&mut slirp).last().unwrap(), "--enable-ipv6");
     }
-    Ok(())
 }
@@ -1,12 +1,14 @@
 use std::{
+    cell::RefCell,
     ffi::OsStr,
     path::PathBuf,
     process::{Command, Output},
+    sync::Arc,
 };
 
 use anyhow::Result;
 use derive_more::derive::Deref;
-use tracing::{error, instrument, trace};
+use tracing::{debug, instrument, trace};
 
 pub fn resolve_command(command: impl AsRef<OsStr>) -> PathBuf {
     let command = command.as_ref();
@@ -23,10 +25,56 @@ pub fn resolve_command(command: impl AsRef<OsStr>) -> PathBuf {
     command.into()
 }
 
+/// A command that ran but exited unsuccessfully. Its stderr is part of the message, so callers
+/// that expect a failure can handle it without it being logged.
+#[derive(Debug, thiserror::Error)]
+#[error("Command {command} failed: {}", stderr.trim_end())]
+pub struct CommandFailed {
+    pub command: String,
+    pub stderr: String,
+}
+
+/// Runs the commands containix shells out to, like `nix` and `nix-store`.
+///
+/// The default runs them on the host. Tests can swap it out with [`set_command_runner`],
+/// e.g. for one that returns canned output or runs fake binaries instead. The runner is set
+/// per thread, so tests running in parallel don’t replace each other’s.
+pub trait CommandRunner: Send + Sync {
+    fn run(&self, command: Command) -> Result<Output>;
+}
+
+impl<F> CommandRunner for F
+where
+    F: Fn(Command) -> Result<Output> + Send + Sync,
+{
+    fn run(&self, command: Command) -> Result<Output> {
+        self(command)
+    }
+}
+
+thread_local! {
+    static COMMAND_RUNNER: RefCell<Option<Arc<dyn CommandRunner>>> = const { RefCell::new(None) };
+}
+
+/// Replaces the runner used by [`run_command`] on the current thread. `None` restores running
+/// commands on the host.
+pub fn set_command_runner(runner: Option<Arc<dyn CommandRunner>>) {
+    COMMAND_RUNNER.with(|current| *current.borrow_mut() = runner);
+}
+
 #[instrument(level = "trace", fields(
     current_dir = %command.get_current_dir().map(|v| v.to_path_buf()).or_else(|| std::env::current_dir().ok()).unwrap_or_else(|| "<unknown>".into()).display()
 ), ret)]
 pub fn run_command(command: Command) -> Result<Output> {
+    let runner = COMMAND_RUNNER.with(|runner| runner.borrow().clone());
+    match runner {
+        Some(runner) => runner.run(command),
+        None => run_on_host(command),
+    }
+}
+
+/// Runs `command` on the host and fails if it exits unsuccessfully.
+pub fn run_on_host(command: Command) -> Result<Output> {
     // This is a dirty hack.
     // For some reason, std::process::Command is not actually respecting $PATH
     // so I currently have to re-implement it.
@@ -43,42 +91,96 @@ pub fn run_command(command: Command) -> Result<Output> {
     if !output.status.success() {
         let stderr = String::from_utf8(output.stderr)
             .unwrap_or_else(|_| "<Invalid UTF-8 on stderr>".to_string());
-        error!("Command {command:?} failed: {stderr}");
-        anyhow::bail!("Command {command:?} failed");
+        debug!("Command {command:?} failed: {stderr}");
+        return Err(CommandFailed {
+            command: format!("{command:?}"),
+            stderr,
+        }
+        .into());
     }
     Ok(output)
 }
 
+/// A process containix started and is responsible for reaping.
+///
+/// Each child is only ever reaped through its own [`wait`](ChildProcess::wait), which waits
+/// for that PID specifically. That way the container and slirp, which is reaped by its
+/// supervisor thread, can’t collect each other’s exit status.
 pub trait ChildProcess {
+    /// Waits for the process to exit and returns its exit code, or `None` if it was killed
+    /// by a signal. Once it has exited, this keeps returning the same status.
     fn wait(&mut self) -> Result<Option<i32>>;
+    /// Asks the process to terminate. Does nothing once it has been reaped.
     fn kill(&mut self) -> Result<()>;
     fn pid(&self) -> u32;
 }
 
 #[derive(Debug, Deref)]
-pub struct NixUnistdChild(nix::unistd::Pid);
+pub struct NixUnistdChild {
+    #[deref]
+    pid: nix::unistd::Pid,
+    /// Set once the process has been reaped. Its PID might belong to another process by then.
+    status: Option<Option<i32>>,
+    /// The signal that killed the process, if any.
+    signal: Option<nix::sys::signal::Signal>,
+}
+
+impl NixUnistdChild {
+    /// The exit status a shell would report once the process has been reaped: its exit code,
+    /// or 128 + the signal number if it was killed by a signal.
+    pub fn exit_status(&self) -> Option<i32> {
+        match self.status? {
+            Some(code) => Some(code),
+            None => self.signal.map(|signal| 128 + signal as i32),
+        }
+    }
+}
 
 impl ChildProcess for NixUnistdChild {
     fn wait(&mut self) -> Result<Option<i32>> {
-        match nix::sys::wait::waitpid(self.0, None)? {
-            nix::sys::wait::WaitStatus::Exited(_, status) => Ok(Some(status)),
-            _ => Ok(None),
+        use nix::sys::wait::{waitpid, WaitStatus};
+
+        if let Some(status) = self.status {
+            return Ok(status);
         }
+        let status = loop {
+            match waitpid(self.pid, None) {
+                Ok(WaitStatus::Exited(_, code)) => break Some(code),
+                Ok(WaitStatus::Signaled(_, signal, _)) => {
+                    self.signal = Some(signal);
+                    break None;
+                }
+                // Stops and continues are only reported with WUNTRACED or WCONTINUED, which
+                // aren’t passed, but they don’t end the process either way.
+                Ok(_) => continue,
+                // A signal handler, e.g. one forwarding signals to the container, ran.
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        self.status = Some(status);
+        Ok(status)
     }
 
     fn kill(&mut self) -> Result<()> {
-        _ = nix::sys::signal::kill(self.0, nix::sys::signal::Signal::SIGTERM);
+        if self.status.is_none() {
+            _ = nix::sys::signal::kill(self.pid, nix::sys::signal::Signal::SIGTERM);
+        }
         Ok(())
     }
 
     fn pid(&self) -> u32 {
-        self.0.as_raw().try_into().unwrap()
+        self.pid.as_raw().try_into().unwrap()
     }
 }
 
 impl From<nix::unistd::Pid> for NixUnistdChild {
     fn from(pid: nix::unistd::Pid) -> Self {
-        Self(pid)
+        Self {
+            pid,
+            status: None,
+            signal: None,
+        }
     }
 }
 
@@ -1,30 +1,64 @@
 use crate::{ports::PortMapping, tempdir::TempDir};
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use derive_builder::Builder;
 use derive_more::derive::{Deref, DerefMut};
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    net::if_::InterfaceFlags,
+    sys::socket::{AddressFamily, SockFlag, SockType},
+    sys::stat::Mode,
+    unistd::{Gid, Pid},
+};
+use serde::{Deserialize, Serialize};
 use tracing::{error, instrument, trace, warn, Level};
 
 use std::{
+    collections::HashMap,
     ffi::OsStr,
+    io::Write,
+    net::IpAddr,
     ops::Deref,
-    os::unix::process::CommandExt,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{
+            fs::{FileTypeExt, MetadataExt},
+            process::CommandExt,
+        },
+    },
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Output},
+    sync::Arc,
+    time::Instant,
 };
 
 use crate::{
-    cli_wrappers::slirp::Slirp,
-    command::ChildProcess,
+    capabilities::{drop_capabilities, CapabilitySet, ProcessCapabilities},
+    cgroup::Cgroup,
+    cli_wrappers::slirp::{Slirp, SlirpHandle},
+    command::{ChildProcess, NixUnistdChild},
     env::EnvVariable,
-    host_tools::get_host_tools,
-    mount::{BindMount, MountGuard},
-    path_ext::PathExt,
-    unshare::{UnshareEnvironmentBuilder, UnshareNamespaces},
-    volume_mount::VolumeMount,
+    error::{ContainixError, ResultExt},
+    host_tools::{default_host_tools, HostTools},
+    hosts::{render_hosts, render_resolv_conf, HostEntry},
+    init,
+    mount::{
+        mount_overlay, mount_tmpfs, mount_tmpfs_with, remount_read_only, BindMount, MountGuard,
+    },
+    path_ext::{join_within_root, PathExt},
+    rlimit::Rlimit,
+    seccomp::SeccompFilter,
+    timings,
+    unshare::{
+        fork_into_pid_namespace, wait_for_byte, IdRangeMap, PersistentNamespaces,
+        ProcessNamespaces, UnshareEnvironmentBuilder, UnshareNamespaces,
+    },
+    user::{render_group, render_passwd, UserSpec},
+    volume_mount::{Mount, VolumeMount},
 };
 
 #[derive(Debug, Clone, Builder)]
-#[builder(build_fn(name = __build, vis = ""))]
+#[builder(build_fn(name = __build, vis = ""), derive(Serialize, Deserialize))]
 pub struct ContainerFs {
     #[builder(default, setter(into, strip_option))]
     rootfs: Option<PathBuf>,
@@ -32,8 +66,41 @@ pub struct ContainerFs {
     volumes: Vec<VolumeMount>,
     #[builder(default, setter(custom, name = "nix_component"))]
     nix_components: Vec<PathBuf>,
+    /// Nix components to mount through an overlay, so they can be modified in the container
+    /// without touching the store. They must also be added as components.
+    #[builder(default, setter(custom, name = "writable_component"))]
+    writable_components: Vec<PathBuf>,
+    /// Make the root of the container read-only. Volumes keep their own mode.
+    #[builder(default)]
+    read_only: bool,
+    /// Mount the host’s `/etc/localtime` into the container.
+    #[builder(default)]
+    host_localtime: bool,
+    /// Extra `/etc/hosts` entries. If there are any, an `/etc/hosts` is mounted into the container.
+    #[builder(default, setter(custom, name = "host"))]
+    hosts: Vec<HostEntry>,
+    /// DNS servers for the container. If there are any, an `/etc/resolv.conf` is mounted into
+    /// the container.
+    #[builder(default, setter(custom, name = "nameserver"))]
+    nameservers: Vec<IpAddr>,
+    /// Mount a minimal `/etc/passwd` and `/etc/group` with root (and `passwd_user`) into the
+    /// container, unless a volume, mount or directory already provides them.
+    #[builder(default)]
+    passwd: bool,
+    /// Uid and gid of an additional entry in the generated `/etc/passwd` and `/etc/group`.
+    #[builder(default, setter(into))]
+    passwd_user: Option<(u32, u32)>,
+    /// Empty, writable directories to create in the container. On a read-only root they are
+    /// backed by a tmpfs.
+    #[builder(default, setter(custom, name = "directory"))]
+    directories: Vec<PathBuf>,
+    /// Mounts given with the explicit `type=...` syntax. Bind mounts are treated as volumes.
+    #[builder(default, setter(custom, name = "mount"))]
+    mounts: Vec<Mount>,
 }
 
+const HOST_LOCALTIME: &str = "/etc/localtime";
+
 impl ContainerFsBuilder {
     pub fn volume(&mut self, volume_mount: VolumeMount) -> &mut Self {
         self.volumes
@@ -42,6 +109,39 @@ impl ContainerFsBuilder {
         self
     }
 
+    pub fn writable_component(&mut self, component: impl AsRef<Path>) -> &mut Self {
+        self.writable_components
+            .get_or_insert_with(std::vec::Vec::new)
+            .push(component.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn directory(&mut self, directory: impl AsRef<Path>) -> &mut Self {
+        self.directories
+            .get_or_insert_with(std::vec::Vec::new)
+            .push(directory.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn mount(&mut self, mount: Mount) -> &mut Self {
+        self.mounts
+            .get_or_insert_with(std::vec::Vec::new)
+            .push(mount);
+        self
+    }
+
+    pub fn host(&mut self, host: HostEntry) -> &mut Self {
+        self.hosts.get_or_insert_with(std::vec::Vec::new).push(host);
+        self
+    }
+
+    pub fn nameserver(&mut self, nameserver: IpAddr) -> &mut Self {
+        self.nameservers
+            .get_or_insert_with(std::vec::Vec::new)
+            .push(nameserver);
+        self
+    }
+
     pub fn nix_component(&mut self, nix_mount: impl AsRef<Path>) -> &mut Self {
         self.nix_components
             .get_or_insert_with(std::vec::Vec::new)
@@ -51,24 +151,121 @@ impl ContainerFsBuilder {
 
     #[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
     pub fn build(self) -> Result<ContainerFsGuard> {
-        let container = self.__build()?;
+        let start = Instant::now();
+        let fs = self.assemble().error_kind(ContainixError::Filesystem)?;
+        timings::record(
+            format!("Assembling filesystem ({} mounts)", fs.mount_count()),
+            start.elapsed(),
+        );
+        Ok(fs)
+    }
+
+    fn assemble(self) -> Result<ContainerFsGuard> {
+        let mut container = self.__build()?;
+        container.check_writable_components()?;
         let tempdir = TempDir::with_prefix("containix-container").context("Creating tempdir")?;
+        tempdir.create()?;
         let root = tempdir.join("root");
         std::fs::create_dir_all(&root)
             .with_context(|| format!("Creating rootfs at {}", root.display()))?;
 
+        if container.host_localtime {
+            // `/etc/localtime` is usually a symlink into the host’s zoneinfo, which
+            // wouldn’t resolve inside the container.
+            let zoneinfo = std::fs::canonicalize(HOST_LOCALTIME)
+                .with_context(|| format!("Resolving {HOST_LOCALTIME}"))?;
+            container
+                .volumes
+                .push(VolumeMount::read_only(zoneinfo, HOST_LOCALTIME));
+        }
+        if !container.hosts.is_empty() {
+            // Mounted rather than written into the rootfs so it takes precedence over an
+            // `/etc/hosts` provided by the container.
+            let hostname = nix::unistd::gethostname().context("Getting hostname")?;
+            let hosts_file = tempdir.join("hosts");
+            std::fs::write(
+                &hosts_file,
+                render_hosts(&hostname.to_string_lossy(), &container.hosts),
+            )
+            .context("Writing /etc/hosts")?;
+            container
+                .volumes
+                .push(VolumeMount::read_only(hosts_file, "/etc/hosts"));
+        }
+        if !container.nameservers.is_empty() {
+            let resolv_conf = tempdir.join("resolv.conf");
+            std::fs::write(&resolv_conf, render_resolv_conf(&container.nameservers))
+                .context("Writing /etc/resolv.conf")?;
+            container
+                .volumes
+                .push(VolumeMount::read_only(resolv_conf, "/etc/resolv.conf"));
+        }
+        if container.passwd {
+            let files = [
+                ("passwd", render_passwd(container.passwd_user)),
+                ("group", render_group(container.passwd_user)),
+            ];
+            for (name, contents) in files {
+                let container_path = Path::new("/etc").join(name);
+                if container.provides(&container_path)? {
+                    continue;
+                }
+                let file = tempdir.join(name);
+                std::fs::write(&file, contents)
+                    .with_context(|| format!("Writing {}", container_path.display()))?;
+                container
+                    .volumes
+                    .push(VolumeMount::read_only(file, container_path));
+            }
+        }
+        for mount in std::mem::take(&mut container.mounts) {
+            match mount {
+                Mount::Bind(volume) => container.volumes.push(volume),
+                mount => container.mounts.push(mount),
+            }
+        }
+        container.check_mount_targets()?;
+
         if container.rootfs.is_some() {
             warn!("Not sure how rootfs got set, but it isn’t supported yet.");
         }
 
+        // To make the root read-only later, it needs to be a mount point itself.
+        let root_mount = if container.read_only {
+            Some(
+                BindMount::default()
+                    .src(&root)
+                    .dest(&root)
+                    .mount()
+                    .context("Bind-mounting rootfs onto itself")?,
+            )
+        } else {
+            None
+        };
+
+        // Which source each mount point was created for, to name both in a conflict.
+        let mut mount_points = HashMap::new();
         let nix_mounts = container
             .nix_components
-            .into_iter()
+            .iter()
             .map(|item| {
-                let target = root.join(item.rootless());
-                std::fs::create_dir_all(&target)?;
+                let target = join_within_root(&root, item)?;
+                // Store paths can be single files, like scripts from `writeScript`.
+                create_mount_point(item, &target, &mut mount_points)
+                    .with_context(|| format!("Mounting {}", item.display()))?;
+                if container.writable_components.contains(item) {
+                    let overlay = tempdir
+                        .join("overlays")
+                        .join(item.file_name().unwrap_or_default());
+                    let (upper, work) = (overlay.join("upper"), overlay.join("work"));
+                    std::fs::create_dir_all(&upper)?;
+                    std::fs::create_dir_all(&work)?;
+                    return mount_overlay(item, &upper, &work, &target).with_context(|| {
+                        format!("Mounting writable overlay of {}", item.display())
+                    });
+                }
                 BindMount::default()
-                    .src(&item)
+                    .src(item)
                     .dest(&target)
                     .read_only(true)
                     .mount()
@@ -76,44 +273,333 @@ impl ContainerFsBuilder {
             })
             .collect::<Result<Vec<_>>>()?;
 
-        let volume_mounts = container
-            .volumes
-            .into_iter()
-            .map(|volume_mount| {
-                let src = volume_mount.host_path.as_path();
-                let dest = root.join(volume_mount.container_path.rootless());
-                std::fs::create_dir_all(&dest)
-                    .with_context(|| format!("Creating directory {dest:?} for volume mount"))?;
+        let mut directory_mounts = Vec::new();
+        for directory in &container.directories {
+            let target = join_within_root(&root, directory)?;
+            std::fs::create_dir_all(&target)
+                .with_context(|| format!("Creating directory {}", directory.display()))?;
+            // On a read-only root, the directory needs its own filesystem to stay writable.
+            if root_mount.is_some() {
+                directory_mounts.push(
+                    mount_tmpfs(&target)
+                        .with_context(|| format!("Mounting tmpfs at {}", directory.display()))?,
+                );
+            }
+        }
+
+        let mut mounts = Vec::new();
+        for (index, mount) in container.mounts.iter().enumerate() {
+            let target = join_within_root(&root, mount.target())?;
+            std::fs::create_dir_all(&target)
+                .with_context(|| format!("Creating mount point {}", target.display()))?;
+            let guard = match mount {
+                Mount::Tmpfs { size, mode, .. } => {
+                    mount_tmpfs_with(&target, size.as_deref(), *mode)
+                        .with_context(|| format!("Mounting tmpfs at {}", mount.target().display()))
+                }
+                Mount::Overlay { source, .. } => {
+                    let overlay = tempdir.join("mounts").join(index.to_string());
+                    let (upper, work) = (overlay.join("upper"), overlay.join("work"));
+                    std::fs::create_dir_all(&upper)?;
+                    std::fs::create_dir_all(&work)?;
+                    mount_overlay(source, &upper, &work, &target).with_context(|| {
+                        format!("Mounting writable overlay of {}", source.display())
+                    })
+                }
+                Mount::Bind(_) => unreachable!("Bind mounts are moved to the volumes"),
+            }?;
+            mounts.push(guard);
+        }
+
+        let mut volume_mounts = Vec::new();
+        for volume_mount in container.volumes {
+            let src = volume_mount.host_path.as_path();
+            let dest = join_within_root(&root, &volume_mount.container_path)?;
+            if volume_mount.copy {
+                copy_recursively(src, &dest)
+                    .with_context(|| format!("Copying {src:?} -> {dest:?}"))?;
+                continue;
+            }
+            create_mount_point(src, &dest, &mut mount_points).context("Mounting volumes")?;
+            volume_mounts.push(
                 BindMount::default()
                     .src(src)
                     .dest(&dest)
                     .read_only(volume_mount.read_only)
                     .mount()
-                    .with_context(|| format!("Mounting {src:?} -> {dest:?}"))
-            })
-            .collect::<Result<Vec<_>>>()
-            .context("Mounting volumes")?;
+                    .with_context(|| format!("Mounting {src:?} -> {dest:?}"))?,
+            );
+        }
+
+        if root_mount.is_some() {
+            remount_read_only(&root).context("Making rootfs read-only")?;
+        }
 
         Ok(ContainerFsGuard {
             volume_mounts,
+            mounts,
+            directory_mounts,
             nix_mounts,
+            root_mount,
             tempdir,
             root,
         })
     }
 }
 
+impl ContainerFs {
+    /// Makes sure every writable component is a directory in the container’s closure, as
+    /// only those get an overlay.
+    fn check_writable_components(&self) -> Result<()> {
+        for component in &self.writable_components {
+            if !self.nix_components.contains(component) {
+                bail!(
+                    "{} is not part of the container’s closure and can’t be made writable",
+                    component.display()
+                );
+            }
+            if !component.is_dir() {
+                bail!(
+                    "{} is a file, only directories can be made writable",
+                    component.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a volume, mount or directory covers `path`, so the container brings its own file.
+    fn provides(&self, path: &Path) -> Result<bool> {
+        let targets = self
+            .volumes
+            .iter()
+            .map(|volume| volume.container_path.as_path())
+            .chain(self.mounts.iter().map(Mount::target))
+            .chain(self.directories.iter().map(PathBuf::as_path));
+        for target in targets {
+            if path.starts_with(target.normalize_container_path()?) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Makes sure no two mounts target the same path or a path inside another mount,
+    /// which would shadow the other mount or fail with EBUSY halfway through mounting.
+    fn check_mount_targets(&self) -> Result<()> {
+        let mut targets =
+            self.nix_components
+                .iter()
+                .map(|item| Ok((item.normalize_container_path()?, item.as_path())))
+                .chain(self.volumes.iter().map(|volume| {
+                    Ok((
+                        volume.container_path.normalize_container_path()?,
+                        volume.host_path.as_path(),
+                    ))
+                }))
+                .chain(self.directories.iter().map(|directory| {
+                    Ok((directory.normalize_container_path()?, Path::new("--mkdir")))
+                }))
+                .chain(self.mounts.iter().map(|mount| {
+                    let source = match mount {
+                        Mount::Overlay { source, .. } => source.as_path(),
+                        _ => Path::new("tmpfs"),
+                    };
+                    Ok((mount.target().normalize_container_path()?, source))
+                }))
+                .collect::<Result<Vec<_>>>()?;
+        // Paths are ordered component-wise, so any path inside another mount target
+        // sorts directly after it (or after another path inside it).
+        targets.sort();
+        for pair in targets.windows(2) {
+            let [(target_a, source_a), (target_b, source_b)] = pair else {
+                unreachable!()
+            };
+            if target_b.starts_with(target_a) {
+                bail!(
+                    "Mount target {} of {} overlaps with mount target {} of {}",
+                    target_b.display(),
+                    source_b.display(),
+                    target_a.display(),
+                    source_a.display()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Waits briefly for an interface besides loopback to be up with an IPv4 address. Runs inside
+/// the container’s network namespace.
+fn wait_for_network() -> Result<()> {
+    for _ in 0..NETWORK_POLL_ATTEMPTS {
+        let up = nix::ifaddrs::getifaddrs()
+            .context("Listing interface addresses")?
+            .any(|iface| {
+                iface.flags.contains(InterfaceFlags::IFF_UP)
+                    && !iface.flags.contains(InterfaceFlags::IFF_LOOPBACK)
+                    && iface
+                        .address
+                        .is_some_and(|address| address.as_sockaddr_in().is_some())
+            });
+        if up {
+            return Ok(());
+        }
+        std::thread::sleep(NETWORK_POLL_INTERVAL);
+    }
+    bail!("No interface besides loopback has an address")
+}
+
+/// Sets the loopback interface of the calling process’s network namespace up, which a new
+/// namespace starts without.
+fn bring_up_loopback() -> nix::Result<()> {
+    let socket = nix::sys::socket::socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    // SAFETY: ifreq is plain data, for which all zeroes is valid.
+    let mut request: nix::libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dest, src) in request.ifr_name.iter_mut().zip(b"lo") {
+        *dest = *src as nix::libc::c_char;
+    }
+    // SAFETY: `request` names an interface and has room for its flags.
+    Errno::result(unsafe {
+        nix::libc::ioctl(socket.as_raw_fd(), nix::libc::SIOCGIFFLAGS, &mut request)
+    })?;
+    // SAFETY: SIOCGIFFLAGS filled in the flags.
+    unsafe { request.ifr_ifru.ifru_flags |= nix::libc::IFF_UP as nix::libc::c_short };
+    // SAFETY: see above.
+    Errno::result(unsafe {
+        nix::libc::ioctl(socket.as_raw_fd(), nix::libc::SIOCSIFFLAGS, &request)
+    })?;
+    Ok(())
+}
+
+/// Creates the target for a bind mount of `src`.
+///
+/// Directories are mounted onto directories. Everything else (regular files, Unix
+/// sockets, device nodes) needs an empty file as a placeholder to be mounted onto.
+///
+/// `created` maps the mount points created so far to their sources. A mount point that
+/// conflicts with one of them, or with a directory created on the way to one of them, names
+/// that source in the error.
+fn create_mount_point(
+    src: &Path,
+    dest: &Path,
+    created: &mut HashMap<PathBuf, PathBuf>,
+) -> Result<()> {
+    let metadata =
+        std::fs::metadata(src).with_context(|| format!("Reading metadata of {src:?}"))?;
+    if let Ok(existing) = std::fs::symlink_metadata(dest) {
+        if existing.is_dir() != metadata.is_dir() {
+            let owner = created
+                .get(dest)
+                .or_else(|| {
+                    created
+                        .iter()
+                        .find(|(target, _)| target.starts_with(dest))
+                        .map(|(_, source)| source)
+                })
+                .map(|source| format!(" for {}", source.display()))
+                .unwrap_or_default();
+            bail!(
+                "{} is a {}, but the container already has a {} at {}{owner}",
+                src.display(),
+                file_kind(metadata.is_dir()),
+                file_kind(existing.is_dir()),
+                dest.display()
+            );
+        }
+    }
+    created.insert(dest.to_path_buf(), src.to_path_buf());
+    if metadata.is_dir() {
+        return std::fs::create_dir_all(dest)
+            .with_context(|| format!("Creating directory {dest:?} for volume mount"));
+    }
+
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Creating directory {parent:?} for volume mount"))?;
+    }
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dest)
+        .with_context(|| format!("Creating placeholder {dest:?} for volume mount"))?;
+    Ok(())
+}
+
+fn file_kind(is_dir: bool) -> &'static str {
+    if is_dir {
+        "directory"
+    } else {
+        "file"
+    }
+}
+
+/// Copies `src` to `dest`, descending into directories. Symlinks are copied as symlinks and
+/// FIFOs are recreated. Sockets and devices can’t be copied, so they are skipped with a warning.
+fn copy_recursively(src: &Path, dest: &Path) -> Result<()> {
+    let metadata =
+        std::fs::symlink_metadata(src).with_context(|| format!("Reading metadata of {src:?}"))?;
+    let file_type = metadata.file_type();
+    if file_type.is_socket() || file_type.is_block_device() || file_type.is_char_device() {
+        warn!("Not copying {src:?}, sockets and devices can’t be copied");
+        return Ok(());
+    }
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Creating directory {parent:?}"))?;
+    }
+    if file_type.is_symlink() {
+        let target = std::fs::read_link(src).with_context(|| format!("Reading link {src:?}"))?;
+        std::os::unix::fs::symlink(target, dest)
+            .with_context(|| format!("Creating symlink {dest:?}"))?;
+    } else if file_type.is_dir() {
+        std::fs::create_dir_all(dest).with_context(|| format!("Creating directory {dest:?}"))?;
+        std::fs::set_permissions(dest, metadata.permissions())
+            .with_context(|| format!("Setting permissions of {dest:?}"))?;
+        for entry in std::fs::read_dir(src).with_context(|| format!("Reading {src:?}"))? {
+            let entry = entry.with_context(|| format!("Reading {src:?}"))?;
+            copy_recursively(&entry.path(), &dest.join(entry.file_name()))?;
+        }
+    } else if file_type.is_fifo() {
+        // Opening a FIFO to copy it would block until someone writes to it.
+        nix::unistd::mkfifo(dest, Mode::from_bits_truncate(metadata.mode()))
+            .with_context(|| format!("Creating FIFO {dest:?}"))?;
+    } else {
+        std::fs::copy(src, dest).with_context(|| format!("Copying {src:?}"))?;
+    }
+    Ok(())
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct ContainerFsGuard {
     // Order is important here, as drop runs in order of declaration.
     // https://doc.rust-lang.org/stable/std/ops/trait.Drop.html#drop-order
     volume_mounts: Vec<MountGuard>,
+    mounts: Vec<MountGuard>,
+    directory_mounts: Vec<MountGuard>,
     nix_mounts: Vec<MountGuard>,
+    root_mount: Option<MountGuard>,
     tempdir: TempDir,
     root: PathBuf,
 }
 
+impl ContainerFsGuard {
+    fn mount_count(&self) -> usize {
+        self.volume_mounts.len()
+            + self.mounts.len()
+            + self.directory_mounts.len()
+            + self.nix_mounts.len()
+            + usize::from(self.root_mount.is_some())
+    }
+}
+
 impl Deref for ContainerFsGuard {
     type Target = Path;
 
@@ -128,23 +614,119 @@ impl AsRef<Path> for ContainerFsGuard {
     }
 }
 
+/// Enters the namespace the container filesystem is set up in. Without custom id maps, the
+/// current user becomes root in it.
+#[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+pub fn enter_root_ns(uid_maps: &[IdRangeMap], gid_maps: &[IdRangeMap]) -> Result<()> {
+    let mut builder = UnshareEnvironmentBuilder::default();
+    builder
+        .namespace(UnshareNamespaces::User)
+        .namespace(UnshareNamespaces::Mount);
+    if uid_maps.is_empty() && gid_maps.is_empty() {
+        builder.map_current_user_to_root();
+    } else {
+        for map in uid_maps {
+            builder.uid_map(map.clone());
+        }
+        for map in gid_maps {
+            builder.gid_map(map.clone());
+        }
+    }
+    builder.enter()?;
+    Ok(())
+}
+
+/// How long [`wait_for_network`] waits for the container’s interface to get an address.
+const NETWORK_POLL_ATTEMPTS: u32 = 20;
+const NETWORK_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
 #[derive(Debug, Builder)]
 #[builder(pattern = "owned")]
-#[builder(build_fn(name = __build, vis = ""))]
+#[builder(build_fn(name = __build, vis = ""), derive(Serialize, Deserialize))]
 pub struct Container {
+    /// The user and network namespace kept with `keep_network`. Declared before `root`, so
+    /// they are unmounted before its tempdir is removed.
+    #[builder(setter(skip))]
+    kept_namespaces: Option<PersistentNamespaces>,
+    /// The cgroup enforcing `pids_limit`, created when the container is first started.
+    #[builder(setter(skip))]
+    cgroup: Option<Cgroup>,
+    #[builder_field_attr(serde(skip))]
     root: ContainerFsGuard,
-    // #[builder(default, setter(strip_option, into))]
-    // uid: Option<u32>,
-    // #[builder(default, setter(strip_option, into))]
-    // gid: Option<u32>,
+    /// User to run the container command as. Names are resolved inside the container.
+    #[builder(default, setter(into))]
+    user: Option<UserSpec>,
+    /// Supplementary groups of the container process. They have to be mapped through `gid_maps`.
+    #[builder(default, setter(into))]
+    groups: Vec<u32>,
+    /// Resource limits set right before the command is executed.
+    #[builder(default, setter(into))]
+    rlimits: Vec<Rlimit>,
+    /// Maximum number of processes in the container, enforced through a cgroup. Unlike
+    /// `RLIMIT_NPROC`, it also counts processes of other users. Without a delegated cgroup,
+    /// the limit is skipped with a warning.
+    #[builder(default)]
+    pids_limit: Option<u64>,
     #[builder(default, setter(custom, name = "env"))]
     envs: Vec<EnvVariable>,
     #[builder(setter(into))]
     command: String,
     #[builder(default, setter(custom, name = "arg"))]
     args: Vec<String>,
+    /// `argv[0]` for the command instead of the command itself, e.g. for multi-call binaries.
+    #[builder(default, setter(into))]
+    argv0: Option<String>,
+    /// Directory inside the container the command starts in, `/` if unset.
+    #[builder(default, setter(into))]
+    workdir: Option<PathBuf>,
     #[builder(default, setter(custom, name = "port"))]
     port_mappings: Vec<PortMapping>,
+    /// Connect the container to the host’s network through slirp. Without it, the container
+    /// only has a loopback interface.
+    #[builder(default = "true")]
+    network: bool,
+    /// Hold the command back until the container’s network is up, and fail if it doesn’t
+    /// come up. Without it, the command starts while slirp is still being set up.
+    #[builder(default)]
+    wait_net: bool,
+    /// Keep the network namespace and slirp when the container restarts, so port forwards
+    /// stay in place. The namespace is bind-mounted into the tempdir when first started.
+    #[builder(default)]
+    keep_network: bool,
+    /// Restart slirp if it crashes while the container is running.
+    #[builder(default)]
+    slirp_restart: bool,
+    /// Capabilities to remove from the container process.
+    #[builder(default)]
+    dropped_capabilities: CapabilitySet,
+    /// Print the capabilities the command gets to the container’s stderr before executing it.
+    #[builder(default)]
+    print_capabilities: bool,
+    /// Run a minimal init as PID 1 that reaps orphaned processes and forwards signals.
+    #[builder(default)]
+    init: bool,
+    /// Set `PR_SET_NO_NEW_PRIVS` so setuid binaries can’t gain privileges in the container.
+    #[builder(default = "true")]
+    no_new_privs: bool,
+    /// Seccomp filter installed right before executing the container command.
+    #[builder(default)]
+    seccomp: Option<SeccompFilter>,
+    /// File the container command’s stdout and stderr are appended to. If unset, they are inherited.
+    #[builder(default, setter(into))]
+    log_file: Option<PathBuf>,
+    /// Id maps of the namespace the container is started from. If set, the container gets
+    /// the same ids instead of only root.
+    #[builder(default, setter(into))]
+    uid_maps: Vec<IdRangeMap>,
+    #[builder(default, setter(into))]
+    gid_maps: Vec<IdRangeMap>,
+    /// Namespaces the container shares with the host instead of getting its own.
+    #[builder(default, setter(custom, name = "share_namespace"))]
+    shared_namespaces: Vec<UnshareNamespaces>,
+    /// Tools to run on the host, like slirp. If unset, the ones from
+    /// [`setup_host_tools`](crate::host_tools::setup_host_tools) are used.
+    #[builder(default, setter(into))]
+    host_tools: Option<HostTools>,
 }
 
 #[allow(dead_code)]
@@ -188,76 +770,653 @@ impl ContainerBuilder {
         self
     }
 
+    pub fn share_namespace(mut self, namespace: UnshareNamespaces) -> Self {
+        self.shared_namespaces
+            .get_or_insert_with(std::vec::Vec::new)
+            .push(namespace);
+        self
+    }
+
+    pub fn share_namespaces(
+        mut self,
+        namespaces: impl IntoIterator<Item = UnshareNamespaces>,
+    ) -> Self {
+        self.shared_namespaces
+            .get_or_insert_with(std::vec::Vec::new)
+            .extend(namespaces);
+        self
+    }
+
+    #[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+    pub fn spawn(self) -> Result<ContainerGuard<NixUnistdChild, SlirpHandle>> {
+        let mut container = self.__build()?;
+        container.check_user()?;
+        container.check_groups()?;
+        container.check_shared_namespaces()?;
+        container.check_network()?;
+        // Prepended, so they can still be overridden explicitly.
+        container.envs.splice(
+            0..0,
+            [
+                EnvVariable::new("CONTAINIX_CONTAINER", "1"),
+                EnvVariable::new("CONTAINIX_VERSION", env!("CARGO_PKG_VERSION")),
+            ],
+        );
+        container.setup_cgroup();
+        let (handle, slirp) = container.start(true)?;
+        Ok(ContainerGuard {
+            slirp,
+            handle,
+            container,
+        })
+    }
+
+    /// Like [`spawn`](Self::spawn), but leaves the calling process’s namespaces and mounts
+    /// untouched. A helper process enters the root namespace, builds `fs` as the container root,
+    /// runs the container and cleans up once it exits. The returned handle is that helper: it
+    /// exits with the container’s exit status and forwards termination signals to it.
+    ///
+    /// The root set on the builder is replaced. The helper is the current executable started
+    /// again, so its `main` must call [`run_isolated_helper`] before doing anything else.
     #[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
-    pub fn spawn<'a>(self) -> Result<ContainerGuard<impl ChildProcess, impl ChildProcess>> {
-        let opts = self.__build()?;
+    pub fn spawn_isolated(self, fs: ContainerFsBuilder) -> Result<NixUnistdChild> {
+        let spec = serde_json::to_vec(&IsolatedSpec {
+            container: self,
+            fs,
+        })
+        .context("Serializing isolated container")?;
+        let (reader, writer) =
+            nix::unistd::pipe2(OFlag::O_CLOEXEC).context("Creating spec pipe")?;
+        let spec_fd = reader.as_raw_fd();
+        let mut cmd = Command::new("/proc/self/exe");
+        cmd.env(ISOLATED_SPEC_FD_ENV, spec_fd.to_string());
+        // SAFETY: fcntl is async-signal-safe.
+        unsafe {
+            cmd.pre_exec(move || {
+                nix::fcntl::fcntl(spec_fd, nix::fcntl::F_SETFD(nix::fcntl::FdFlag::empty()))?;
+                Ok(())
+            });
+        }
+        let helper = cmd.spawn().context("Starting isolated container helper")?;
+        drop(reader);
+        // Dropping the `Child` neither kills nor reaps it, the returned handle does that.
+        let helper = NixUnistdChild::from(Pid::from_raw(helper.id() as i32));
+        std::fs::File::from(writer)
+            .write_all(&spec)
+            .context("Sending isolated container spec")?;
+        Ok(helper)
+    }
+
+    fn run_isolated(self, fs: ContainerFsBuilder) -> Result<i32> {
+        let uid_maps = self.uid_maps.clone().unwrap_or_default();
+        let gid_maps = self.gid_maps.clone().unwrap_or_default();
+        enter_root_ns(&uid_maps, &gid_maps)?;
+        let root = fs.build().context("Building container fs")?;
+        let mut container = self.root(root).spawn()?;
+        init::forward_signals_to(Pid::from_raw(container.pid() as i32));
+        let status = container.wait().context("Waiting for container to exit");
+        init::child_exited();
+        Ok(status?.unwrap_or(1))
+    }
+}
+
+/// What [`ContainerBuilder::spawn_isolated`] sends to its helper process.
+#[derive(Serialize, Deserialize)]
+struct IsolatedSpec {
+    container: ContainerBuilder,
+    fs: ContainerFsBuilder,
+}
+
+/// Environment variable holding the file descriptor the helper reads its [`IsolatedSpec`] from.
+const ISOLATED_SPEC_FD_ENV: &str = "CONTAINIX_ISOLATED_SPEC_FD";
+
+/// Runs the container if this process is a helper started by
+/// [`ContainerBuilder::spawn_isolated`], and exits with its exit status. Otherwise, it returns
+/// right away.
+///
+/// Programs using `spawn_isolated` must call this at the start of `main`, before starting any
+/// threads. Errors are logged through `tracing`, so install a subscriber first to see them.
+pub fn run_isolated_helper() {
+    let Some(fd) = std::env::var_os(ISOLATED_SPEC_FD_ENV) else {
+        return;
+    };
+    std::env::remove_var(ISOLATED_SPEC_FD_ENV);
+    let code = read_isolated_spec(&fd)
+        .and_then(|spec| spec.container.run_isolated(spec.fs))
+        .unwrap_or_else(|e| {
+            error!("Isolated container failed: {e:#}");
+            1
+        });
+    // Nothing is left to flush, and skipping atexit handlers keeps the helper from running
+    // cleanup registered by the program it was started from.
+    // SAFETY: `_exit` is always safe to call, it just skips the usual cleanup.
+    unsafe { nix::libc::_exit(code) }
+}
+
+fn read_isolated_spec(fd: &OsStr) -> Result<IsolatedSpec> {
+    let fd: RawFd = fd
+        .to_str()
+        .and_then(|fd| fd.parse().ok())
+        .with_context(|| format!("Invalid {ISOLATED_SPEC_FD_ENV}: {fd:?}"))?;
+    // SAFETY: `spawn_isolated` passed this descriptor, and nothing else uses it.
+    let reader = std::fs::File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    serde_json::from_reader(reader).context("Reading isolated container spec")
+}
+
+impl Container {
+    fn namespaces(&self) -> Vec<UnshareNamespaces> {
+        [
+            UnshareNamespaces::Mount,
+            UnshareNamespaces::Pid,
+            UnshareNamespaces::Ipc,
+            UnshareNamespaces::User,
+            UnshareNamespaces::Uts,
+            UnshareNamespaces::Network,
+        ]
+        .into_iter()
+        .filter(|ns| !self.shared_namespaces.contains(ns))
+        .collect()
+    }
+
+    fn check_user(&self) -> Result<()> {
+        if self.user.is_some() && !(self.uid_maps.is_empty() && self.gid_maps.is_empty()) {
+            bail!("A user can’t be combined with custom id maps");
+        }
+        Ok(())
+    }
+
+    /// Supplementary groups can only be set if setgroups wasn’t denied when entering the root
+    /// namespace, which only newgidmap does, and if the groups are mapped.
+    fn check_groups(&self) -> Result<()> {
+        if self.groups.is_empty() {
+            return Ok(());
+        }
+        if self.gid_maps.is_empty() {
+            bail!("Supplementary groups need custom gid maps that include them");
+        }
+        for gid in &self.groups {
+            if !self.gid_maps.iter().any(|map| map.maps_inner(*gid)) {
+                bail!("Supplementary group {gid} isn’t mapped by the gid maps");
+            }
+        }
+        let setgroups =
+            std::fs::read_to_string("/proc/self/setgroups").context("Reading setgroups")?;
+        if setgroups.trim() == "deny" {
+            bail!(
+                "setgroups is denied in this user namespace, so supplementary groups can’t be set. \
+                Mapping gids other than your own with newgidmap allows it."
+            );
+        }
+        Ok(())
+    }
+
+    /// Creates the cgroup for the container’s limits, if it has any.
+    fn setup_cgroup(&mut self) {
+        let Some(pids_limit) = self.pids_limit else {
+            return;
+        };
+        let name = format!("containix-{}", uuid::Uuid::new_v4());
+        let cgroup = Cgroup::create(&name).and_then(|cgroup| {
+            cgroup.set_pids_max(pids_limit)?;
+            Ok(cgroup)
+        });
+        match cgroup {
+            Ok(cgroup) => self.cgroup = Some(cgroup),
+            Err(e) => warn!(
+                "Not limiting the container’s processes, its cgroup can’t be set up: {e:#}. \
+                This needs a cgroup v2 delegated to you that allows `+pids` in its \
+                cgroup.subtree_control."
+            ),
+        }
+    }
+
+    /// The host tools given to the builder, or the process-wide default.
+    fn host_tools(&self) -> Result<&HostTools> {
+        match &self.host_tools {
+            Some(host_tools) => Ok(host_tools),
+            None => default_host_tools()
+                .context("No host tools were given to the container and none were set up"),
+        }
+    }
+
+    /// Path of slirp’s control socket. Slirp shortens it if it is too long for a Unix socket.
+    fn slirp_socket(&self) -> PathBuf {
+        self.root.tempdir.join("slirp.sock")
+    }
+
+    /// Makes sure slirp can be started before anything is set up for the container.
+    fn check_network(&self) -> Result<()> {
+        if !self.network {
+            if !self.port_mappings.is_empty() {
+                bail!("Ports can’t be published from a container without network");
+            }
+            if self.keep_network {
+                bail!("A container without network has no network to keep");
+            }
+            return Ok(());
+        }
+        self.host_tools()?.require("slirp4netns").context(
+            "slirp4netns provides the container’s network, make sure the host tools include it",
+        )?;
+        Ok(())
+    }
+
+    fn check_shared_namespaces(&self) -> Result<()> {
+        for namespace in &self.shared_namespaces {
+            match namespace {
+                UnshareNamespaces::User => bail!(
+                    "The user namespace can’t be shared, it is what allows containix to run without root"
+                ),
+                UnshareNamespaces::Mount => bail!(
+                    "The mount namespace can’t be shared, the container’s filesystem only exists inside it"
+                ),
+                UnshareNamespaces::Network => bail!(
+                    "The network namespace can’t be shared, slirp needs a separate one to provide the container’s network"
+                ),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts the container process and, if enabled and `start_network` is set, its network.
+    /// With kept namespaces, the process joins them instead of creating its own.
+    fn start(&mut self, start_network: bool) -> Result<(NixUnistdChild, Option<SlirpHandle>)> {
+        let joined = self
+            .kept_namespaces
+            .as_ref()
+            .map(|kept| kept.open().map(Arc::new))
+            .transpose()
+            .context("Opening the kept namespaces")?;
         let mut unshare_builder = UnshareEnvironmentBuilder::default();
+        for namespace in self.namespaces() {
+            if joined.is_some()
+                && matches!(
+                    namespace,
+                    UnshareNamespaces::User | UnshareNamespaces::Network
+                )
+            {
+                continue;
+            }
+            unshare_builder.namespace(namespace);
+        }
+        match &self.user {
+            // The joined user namespace already maps the ids.
+            _ if joined.is_some() => {}
+            Some(user) => {
+                let (uid, gid) = user
+                    .resolve(self.root.as_ref())
+                    .with_context(|| format!("Resolving user {user}"))?;
+                unshare_builder.map_current_user_to(uid, gid);
+            }
+            None if self.uid_maps.is_empty() && self.gid_maps.is_empty() => {
+                unshare_builder.map_current_user_to_root();
+            }
+            None => {
+                for map in &self.uid_maps {
+                    unshare_builder.uid_map(map.identity());
+                }
+                for map in &self.gid_maps {
+                    unshare_builder.gid_map(map.identity());
+                }
+            }
+        }
         unshare_builder
-            .namespace(UnshareNamespaces::Mount)
-            .namespace(UnshareNamespaces::Pid)
-            .namespace(UnshareNamespaces::Ipc)
-            .namespace(UnshareNamespaces::User)
-            .namespace(UnshareNamespaces::Uts)
-            .namespace(UnshareNamespaces::Network)
-            .map_current_user_to_root()
-            .root(opts.root.as_ref());
+            .allow_setgroups(!self.groups.is_empty())
+            .root(self.root.as_ref());
+        if let Some(joined) = joined {
+            unshare_builder.join(joined);
+        }
+        if let Some(cgroup) = &self.cgroup {
+            unshare_builder.cgroup_procs(cgroup.procs());
+        }
+        let groups: Vec<_> = self.groups.iter().copied().map(Gid::from_raw).collect();
 
+        let log_file = self
+            .log_file
+            .as_ref()
+            .map(|path| {
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Opening log file {}", path.display()))
+            })
+            .transpose()?;
+
+        // Slirp can only be started for the container process, so with `wait_net` the
+        // process waits on this pipe until slirp is ready.
+        let net_ready = (self.network && self.wait_net && start_network)
+            .then(|| nix::unistd::pipe2(OFlag::O_CLOEXEC))
+            .transpose()
+            .context("Creating network ready pipe")?;
+        let net_ready_fds = net_ready
+            .as_ref()
+            .map(|(rx, tx)| (rx.as_raw_fd(), tx.as_raw_fd()));
+        // Slirp brings up loopback along with its interface. Without it, nothing else does.
+        let own_loopback =
+            !self.network && !self.shared_namespaces.contains(&UnshareNamespaces::Network);
+
+        let spawn_start = Instant::now();
         let handle = unshare_builder
-            .execute(move || {
-                let mut cmd = Command::new(&opts.command);
-                cmd.args(&opts.args).env_clear().envs(
-                    opts.envs
-                        .iter()
-                        .map(|v| (v.key.as_os_str(), v.value.as_os_str())),
-                );
-                let err = cmd.exec();
-                error!("Failed to execute `{:?}`: {err}", cmd);
-                -100
+            .execute(|| {
+                if let Some(log_file) = &log_file {
+                    for fd in [nix::libc::STDOUT_FILENO, nix::libc::STDERR_FILENO] {
+                        if let Err(e) = nix::unistd::dup2(log_file.as_raw_fd(), fd) {
+                            error!("Failed to redirect output to log file: {e}");
+                            return -1;
+                        }
+                    }
+                }
+                if !groups.is_empty() {
+                    if let Err(e) = nix::unistd::setgroups(&groups) {
+                        error!("Failed to set supplementary groups: {e}");
+                        return -1;
+                    }
+                }
+                for rlimit in &self.rlimits {
+                    if let Err(e) = rlimit.apply() {
+                        error!("Failed to set rlimit {rlimit}: {e}");
+                        return -1;
+                    }
+                }
+                if let Some(workdir) = &self.workdir {
+                    if let Err(e) = nix::unistd::chdir(workdir) {
+                        error!("Failed to change into {}: {e}", workdir.display());
+                        return -1;
+                    }
+                }
+                if own_loopback {
+                    if let Err(e) = bring_up_loopback() {
+                        error!("Failed to bring up the loopback interface: {e}");
+                        return -1;
+                    }
+                }
+                if let Some((rx, tx)) = net_ready_fds {
+                    _ = nix::unistd::close(tx);
+                    if let Err(e) = wait_for_byte(rx) {
+                        error!("Container network failed to start: {e}");
+                        return -1;
+                    }
+                    _ = nix::unistd::close(rx);
+                    if let Err(e) = wait_for_network() {
+                        error!("Container network isn’t up: {e}");
+                        return -1;
+                    }
+                }
+                if !self.dropped_capabilities.is_empty() {
+                    if let Err(e) = drop_capabilities(self.dropped_capabilities) {
+                        error!("Failed to drop capabilities: {e}");
+                        return -1;
+                    }
+                }
+                if self.no_new_privs {
+                    if let Err(e) = nix::sys::prctl::set_no_new_privs() {
+                        error!("Failed to set no_new_privs: {e}");
+                        return -1;
+                    }
+                }
+                if let Some(seccomp) = &self.seccomp {
+                    if let Err(e) = seccomp.install() {
+                        error!("Failed to install seccomp filter: {e}");
+                        return -1;
+                    }
+                }
+                if self.print_capabilities {
+                    match ProcessCapabilities::current() {
+                        Ok(caps) => eprintln!(
+                            "Container capabilities:\n{}",
+                            caps.after_exec(nix::unistd::geteuid().is_root())
+                        ),
+                        Err(e) => error!("Failed to read capabilities: {e}"),
+                    }
+                }
+                let exec = || {
+                    let mut cmd = Command::new(&self.command);
+                    cmd.args(&self.args).env_clear().envs(
+                        self.envs
+                            .iter()
+                            .map(|v| (v.key.as_os_str(), v.value.as_os_str())),
+                    );
+                    if let Some(argv0) = &self.argv0 {
+                        cmd.arg0(argv0);
+                    }
+                    let err = cmd.exec();
+                    error!("Failed to execute `{:?}`: {err}", cmd);
+                    -100
+                };
+                if self.init {
+                    init::run(exec)
+                } else {
+                    exec()
+                }
             })
-            .context("Entering unshare environment")?;
+            .context("Entering unshare environment")
+            .error_kind(ContainixError::Spawn)?;
+        timings::record("Spawning container", spawn_start.elapsed());
         trace!("Container spawned with PID {}", handle.pid());
+        // If starting slirp fails, dropping the write end lets the container process bail.
+        let net_ready_tx = net_ready.map(|(_, tx)| tx);
+        if self.keep_network && self.kept_namespaces.is_none() {
+            let kept = PersistentNamespaces::persist(
+                handle.pid(),
+                [UnshareNamespaces::User, UnshareNamespaces::Network],
+                &self.root.tempdir.join("namespaces"),
+            );
+            match kept {
+                Ok(kept) => self.kept_namespaces = Some(kept),
+                Err(e) => {
+                    let mut handle = handle;
+                    _ = handle.kill();
+                    return Err(e).context("Keeping the container’s network namespace");
+                }
+            }
+        }
+        if !self.network || !start_network {
+            return Ok((handle, None));
+        }
 
+        let socket = self.slirp_socket();
+        // A previous slirp instance might have left its socket behind.
+        _ = std::fs::remove_file(&socket);
         let mut slirp = Slirp::default();
         slirp
             .pid(handle.pid())
-            .socket(opts.root.tempdir.join("slirp.sock"));
+            .socket(socket)
+            .restart(self.slirp_restart)
+            .enable_ipv6(
+                self.port_mappings
+                    .iter()
+                    .any(|port| port.host_ip.is_some_and(|ip| ip.is_ipv6())),
+            );
 
-        let slirp_binary = get_host_tools().join("bin").join("slirp4netns");
+        // Slirp joins the kept namespace by path, so it can also be restarted after the
+        // process it was started for is gone.
+        if let Some(kept) = &self.kept_namespaces {
+            slirp
+                .netns_path(kept.path(UnshareNamespaces::Network))
+                .userns_path(kept.path(UnshareNamespaces::User));
+        }
+
+        let slirp_binary = self.host_tools()?.require("slirp4netns")?;
         trace!("Using slirp binary: {}", slirp_binary.display());
         slirp.binary(slirp_binary);
 
-        for port in opts.port_mappings {
-            slirp.port(port);
+        for port in &self.port_mappings {
+            slirp.port(port.clone());
         }
 
-        let slirp = slirp.activate().context("Activating slirp")?;
-
-        return Ok(ContainerGuard {
-            slirp,
-            handle,
-            root: opts.root,
-        });
+        let slirp = timings::measure("Starting slirp", || slirp.activate())
+            .context("Activating slirp")
+            .error_kind(ContainixError::Network)?;
+        if let Some(tx) = net_ready_tx {
+            nix::unistd::write(&tx, &[1])
+                .context("Signaling network to container")
+                .error_kind(ContainixError::Network)?;
+        }
+        Ok((handle, Some(slirp)))
     }
 }
 
 #[derive(Debug, Deref, DerefMut)]
 pub struct ContainerGuard<T: ChildProcess, T2: ChildProcess> {
-    slirp: T2,
+    slirp: Option<T2>,
     #[deref]
     #[deref_mut]
     handle: T,
-    root: ContainerFsGuard,
+    container: Container,
 }
 
 impl<T: ChildProcess, T2: ChildProcess> AsRef<Path> for ContainerGuard<T, T2> {
     fn as_ref(&self) -> &Path {
-        self.root.as_ref()
+        self.container.root.as_ref()
     }
 }
 
 impl<T: ChildProcess, T2: ChildProcess> ContainerGuard<T, T2> {
     pub fn root(&self) -> &Path {
-        self.root.as_ref()
+        self.container.root.as_ref()
+    }
+
+    /// Addresses assigned to the container’s network interfaces, excluding loopback and
+    /// link-local addresses.
+    #[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+    pub fn ip_addresses(&self) -> Result<Vec<IpAddr>> {
+        let namespaces =
+            ProcessNamespaces::open(self.handle.pid(), [UnshareNamespaces::Network], false)
+                .context("Opening container network namespace")?;
+        // Only the network namespace is entered, which is allowed for a single thread of a
+        // multi-threaded process. The netlink socket then lists the container’s interfaces.
+        std::thread::spawn(move || {
+            namespaces
+                .enter()
+                .context("Entering container network namespace")?;
+            let addresses = nix::ifaddrs::getifaddrs().context("Listing interface addresses")?;
+            Ok(addresses
+                .filter(|iface| !iface.flags.contains(InterfaceFlags::IFF_LOOPBACK))
+                .filter_map(|iface| iface.address)
+                .filter_map(|address| {
+                    if let Some(v4) = address.as_sockaddr_in() {
+                        return Some(IpAddr::V4(v4.ip()));
+                    }
+                    address
+                        .as_sockaddr_in6()
+                        .map(|v6| v6.ip())
+                        .filter(|ip| !ip.is_unicast_link_local())
+                        .map(IpAddr::V6)
+                })
+                .collect())
+        })
+        .join()
+        .map_err(|_| anyhow::anyhow!("Listing container addresses panicked"))?
+    }
+
+    /// Returns a handle to run additional commands inside the container.
+    pub fn exec_handle(&self) -> ContainerExec {
+        ContainerExec {
+            pid: self.handle.pid(),
+            root: self.root().to_path_buf(),
+            envs: self.container.envs.clone(),
+            namespaces: self.container.namespaces(),
+        }
+    }
+}
+
+/// Runs additional commands in the namespaces of a running container.
+#[derive(Debug, Clone)]
+pub struct ContainerExec {
+    pid: u32,
+    root: PathBuf,
+    envs: Vec<EnvVariable>,
+    namespaces: Vec<UnshareNamespaces>,
+}
+
+impl ContainerExec {
+    /// Creates a command that will run inside the container with the container’s environment.
+    ///
+    /// The program is looked up in the container’s `PATH` unless it is an absolute path.
+    pub fn command(&self, program: impl AsRef<Path>) -> Result<Command> {
+        let namespaces = ProcessNamespaces::open(self.pid, self.namespaces.iter().copied(), true)
+            .context("Opening container namespaces")?;
+        let pid_namespace = self.namespaces.contains(&UnshareNamespaces::Pid);
+        let mut cmd = Command::new(self.resolve(program.as_ref()));
+        cmd.env_clear().envs(
+            self.envs
+                .iter()
+                .map(|v| (v.key.as_os_str(), v.value.as_os_str())),
+        );
+        // SAFETY: `enter` and `fork_into_pid_namespace` only issue syscalls and do not allocate.
+        unsafe {
+            cmd.pre_exec(move || {
+                namespaces.enter()?;
+                if pid_namespace {
+                    fork_into_pid_namespace()?;
+                }
+                Ok(())
+            });
+        }
+        Ok(cmd)
+    }
+
+    /// Runs `program` with `args` inside the container and collects its output.
+    #[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+    pub fn output(
+        &self,
+        program: impl AsRef<Path>,
+        args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    ) -> Result<Output> {
+        let program = program.as_ref();
+        self.command(program)?
+            .args(args)
+            .output()
+            .with_context(|| format!("Running {} in container", program.display()))
+    }
+
+    /// Looks up `program` in the container’s `PATH` and returns its path inside the container.
+    fn resolve(&self, program: &Path) -> PathBuf {
+        if program.is_absolute() {
+            return program.to_path_buf();
+        }
+        let Some(path) = self.envs.iter().rev().find(|v| v.key == "PATH") else {
+            return program.to_path_buf();
+        };
+        std::env::split_paths(&path.value)
+            .map(|dir| dir.join(program))
+            .find(|candidate| self.root.join(candidate.rootless()).exists())
+            .unwrap_or_else(|| program.to_path_buf())
+    }
+}
+
+impl<T: ChildProcess> ContainerGuard<T, SlirpHandle> {
+    /// Whether the container’s network is still up. Always true without network.
+    pub fn network_healthy(&self) -> bool {
+        self.slirp.as_ref().is_none_or(SlirpHandle::is_healthy)
+    }
+}
+
+impl ContainerGuard<NixUnistdChild, SlirpHandle> {
+    /// Starts the container process again in the same root filesystem, e.g. after it exited.
+    ///
+    /// Slirp is restarted as well, as it is tied to the network namespace of the old process.
+    /// If the container keeps its network, the new process joins the old namespace and a
+    /// healthy slirp keeps running, along with its port forwards.
+    #[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+    pub fn restart(&mut self) -> Result<()> {
+        self.handle.kill()?;
+        let keep_slirp = self.container.kept_namespaces.is_some()
+            && self.slirp.as_ref().is_some_and(SlirpHandle::is_healthy);
+        if !keep_slirp {
+            if let Some(slirp) = &mut self.slirp {
+                slirp.kill()?;
+                slirp.wait().context("Waiting for slirp to exit")?;
+            }
+        }
+        let (handle, slirp) = self.container.start(!keep_slirp)?;
+        self.handle = handle;
+        if !keep_slirp {
+            self.slirp = slirp;
+        }
+        Ok(())
     }
 }
 
@@ -266,8 +1425,112 @@ impl<T: ChildProcess, T2: ChildProcess> Drop for ContainerGuard<T, T2> {
         if let Err(e) = self.handle.kill() {
             error!("Failed to kill container: {e}");
         }
-        if let Err(e) = self.slirp.kill() {
+        let Some(slirp) = &mut self.slirp else {
+            return;
+        };
+        if let Err(e) = slirp.kill() {
             error!("Failed to kill slirp: {e}");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brings_up_loopback() {
+        use nix::sched::{unshare, CloneFlags};
+        use nix::sys::wait::{waitpid, WaitStatus};
+        use nix::unistd::ForkResult;
+
+        // SAFETY: the child only makes syscalls and exits without returning to the harness.
+        let child = match unsafe { nix::unistd::fork() }.unwrap() {
+            ForkResult::Parent { child } => child,
+            ForkResult::Child => {
+                let code = if unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNET).is_err()
+                {
+                    // Namespaces aren’t available here, nothing to test.
+                    0
+                } else if bring_up_loopback().is_err() {
+                    1
+                } else {
+                    let up = nix::ifaddrs::getifaddrs().is_ok_and(|mut ifaces| {
+                        ifaces.any(|iface| {
+                            iface.interface_name == "lo"
+                                && iface.flags.contains(InterfaceFlags::IFF_UP)
+                        })
+                    });
+                    if up {
+                        0
+                    } else {
+                        2
+                    }
+                };
+                unsafe { nix::libc::_exit(code) };
+            }
+        };
+        assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+    }
+
+    #[test]
+    fn mount_point_conflicts_name_both_sources() {
+        let tempdir = TempDir::new().unwrap();
+        tempdir.create().unwrap();
+        let directory = tempdir.join("aaaa-dir");
+        let (file, other_file) = (tempdir.join("bbbb-file"), tempdir.join("cccc-file"));
+        std::fs::create_dir(&directory).unwrap();
+        std::fs::write(&file, "").unwrap();
+        std::fs::write(&other_file, "").unwrap();
+        let root = tempdir.join("root");
+
+        let cases = [
+            // The same target for a directory and a file.
+            (&directory, root.join("same"), root.join("same")),
+            // A file where another mount point needed a parent directory.
+            (&other_file, root.join("parent/child"), root.join("parent")),
+        ];
+        for (first_source, first, second) in cases {
+            let mut created = HashMap::new();
+            create_mount_point(first_source, &first, &mut created).unwrap();
+            let err = create_mount_point(&file, &second, &mut created)
+                .unwrap_err()
+                .to_string();
+            assert_eq!(
+                err,
+                format!(
+                    "{} is a file, but the container already has a directory at {} for {}",
+                    file.display(),
+                    second.display(),
+                    first_source.display()
+                )
+            );
+        }
+    }
+    #[test]
+    fn copies_special_files() {
+        let tempdir = TempDir::new().unwrap();
+        tempdir.create().unwrap();
+        let src = tempdir.join("src");
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(src.join("file"), "contents").unwrap();
+        std::os::unix::fs::symlink("file", src.join("link")).unwrap();
+        nix::unistd::mkfifo(&src.join("fifo"), Mode::from_bits_truncate(0o640)).unwrap();
+        let _socket = std::os::unix::net::UnixListener::bind(src.join("socket")).unwrap();
+        let dest = tempdir.join("dest");
+
+        copy_recursively(&src, &dest).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(dest.join("file")).unwrap(),
+            "contents"
+        );
+        assert_eq!(
+            std::fs::read_link(dest.join("link")).unwrap(),
+            Path::new("file")
+        );
+        let fifo = std::fs::symlink_metadata(dest.join("fifo")).unwrap();
+        assert!(fifo.file_type().is_fifo());
+        assert!(!dest.join("socket").exists());
+    }
+}
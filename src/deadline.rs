@@ -0,0 +1,87 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tracing::warn;
+
+use crate::init;
+
+/// How long the container gets to exit after `SIGTERM` before it is killed.
+const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct DeadlineState {
+    pid: AtomicU32,
+    expired: AtomicBool,
+}
+
+impl DeadlineState {
+    /// Terminates the container, unless it already exited.
+    fn terminate(&self) {
+        let pid = self.pid.load(Ordering::SeqCst);
+        if pid != 0 {
+            init::terminate(pid);
+        }
+    }
+}
+
+/// Terminates a container that runs longer than a timeout, first with `SIGTERM` and then,
+/// if it’s still running after a grace period, with `SIGKILL`. Dropping it cancels the
+/// timeout.
+#[derive(Debug)]
+pub struct Deadline {
+    state: Arc<DeadlineState>,
+    _cancel: Sender<()>,
+}
+
+impl Deadline {
+    pub fn start(timeout: Duration, pid: u32) -> Self {
+        let state = Arc::new(DeadlineState {
+            pid: AtomicU32::new(pid),
+            expired: AtomicBool::new(false),
+        });
+        let (cancel, cancelled) = mpsc::channel();
+        let thread_state = state.clone();
+        std::thread::spawn(move || {
+            // Nothing is ever sent, so this only returns early once the sender is dropped.
+            if cancelled.recv_timeout(timeout) != Err(RecvTimeoutError::Timeout) {
+                return;
+            }
+            warn!("Container ran longer than {timeout:?}, stopping it");
+            thread_state.expired.store(true, Ordering::SeqCst);
+            thread_state.terminate();
+            if cancelled.recv_timeout(GRACE_PERIOD) != Err(RecvTimeoutError::Timeout) {
+                return;
+            }
+            if thread_state.pid.load(Ordering::SeqCst) != 0 {
+                warn!("Container didn’t stop within {GRACE_PERIOD:?}, killing it");
+            }
+            thread_state.terminate();
+        });
+        Deadline {
+            state,
+            _cancel: cancel,
+        }
+    }
+
+    /// Updates the PID to terminate, e.g. after the container was restarted.
+    pub fn set_pid(&self, pid: u32) {
+        self.state.pid.store(pid, Ordering::SeqCst);
+    }
+
+    /// Forgets the PID once the container was reaped, so a PID that might have been reused,
+    /// e.g. during a restart backoff, isn’t signalled.
+    pub fn clear_pid(&self) {
+        self.set_pid(0);
+    }
+
+    /// Whether the timeout passed and the container was told to stop.
+    pub fn expired(&self) -> bool {
+        self.state.expired.load(Ordering::SeqCst)
+    }
+}
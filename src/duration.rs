@@ -0,0 +1,48 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use derive_more::derive::Deref;
+
+/// A duration given on the command line, like `300ms`, `30s`, `5m` or `1h`.
+///
+/// A bare number is interpreted as seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deref)]
+pub struct HumanDuration(Duration);
+
+impl From<HumanDuration> for Duration {
+    fn from(value: HumanDuration) -> Self {
+        value.0
+    }
+}
+
+impl FromStr for HumanDuration {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (value, unit) = s.split_at(split);
+        if value.is_empty() {
+            bail!("Invalid duration: {s}");
+        }
+        let value: u64 = value
+            .parse()
+            .with_context(|| format!("Invalid duration: {s}"))?;
+        let duration = match unit {
+            "ms" => Duration::from_millis(value),
+            "" | "s" => Duration::from_secs(value),
+            "m" => Duration::from_secs(value * 60),
+            "h" => Duration::from_secs(value * 60 * 60),
+            _ => bail!("Invalid duration unit `{unit}` in {s}, expected one of ms, s, m, h"),
+        };
+        Ok(HumanDuration(duration))
+    }
+}
+
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.subsec_millis() != 0 {
+            write!(f, "{}ms", self.0.as_millis())
+        } else {
+            write!(f, "{}s", self.0.as_secs())
+        }
+    }
+}
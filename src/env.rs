@@ -4,9 +4,10 @@ use std::{
     str::FromStr,
 };
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EnvVariable {
     pub key: OsString,
     pub value: OsString,
@@ -49,3 +50,109 @@ impl fmt::Display for EnvVariable {
         )
     }
 }
+
+/// Which wins when a variable inherited from the host is also set explicitly.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EnvPrecedence {
+    /// Explicitly set variables override inherited ones.
+    #[default]
+    Explicit,
+    /// Inherited variables override explicitly set ones.
+    Host,
+}
+
+impl FromStr for EnvPrecedence {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "explicit" => Ok(EnvPrecedence::Explicit),
+            "host" => Ok(EnvPrecedence::Host),
+            _ => bail!("Invalid env precedence {s}, expected explicit or host"),
+        }
+    }
+}
+
+impl EnvPrecedence {
+    /// Merges a container’s variables, from lowest to highest precedence: the `defaults`, the
+    /// flake’s, then the ones inherited from the host and the explicitly set ones in the order
+    /// this picks.
+    pub fn merge(
+        self,
+        defaults: &[EnvVariable],
+        flake: &[EnvVariable],
+        host: &[EnvVariable],
+        explicit: &[EnvVariable],
+    ) -> Vec<EnvVariable> {
+        match self {
+            EnvPrecedence::Explicit => merge_env([defaults, flake, host, explicit]),
+            EnvPrecedence::Host => merge_env([defaults, flake, explicit, host]),
+        }
+    }
+}
+
+/// Merges layers of variables into one list. Later layers override earlier ones, and a
+/// variable keeps the position where it was first set.
+pub fn merge_env<'a>(layers: impl IntoIterator<Item = &'a [EnvVariable]>) -> Vec<EnvVariable> {
+    let mut merged: Vec<EnvVariable> = Vec::new();
+    for var in layers.into_iter().flatten() {
+        match merged.iter_mut().find(|existing| existing.key == var.key) {
+            Some(existing) => existing.value.clone_from(&var.value),
+            None => merged.push(var.clone()),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<EnvVariable> {
+        vars.iter()
+            .map(|(key, value)| EnvVariable::new(key, value))
+            .collect()
+    }
+
+    #[test]
+    fn merges_by_precedence() {
+        let defaults = vars(&[("PATH", "/default/bin"), ("TZ", "UTC")]);
+        let flake = vars(&[("PATH", "/flake/bin"), ("FLAKE", "1")]);
+        let host = vars(&[("TERM", "xterm"), ("PATH", "/host/bin")]);
+        let explicit = vars(&[("TERM", "dumb"), ("EXPLICIT", "1")]);
+        let cases = [
+            (
+                EnvPrecedence::Explicit,
+                "PATH=/host/bin TZ=UTC FLAKE=1 TERM=dumb EXPLICIT=1",
+            ),
+            (
+                EnvPrecedence::Host,
+                "PATH=/host/bin TZ=UTC FLAKE=1 TERM=xterm EXPLICIT=1",
+            ),
+        ];
+        for (precedence, expected) in cases {
+            let merged = precedence.merge(&defaults, &flake, &host, &explicit);
+            let merged: Vec<_> = merged.iter().map(ToString::to_string).collect();
+            assert_eq!(merged.join(" "), expected, "{precedence:?}");
+        }
+    }
+
+    #[test]
+    fn merging_keeps_first_position() {
+        let cases = [
+            (
+                vec![vars(&[("A", "1"), ("B", "1")]), vars(&[("A", "2")])],
+                "A=2 B=1",
+            ),
+            (
+                vec![vars(&[("A", "1")]), vars(&[("B", "2"), ("A", "2")])],
+                "A=2 B=2",
+            ),
+            (vec![vars(&[("A", "1"), ("A", "2")]), vec![]], "A=2"),
+        ];
+        for (layers, expected) in cases {
+            let merged = merge_env(layers.iter().map(Vec::as_slice));
+            let merged: Vec<_> = merged.iter().map(ToString::to_string).collect();
+            assert_eq!(merged.join(" "), expected, "{layers:?}");
+        }
+    }
+}
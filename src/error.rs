@@ -0,0 +1,48 @@
+/// The major ways containix can fail.
+///
+/// Functions keep returning [`anyhow::Result`] with descriptive context, but failures in
+/// these areas carry a `ContainixError` in their context chain. Use [`ContainixError::of`] to
+/// find it, e.g. to tell a failed flake build apart from a failed network setup. Only the
+/// innermost, most specific one is attached.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum ContainixError {
+    #[error("Evaluating the container flake failed")]
+    FlakeEval,
+    #[error("Building the container flake failed")]
+    FlakeBuild,
+    #[error("Host tool {0} is not available")]
+    MissingHostTool(String),
+    #[error("Creating namespaces was not permitted")]
+    PermissionDenied,
+    #[error("Setting up the container filesystem failed")]
+    Filesystem,
+    #[error("Setting up the container network failed")]
+    Network,
+    #[error("Starting the container process failed")]
+    Spawn,
+}
+
+impl ContainixError {
+    /// Returns the `ContainixError` attached anywhere in the context chain of `err`.
+    pub fn of(err: &anyhow::Error) -> Option<&ContainixError> {
+        err.downcast_ref()
+    }
+}
+
+pub(crate) trait ResultExt<T> {
+    /// Attaches `error` unless the error already carries a `ContainixError`.
+    fn error_kind(self, error: ContainixError) -> anyhow::Result<T>;
+}
+
+impl<T> ResultExt<T> for anyhow::Result<T> {
+    fn error_kind(self, error: ContainixError) -> anyhow::Result<T> {
+        self.map_err(|err| {
+            if ContainixError::of(&err).is_some() {
+                err
+            } else {
+                err.context(error)
+            }
+        })
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    fs::File,
+    io::Write,
+    os::fd::{FromRawFd, RawFd},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use anyhow::{bail, Context, Result};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use serde::Serialize;
+use tracing::debug;
+
+use crate::health::HealthStatus;
+
+static SINK: Mutex<Option<File>> = Mutex::new(None);
+
+/// A point in a container’s lifecycle, written as one line of JSON like
+/// `{"event":"started","pid":1234,"ports":["8080:80"]}`.
+///
+/// Unlike the logs, these are meant to be parsed, e.g. by a UI or an orchestrator driving
+/// containix. Fields are only ever added, not changed or removed.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// The container flake is about to be built.
+    Building { flake: String },
+    /// The closure to mount into the container is known.
+    ClosureComputed { store_paths: usize },
+    /// The container’s root filesystem is set up.
+    FsAssembled { root: PathBuf },
+    /// The container process is running, after the first start and after each restart.
+    Started { pid: u32, ports: Vec<String> },
+    /// The container process exited. `code` is null if it was killed by a signal.
+    Exited { code: Option<i32> },
+    /// The container’s health check changed its verdict, to `healthy` or `unhealthy`.
+    Health { status: HealthStatus },
+}
+
+/// Writes events to file descriptor `fd` from now on. It’s closed on exec, so the container
+/// doesn’t inherit it.
+pub fn emit_to_fd(fd: RawFd) -> Result<()> {
+    if fd <= nix::libc::STDERR_FILENO {
+        bail!("Events can’t be written to fd {fd}, use --events for stderr");
+    }
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+        .with_context(|| format!("Events fd {fd} isn’t open"))?;
+    // SAFETY: the fd is open and nothing else in containix uses it.
+    emit_to(unsafe { File::from_raw_fd(fd) });
+    Ok(())
+}
+
+/// Writes events to `file` from now on.
+pub fn emit_to(file: File) {
+    *SINK.lock().expect("Events lock poisoned") = Some(file);
+}
+
+/// Writes `event`, if events are enabled. Failing to write doesn’t fail the container.
+pub fn emit(event: Event) {
+    let mut sink = SINK.lock().expect("Events lock poisoned");
+    let Some(file) = sink.as_mut() else {
+        return;
+    };
+    let mut line = serde_json::to_vec(&event).expect("Events serialize to JSON");
+    line.push(b'\n');
+    if let Err(e) = file.write_all(&line) {
+        debug!("Could not write event: {e}");
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    fs::File,
+    io::{Seek, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::{info, instrument, Level};
+
+use crate::{container::enter_root_ns, run::RunSpec, tempdir::TempDir};
+
+/// Builds the flake, assembles the container filesystem like [`run_container`](crate::run::run_container)
+/// would and writes it to `out` as a tar archive.
+///
+/// Like running a container, this enters a new user and mount namespace in the calling process.
+#[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+pub fn export_rootfs(spec: &RunSpec, out: impl Write) -> Result<()> {
+    let built = spec.build_flakes()?;
+    let container_fs = spec.container_fs(&built)?;
+    enter_root_ns(&[], &[])?;
+    let container_fs = container_fs.build().context("Building container fs")?;
+    info!("Exporting {}", container_fs.as_ref().display());
+    write_tar(container_fs.as_ref(), out)
+}
+
+/// Archives everything below `root`. Mounts are walked like any other directory, so the
+/// archive contains the mounted files rather than empty mount points. Symlinks are kept.
+///
+/// Ownership and timestamps are normalized, so exporting the same closure twice yields the
+/// same archive. Inside the user namespace, most files would be owned by the overflow id anyway.
+#[instrument(level = "trace", skip_all, fields(root = %root.display()), err(level = Level::TRACE))]
+pub fn write_tar(root: &Path, out: impl Write) -> Result<()> {
+    let mut builder = tar::Builder::new(out);
+    builder.follow_symlinks(false);
+    builder.mode(tar::HeaderMode::Deterministic);
+    builder
+        .append_dir_all(".", root)
+        .with_context(|| format!("Archiving {}", root.display()))?;
+    builder
+        .into_inner()
+        .context("Finishing archive")?
+        .flush()
+        .context("Writing archive")?;
+    Ok(())
+}
+
+/// Builds the flake and writes it to `out` as an OCI image archive that `docker load` and
+/// `podman load` understand.
+///
+/// The image has a single layer with the container filesystem, the same as
+/// [`export_rootfs`] writes. Its config uses the entry point and environment a container
+/// started from `spec` would get. `tag` (like `name:latest`) is recorded as the image’s name.
+#[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+pub fn export_oci_image(spec: &RunSpec, tag: Option<&str>, out: impl Write) -> Result<()> {
+    let built = spec.build_flakes()?;
+    let container_fs = spec.container_fs(&built)?;
+    let command = spec.command(&built)?;
+    let env = spec.environment(&built)?;
+    enter_root_ns(&[], &[])?;
+    let container_fs = container_fs.build().context("Building container fs")?;
+    info!("Exporting {}", container_fs.as_ref().display());
+
+    // The layer’s digest is only known once it is written, so it is staged in a file.
+    let tempdir = TempDir::with_prefix("containix-oci").context("Creating tempdir")?;
+    tempdir.create()?;
+    let mut layer = DigestWriter::new(
+        File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(tempdir.join("layer.tar"))
+            .context("Creating layer")?,
+    );
+    write_tar(container_fs.as_ref(), &mut layer)?;
+    let (mut layer, layer_size, layer_digest) = layer.finish();
+    layer.rewind().context("Reading layer")?;
+
+    let mut config = json!({
+        "architecture": oci_architecture(),
+        "os": "linux",
+        "config": {
+            "Entrypoint": [command],
+            "Cmd": spec.args,
+            "Env": env.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        },
+        "rootfs": {
+            "type": "layers",
+            // The layer isn’t compressed, so its digest is also its diff id.
+            "diff_ids": [layer_digest],
+        },
+    });
+    if let Some(user) = &spec.user {
+        config["config"]["User"] = json!(user.to_string());
+    }
+    if !spec.labels.is_empty() {
+        config["config"]["Labels"] = json!(spec.labels);
+    }
+    let config = serde_json::to_vec(&config)?;
+    let config_digest = sha256_digest(&config);
+
+    let manifest = serde_json::to_vec(&json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "digest": config_digest,
+            "size": config.len(),
+        },
+        "layers": [{
+            "mediaType": "application/vnd.oci.image.layer.v1.tar",
+            "digest": layer_digest,
+            "size": layer_size,
+        }],
+    }))?;
+    let manifest_digest = sha256_digest(&manifest);
+
+    let mut manifest_descriptor = json!({
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "digest": manifest_digest,
+        "size": manifest.len(),
+    });
+    if let Some(tag) = tag {
+        manifest_descriptor["annotations"] = json!({
+            "io.containerd.image.name": tag,
+            "org.opencontainers.image.ref.name": tag,
+        });
+    }
+    let index = serde_json::to_vec(&json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "manifests": [manifest_descriptor],
+    }))?;
+    // Older Docker versions only load images that come with Docker’s own manifest.
+    let docker_manifest = serde_json::to_vec(&json!([{
+        "Config": blob_path(&config_digest),
+        "RepoTags": tag.into_iter().collect::<Vec<_>>(),
+        "Layers": [blob_path(&layer_digest)],
+    }]))?;
+
+    let mut builder = tar::Builder::new(out);
+    append_bytes(
+        &mut builder,
+        "oci-layout",
+        br#"{"imageLayoutVersion":"1.0.0"}"#,
+    )?;
+    append_bytes(&mut builder, "index.json", &index)?;
+    append_bytes(&mut builder, "manifest.json", &docker_manifest)?;
+    append_bytes(&mut builder, &blob_path(&config_digest), &config)?;
+    append_bytes(&mut builder, &blob_path(&manifest_digest), &manifest)?;
+    let mut header = blob_header(layer_size);
+    builder
+        .append_data(&mut header, blob_path(&layer_digest), &mut layer)
+        .context("Archiving layer")?;
+    builder
+        .into_inner()
+        .context("Finishing archive")?
+        .flush()
+        .context("Writing archive")?;
+    Ok(())
+}
+
+/// Passes writes through to the inner writer while hashing them.
+struct DigestWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> DigestWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    /// Returns the inner writer, the number of bytes written and their digest.
+    fn finish(self) -> (W, u64, String) {
+        let digest = format!("sha256:{:x}", self.hasher.finalize());
+        (self.inner, self.size, digest)
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+fn blob_path(digest: &str) -> String {
+    format!("blobs/{}", digest.replacen(':', "/", 1))
+}
+
+fn blob_header(size: u64) -> tar::Header {
+    let mut header = tar::Header::new_ustar();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header
+}
+
+fn append_bytes<W: Write>(builder: &mut tar::Builder<W>, path: &str, data: &[u8]) -> Result<()> {
+    let mut header = blob_header(data.len() as u64);
+    builder
+        .append_data(&mut header, path, data)
+        .with_context(|| format!("Archiving {path}"))
+}
+
+/// The host’s architecture, in the names Go (and with it OCI) uses.
+fn oci_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        "powerpc64" => "ppc64le",
+        "loongarch64" => "loong64",
+        arch => arch,
+    }
+}
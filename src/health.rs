@@ -0,0 +1,124 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::{
+    container::ContainerExec,
+    events::{self, Event},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[repr(u8)]
+pub enum HealthStatus {
+    /// No health check has succeeded or exhausted its retries yet.
+    Starting,
+    Healthy,
+    Unhealthy,
+}
+
+impl From<u8> for HealthStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => HealthStatus::Healthy,
+            2 => HealthStatus::Unhealthy,
+            _ => HealthStatus::Starting,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    /// Program and arguments, run inside the container without a shell.
+    pub command: Vec<String>,
+    pub interval: Duration,
+    /// Number of consecutive failures after which the container is considered unhealthy.
+    pub retries: u32,
+}
+
+#[derive(Debug, Default)]
+struct HealthState {
+    status: AtomicU8,
+    stop: AtomicBool,
+}
+
+/// Periodically runs a health check inside a container. The checks stop when this is dropped.
+#[derive(Debug)]
+pub struct HealthMonitor(Arc<HealthState>);
+
+impl HealthMonitor {
+    pub fn start(check: HealthCheck, exec: ContainerExec) -> Self {
+        let state = Arc::new(HealthState::default());
+        let thread_state = state.clone();
+        std::thread::spawn(move || run_checks(check, exec, thread_state));
+        HealthMonitor(state)
+    }
+
+    pub fn status(&self) -> HealthStatus {
+        self.0.status.load(Ordering::SeqCst).into()
+    }
+}
+
+impl Drop for HealthMonitor {
+    fn drop(&mut self) {
+        self.0.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+fn run_checks(check: HealthCheck, exec: ContainerExec, state: Arc<HealthState>) {
+    let Some((program, args)) = check.command.split_first() else {
+        warn!("Empty health check command");
+        return;
+    };
+
+    let mut failures = 0;
+    loop {
+        std::thread::sleep(check.interval);
+        if state.stop.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let passed = match exec.output(program, args) {
+            Ok(output) => {
+                debug!(
+                    "Health check exited with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr)
+                );
+                output.status.success()
+            }
+            Err(e) => {
+                debug!("Failed to run health check: {e:#}");
+                false
+            }
+        };
+
+        let previous = HealthStatus::from(state.status.load(Ordering::SeqCst));
+        let status = if passed {
+            failures = 0;
+            HealthStatus::Healthy
+        } else {
+            failures += 1;
+            if failures < check.retries {
+                continue;
+            }
+            HealthStatus::Unhealthy
+        };
+
+        state.status.store(status as u8, Ordering::SeqCst);
+        if status != previous {
+            match status {
+                HealthStatus::Healthy => info!("Container is healthy"),
+                _ => warn!("Container is unhealthy after {failures} failed health checks"),
+            }
+            events::emit(Event::Health { status });
+        }
+    }
+}
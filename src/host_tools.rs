@@ -3,34 +3,137 @@ use std::{
     sync::OnceLock,
 };
 
-use anyhow::{bail, Result};
-use tracing::{instrument, Level};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{instrument, trace, Level};
 
-use crate::nix_helpers::NixFlake;
+use crate::{
+    error::{ContainixError, ResultExt},
+    nix_helpers::NixFlake,
+};
+
+static HOST_TOOLS: OnceLock<HostTools> = OnceLock::new();
 
-static HOST_TOOLS: OnceLock<PathBuf> = OnceLock::new();
+/// Environment variable with a nixpkgs flake to build the host tools with.
+pub const NIXPKGS_ENV: &str = "CONTAINIX_NIXPKGS";
+
+/// The tools containix runs on the host (like slirp4netns), collected from one or more
+/// store paths and optionally the host’s `PATH`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostTools {
+    paths: Vec<PathBuf>,
+    path_fallback: bool,
+}
+
+impl HostTools {
+    /// Finds `binary` in the `bin` directories of the host tools, in the order they were given,
+    /// falling back to the host’s `PATH` if enabled.
+    pub fn find(&self, binary: impl AsRef<Path>) -> Option<PathBuf> {
+        let binary = binary.as_ref();
+        let found = self
+            .paths
+            .iter()
+            .map(|path| path.join("bin").join(binary))
+            .find(|candidate| candidate.exists());
+        if found.is_some() || !self.path_fallback {
+            return found;
+        }
+        let path = std::env::var_os("PATH")?;
+        std::env::split_paths(&path)
+            .map(|dir| dir.join(binary))
+            .find(|candidate| candidate.exists())
+    }
+
+    /// Like `find`, but fails with an error naming the searched locations.
+    pub fn require(&self, binary: impl AsRef<Path>) -> Result<PathBuf> {
+        let binary = binary.as_ref();
+        let found = self
+            .find(binary)
+            .with_context(|| {
+                format!(
+                    "{} not found in host tools ({}){}",
+                    binary.display(),
+                    self.paths
+                        .iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    if self.path_fallback { " or $PATH" } else { "" }
+                )
+            })
+            .error_kind(ContainixError::MissingHostTool(
+                binary.display().to_string(),
+            ))?;
+        trace!("Using host tool {}", found.display());
+        Ok(found)
+    }
+}
 
+impl HostTools {
+    /// Resolves the host tools from a comma-separated list of store paths and flakes.
+    ///
+    /// `nixpkgs` replaces the `nixpkgs` input of the host tools flakes, so they can reuse a
+    /// nixpkgs that is already fetched. If it is unset, `$CONTAINIX_NIXPKGS` is used, and
+    /// otherwise the flakes’ own pin.
+    #[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+    pub fn resolve(
+        host_tools: impl AsRef<str>,
+        refresh: bool,
+        path_fallback: bool,
+        nixpkgs: Option<&str>,
+    ) -> Result<Self> {
+        let nixpkgs = nixpkgs
+            .map(str::to_string)
+            .or_else(|| std::env::var(NIXPKGS_ENV).ok())
+            .filter(|nixpkgs| !nixpkgs.is_empty());
+        let paths = host_tools
+            .as_ref()
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(|host_tools| resolve_host_tools(host_tools, refresh, nixpkgs.as_deref()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(HostTools {
+            paths,
+            path_fallback,
+        })
+    }
+}
+
+/// Resolves the host tools like [`HostTools::resolve`] and makes them the process-wide
+/// default for containers that weren’t given their own. The default can only be set once.
 #[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
-pub fn setup_host_tools(host_tools: impl AsRef<str>, refresh: bool) -> Result<()> {
-    let host_tools = host_tools.as_ref();
-    let path = if host_tools.starts_with("/nix/store") {
-        PathBuf::from(host_tools)
-    } else {
-        let flake: NixFlake = host_tools.parse()?;
-        let flake_build = flake.build(|args| {
-            args.refresh(refresh);
-        })?;
-        let Some(item) = flake_build.get_bin() else {
-            bail!("Host tools flake did not build any packages");
-        };
-        item.path()
-    };
-    HOST_TOOLS
-        .set(path)
-        .expect("Global host tools path must be unset at this point");
+pub fn setup_host_tools(
+    host_tools: impl AsRef<str>,
+    refresh: bool,
+    path_fallback: bool,
+    nixpkgs: Option<&str>,
+) -> Result<()> {
+    let host_tools = HostTools::resolve(host_tools, refresh, path_fallback, nixpkgs)?;
+    if HOST_TOOLS.set(host_tools).is_err() {
+        bail!("The default host tools are already set up");
+    }
     Ok(())
 }
 
-pub fn get_host_tools() -> &'static Path {
-    HOST_TOOLS.get().expect("Host tools must be set").as_path()
+fn resolve_host_tools(host_tools: &str, refresh: bool, nixpkgs: Option<&str>) -> Result<PathBuf> {
+    if host_tools.starts_with("/nix/store") {
+        return Ok(PathBuf::from(host_tools));
+    }
+    let flake: NixFlake = host_tools.parse()?;
+    let flake_build = flake.build(|args| {
+        args.refresh(refresh);
+        if let Some(nixpkgs) = nixpkgs {
+            args.override_input("nixpkgs", nixpkgs);
+        }
+    })?;
+    let Some(item) = flake_build.get_bin() else {
+        bail!("Host tools flake {host_tools} did not build any packages");
+    };
+    Ok(item.path())
+}
+
+/// The default host tools set up with [`setup_host_tools`], if any.
+pub fn default_host_tools() -> Option<&'static HostTools> {
+    HOST_TOOLS.get()
 }
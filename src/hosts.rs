@@ -0,0 +1,54 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// An additional `/etc/hosts` entry, written as `NAME:IP`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostEntry {
+    pub name: String,
+    pub ip: IpAddr,
+}
+
+impl FromStr for HostEntry {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        // IPv6 addresses contain colons themselves, so only split at the first one.
+        let Some((name, ip)) = s.split_once(':') else {
+            anyhow::bail!("Invalid host entry {s}, expected NAME:IP");
+        };
+        if name.is_empty() {
+            anyhow::bail!("Host entry {s} is missing a name");
+        }
+        Ok(HostEntry {
+            name: name.to_string(),
+            ip: ip
+                .parse()
+                .with_context(|| format!("Invalid IP in host entry {s}"))?,
+        })
+    }
+}
+
+impl fmt::Display for HostEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.name, self.ip)
+    }
+}
+
+/// Renders an `/etc/hosts` with localhost, the container’s `hostname` and `entries`.
+pub fn render_hosts(hostname: &str, entries: &[HostEntry]) -> String {
+    let mut hosts = String::from("127.0.0.1\tlocalhost\n::1\tlocalhost\n");
+    hosts.push_str(&format!("127.0.1.1\t{hostname}\n"));
+    for entry in entries {
+        hosts.push_str(&format!("{}\t{}\n", entry.ip, entry.name));
+    }
+    hosts
+}
+
+/// Renders an `/etc/resolv.conf` that queries `nameservers`, in order.
+pub fn render_resolv_conf(nameservers: &[IpAddr]) -> String {
+    nameservers
+        .iter()
+        .map(|nameserver| format!("nameserver {nameserver}\n"))
+        .collect()
+}
@@ -0,0 +1,133 @@
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+use nix::{
+    sys::{
+        signal::{self, SigHandler, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{fork, ForkResult, Pid},
+};
+use tracing::{debug, error, info};
+
+/// PID of the container command, which signals are forwarded to.
+static MAIN_CHILD: AtomicI32 = AtomicI32::new(0);
+
+const FORWARDED_SIGNALS: &[Signal] = &[
+    Signal::SIGTERM,
+    Signal::SIGINT,
+    Signal::SIGHUP,
+    Signal::SIGQUIT,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+];
+
+/// Set once a termination signal was received.
+static TERMINATING: AtomicBool = AtomicBool::new(false);
+/// Kill the main child on a second termination signal instead of forwarding it.
+static ESCALATE: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn forward_signal(signal: nix::libc::c_int) {
+    let mut signal = signal;
+    let termination = [
+        nix::libc::SIGTERM,
+        nix::libc::SIGINT,
+        nix::libc::SIGHUP,
+        nix::libc::SIGQUIT,
+    ]
+    .contains(&signal);
+    if termination && TERMINATING.swap(true, Ordering::SeqCst) && ESCALATE.load(Ordering::SeqCst) {
+        signal = nix::libc::SIGKILL;
+    }
+    let pid = MAIN_CHILD.load(Ordering::SeqCst);
+    if pid > 0 {
+        // SAFETY: kill is async-signal-safe.
+        unsafe { nix::libc::kill(pid, signal) };
+    }
+}
+
+/// Forwards signals to `pid` like the container’s init does, for a process that cleans up
+/// once `pid` exits. Instead of being terminated itself, it can check [`terminating`]. A
+/// repeated termination signal kills `pid`, e.g. if it ignores `SIGTERM` as PID 1 without a
+/// handler does.
+pub fn supervise_signals(pid: u32) {
+    ESCALATE.store(true, Ordering::SeqCst);
+    forward_signals_to(Pid::from_raw(pid as i32));
+}
+
+/// Whether `SIGTERM`, `SIGINT`, `SIGHUP` or `SIGQUIT` was received and forwarded.
+pub fn terminating() -> bool {
+    TERMINATING.load(Ordering::SeqCst)
+}
+
+/// Terminates `pid` as if this process received `SIGTERM`, e.g. because it ran for too long.
+/// Like a repeated termination signal, calling it again kills `pid`.
+pub fn terminate(pid: u32) {
+    ESCALATE.store(true, Ordering::SeqCst);
+    MAIN_CHILD.store(pid as i32, Ordering::SeqCst);
+    forward_signal(nix::libc::SIGTERM);
+}
+
+/// Stops forwarding signals once the main child has been reaped, as its PID might be reused.
+/// Signals received afterwards are still noted for [`terminating`].
+pub fn child_exited() {
+    MAIN_CHILD.store(0, Ordering::SeqCst);
+}
+
+/// Forwards termination and user signals this process receives to `pid`.
+pub(crate) fn forward_signals_to(pid: Pid) {
+    MAIN_CHILD.store(pid.as_raw(), Ordering::SeqCst);
+    for signal in FORWARDED_SIGNALS {
+        // SAFETY: the handler only calls async-signal-safe functions.
+        if let Err(e) = unsafe { signal::signal(*signal, SigHandler::Handler(forward_signal)) } {
+            error!("Failed to install handler for {signal}: {e}");
+        }
+    }
+}
+
+/// Runs as PID 1 of the container: starts the container command via `command` in a child
+/// process, forwards signals to it and reaps orphaned processes until it exits.
+///
+/// Returns the exit status of the command, or 128 + the signal number if it was killed.
+pub fn run(command: impl FnOnce() -> isize) -> isize {
+    // SAFETY: the child only execs the container command.
+    let child = match unsafe { fork() } {
+        Ok(ForkResult::Child) => std::process::exit(command() as i32),
+        Ok(ForkResult::Parent { child }) => child,
+        Err(e) => {
+            error!("Init failed to fork: {e}");
+            return -1;
+        }
+    };
+    // PID 1 ignores all signals it has no handler for, even when sent from the host.
+    forward_signals_to(child);
+
+    let mut orphans = 0;
+    let status = loop {
+        match waitpid(Pid::from_raw(-1), None) {
+            Ok(WaitStatus::Exited(pid, code)) if pid == child => break code as isize,
+            Ok(WaitStatus::Signaled(pid, signal, _)) if pid == child => {
+                break 128 + signal as isize
+            }
+            Ok(WaitStatus::Exited(pid, _) | WaitStatus::Signaled(pid, _, _)) => {
+                debug!("Init reaped orphaned process {pid}");
+                orphans += 1;
+            }
+            Ok(_) => {}
+            Err(nix::errno::Errno::EINTR) => {}
+            Err(e) => {
+                error!("Init failed to wait for children: {e}");
+                break -1;
+            }
+        }
+    };
+    child_exited();
+
+    // Collect whatever exited together with the main process.
+    while let Ok(WaitStatus::Exited(..) | WaitStatus::Signaled(..)) =
+        waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG))
+    {
+        orphans += 1;
+    }
+    info!("Container init reaped {orphans} orphaned processes");
+    status
+}
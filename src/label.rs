@@ -0,0 +1,30 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Result};
+
+/// A metadata label, written as `KEY=VALUE`. Without a `=`, the value is empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub key: String,
+    pub value: String,
+}
+
+impl FromStr for Label {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, value) = s.split_once('=').unwrap_or((s, ""));
+        if key.is_empty() {
+            bail!("Label {s} is missing a key");
+        }
+        Ok(Label {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
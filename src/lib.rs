@@ -1,13 +1,34 @@
 pub mod cli_wrappers;
 pub mod command;
 pub mod ports;
+pub mod prune;
+pub mod restart;
+pub mod rlimit;
+pub mod run;
+pub mod seccomp;
+pub mod size;
+pub mod spinner;
 pub mod tempdir;
+pub mod timezone;
+pub mod timings;
 pub mod unshare;
+pub mod user;
 pub mod volume_mount;
 
+pub mod capabilities;
+pub mod cgroup;
 pub mod container;
+pub mod deadline;
+pub mod duration;
 pub mod env;
+pub mod error;
+pub mod events;
+pub mod export;
+pub mod health;
 pub mod host_tools;
+pub mod hosts;
+pub mod init;
+pub mod label;
 pub mod mount;
 pub mod nix_helpers;
 pub mod path_ext;
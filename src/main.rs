@@ -1,54 +1,478 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::IsTerminal;
 use std::mem::ManuallyDrop;
+use std::net::IpAddr;
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 use anyhow::{bail, Context, Result};
-use clap::Parser;
-use containix::command::ChildProcess;
-use containix::container::{ContainerBuilder, ContainerFsBuilder};
-use containix::env::EnvVariable;
-use containix::host_tools::setup_host_tools;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use containix::capabilities::{capabilities_to_drop, CapabilityArg};
+use containix::cli_wrappers::nix::set_retries;
+use containix::command::{run_command, ChildProcess};
+use containix::deadline::Deadline;
+use containix::duration::HumanDuration;
+use containix::env::{EnvPrecedence, EnvVariable};
+use containix::events::{self, Event};
+use containix::export::{export_oci_image, export_rootfs};
+use containix::health::{HealthCheck, HealthMonitor, HealthStatus};
+use containix::host_tools::HostTools;
+use containix::hosts::HostEntry;
+use containix::init;
+use containix::label::Label;
 use containix::nix_helpers::ContainixFlake;
+use containix::path_ext::PathExt;
 use containix::ports::PortMapping;
-use containix::unshare::{UnshareEnvironmentBuilder, UnshareNamespaces};
-use containix::volume_mount::VolumeMount;
-use tracing::{debug, info, instrument, trace, warn, Level};
-use tracing_subscriber::{fmt, fmt::format::FmtSpan, EnvFilter};
+use containix::prune::{find_leftovers, remove_leftover};
+use containix::restart::RestartPolicy;
+use containix::rlimit::Rlimit;
+use containix::run::{launch_config, run_container, RunSpecBuilder};
+use containix::seccomp::SeccompProfile;
+use containix::size::ByteSize;
+use containix::timezone::Timezone;
+use containix::timings;
+use containix::unshare::{IdRangeMap, UnshareNamespaces};
+use containix::user::UserSpec;
+use containix::volume_mount::{Mount, VolumeMount};
+use tracing::{debug, error, info, instrument, trace, warn, Level, Subscriber};
+use tracing_subscriber::{fmt, fmt::format::FmtSpan, util::SubscriberInitExt, EnvFilter};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    /// Running a container is the default when no subcommand is given.
+    #[command(flatten)]
+    run: Option<RunArgs>,
+
+    /// Only log warnings and errors. CONTAINIX_LOG takes precedence.
+    #[arg(short = 'q', long = "quiet", global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log more details, repeat for even more. CONTAINIX_LOG takes precedence.
+    #[arg(long = "verbose", global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+impl Cli {
+    fn log_level(&self) -> Level {
+        match (self.quiet, self.verbose) {
+            (true, _) => Level::WARN,
+            (false, 0) => Level::INFO,
+            (false, 1) => Level::DEBUG,
+            (false, _) => Level::TRACE,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Build and run a container (the default).
+    Run(Box<RunArgs>),
+    /// Build a container flake without running it.
+    Build(BuildArgs),
+    /// Build a container flake and print the command and environment it would run with.
+    Config(ConfigArgs),
+    /// Evaluate an attribute of a container flake and print the result.
+    Eval(EvalArgs),
+    /// Build a container flake and write its root filesystem to a tar archive.
+    Export(ExportArgs),
+    /// Build a container flake and write it to a single-layer OCI image archive for `docker load`.
+    OciExport(OciExportArgs),
+    /// Remove temporary directories left behind by containix processes that didn’t exit cleanly.
+    Prune(PruneArgs),
+}
+
+#[derive(Args, Debug)]
+struct PruneArgs {
+    /// Only list what would be removed.
+    #[arg(long = "dry-run")]
+    dry_run: bool,
+}
+
+#[derive(Args, Debug)]
+struct BuildArgs {
+    /// Nix flake container
+    #[arg(short = 'f', long = "flake", value_name = "NIX FLAKE")]
+    flake: ContainixFlake,
+
+    /// Create a symlink to the build result at this path. It acts as a GC root, so the container survives garbage collection.
+    #[arg(long = "out-link", value_name = "PATH")]
+    out_link: Option<PathBuf>,
+
+    /// (Nix passthru:) Allow the flake to access mutable paths and environment variables during evaluation.
+    #[arg(long = "impure")]
+    impure: bool,
+
+    /// (Nix passthru:) Consider all previously downloaded files out-of-date.
+    #[arg(long = "refresh", default_value_t = false)]
+    refresh: bool,
+
+    /// (Nix passthru as --max-jobs:) Maximum number of derivations built in parallel.
+    #[arg(long = "build-jobs", value_name = "N")]
+    build_jobs: Option<u32>,
+
+    /// (Nix passthru as --cores:) Number of cores each build job may use, 0 for all.
+    #[arg(long = "build-cores", value_name = "N")]
+    build_cores: Option<u32>,
+
+    /// Argument appended verbatim to `nix build`, e.g. --nix-arg=--accept-flake-config. Repeat it for more arguments. Arguments that change nix’s output, like --print-build-logs or --log-format, can break containix reading it.
+    #[arg(long = "nix-arg", value_name = "ARG", allow_hyphen_values = true)]
+    nix_args: Vec<String>,
+
+    /// Retry nix builds and evaluations up to N times when they fail to fetch something.
+    #[arg(long = "retries", value_name = "N", default_value_t = 0)]
+    retries: u32,
+}
+
+#[derive(Args, Debug)]
+struct ConfigArgs {
     /// Nix flake container
     #[arg(short = 'f', long = "flake", value_name = "NIX FLAKE")]
     flake: ContainixFlake,
 
+    /// Merge the closure of another flake into the container. Its binaries are added to PATH after the main flake’s.
+    #[arg(long = "with", value_name = "NIX FLAKE")]
+    with_flakes: Vec<ContainixFlake>,
+
     /// Arguments to pass to the container entry point.
     #[arg(trailing_var_arg = true)]
     args: Vec<String>,
 
+    /// Run this command instead of the flake’s entry point.
+    #[arg(long = "entrypoint", value_name = "COMMAND")]
+    entrypoint: Option<String>,
+
+    /// Pass this as argv[0] to the command instead of its path.
+    #[arg(long = "argv0", value_name = "NAME")]
+    argv0: Option<String>,
+
     /// Environment variables to set in the container.
     #[arg(short = 'e', long = "env", value_name = "KEY=VALUE")]
     env: Vec<EnvVariable>,
 
-    /// Set the uid of the user running the container.
+    /// Don’t set PATH to the flake’s bin directory.
+    #[arg(long = "no-default-path")]
+    no_default_path: bool,
+
+    /// Set the environment variables the flake declares in passthru.containix.env.
+    #[arg(long = "env-from-flake")]
+    env_from_flake: bool,
+
+    /// Timezone for the container: host, a name like Europe/London to set TZ, or none.
+    #[arg(long = "timezone", value_name = "TIMEZONE", default_value = "none")]
+    timezone: Timezone,
+
+    /// Print JSON instead of shell that exports the environment and sets the positional parameters to the argv.
+    #[arg(long = "json")]
+    json: bool,
+
+    /// (Nix passthru:) Allow the flake to access mutable paths and environment variables during evaluation.
+    #[arg(long = "impure")]
+    impure: bool,
+
+    /// (Nix passthru:) Consider all previously downloaded files out-of-date.
+    #[arg(long = "refresh", default_value_t = false)]
+    refresh: bool,
+
+    /// (Nix passthru as --max-jobs:) Maximum number of derivations built in parallel.
+    #[arg(long = "build-jobs", value_name = "N")]
+    build_jobs: Option<u32>,
+
+    /// (Nix passthru as --cores:) Number of cores each build job may use, 0 for all.
+    #[arg(long = "build-cores", value_name = "N")]
+    build_cores: Option<u32>,
+
+    /// Retry nix builds and evaluations up to N times when they fail to fetch something.
+    #[arg(long = "retries", value_name = "N", default_value_t = 0)]
+    retries: u32,
+}
+
+#[derive(Args, Debug)]
+struct EvalArgs {
+    /// Nix flake container
+    #[arg(short = 'f', long = "flake", value_name = "NIX FLAKE")]
+    flake: ContainixFlake,
+
+    /// Attribute path into the flake’s outputs, e.g. packages.x86_64-linux.default.passthru.containix. Defaults to the container package.
+    #[arg(long = "attr", value_name = "ATTR")]
+    attr: Option<String>,
+
+    /// Print the result as JSON instead of in Nix syntax.
+    #[arg(long = "json")]
+    json: bool,
+
+    /// (Nix passthru:) Allow the flake to access mutable paths and environment variables during evaluation.
+    #[arg(long = "impure")]
+    impure: bool,
+
+    /// Retry nix evaluations up to N times when they fail to fetch something.
+    #[arg(long = "retries", value_name = "N", default_value_t = 0)]
+    retries: u32,
+}
+
+#[derive(Args, Debug)]
+struct ExportArgs {
+    /// Nix flake container
+    #[arg(short = 'f', long = "flake", value_name = "NIX FLAKE")]
+    flake: ContainixFlake,
+
+    /// Write the archive to this file. Use - for stdout.
+    #[arg(short = 'o', long = "output", value_name = "PATH")]
+    output: PathBuf,
+
+    /// Leave store paths whose name (without the hash) matches this pattern out of the archive. `*` and `?` are wildcards.
+    #[arg(long = "exclude-path", value_name = "PATTERN")]
+    exclude_paths: Vec<String>,
+
+    /// (Nix passthru:) Allow the flake to access mutable paths and environment variables during evaluation.
+    #[arg(long = "impure")]
+    impure: bool,
+
+    /// (Nix passthru:) Consider all previously downloaded files out-of-date.
+    #[arg(long = "refresh", default_value_t = false)]
+    refresh: bool,
+
+    /// (Nix passthru as --max-jobs:) Maximum number of derivations built in parallel.
+    #[arg(long = "build-jobs", value_name = "N")]
+    build_jobs: Option<u32>,
+
+    /// (Nix passthru as --cores:) Number of cores each build job may use, 0 for all.
+    #[arg(long = "build-cores", value_name = "N")]
+    build_cores: Option<u32>,
+
+    /// Retry nix builds and evaluations up to N times when they fail to fetch something.
+    #[arg(long = "retries", value_name = "N", default_value_t = 0)]
+    retries: u32,
+}
+
+#[derive(Args, Debug)]
+struct OciExportArgs {
+    /// Nix flake container
+    #[arg(short = 'f', long = "flake", value_name = "NIX FLAKE")]
+    flake: ContainixFlake,
+
+    /// Write the image archive to this file. Use - for stdout.
+    #[arg(short = 'o', long = "output", value_name = "PATH")]
+    output: PathBuf,
+
+    /// Name of the image, e.g. myimage:latest.
+    #[arg(short = 't', long = "tag", value_name = "NAME[:TAG]")]
+    tag: Option<String>,
+
+    /// Default arguments for the image’s entry point.
+    #[arg(trailing_var_arg = true)]
+    args: Vec<String>,
+
+    /// Use this command as the image’s entry point instead of the flake’s containix-entry-point.
+    #[arg(long = "entrypoint", value_name = "COMMAND")]
+    entrypoint: Option<String>,
+
+    /// Environment variables to set in the image.
+    #[arg(short = 'e', long = "env", value_name = "KEY=VALUE")]
+    env: Vec<EnvVariable>,
+
+    /// Label to add to the image’s config. Can be given multiple times.
+    #[arg(short = 'l', long = "label", value_name = "KEY=VALUE")]
+    labels: Vec<Label>,
+
+    /// User (and group) the image runs as.
+    #[arg(short = 'u', long = "user", value_name = "USER[:GROUP]")]
+    user: Option<UserSpec>,
+
+    /// Leave store paths whose name (without the hash) matches this pattern out of the image. `*` and `?` are wildcards.
+    #[arg(long = "exclude-path", value_name = "PATTERN")]
+    exclude_paths: Vec<String>,
+
+    /// (Nix passthru:) Allow the flake to access mutable paths and environment variables during evaluation.
+    #[arg(long = "impure")]
+    impure: bool,
+
+    /// (Nix passthru:) Consider all previously downloaded files out-of-date.
+    #[arg(long = "refresh", default_value_t = false)]
+    refresh: bool,
+
+    /// (Nix passthru as --max-jobs:) Maximum number of derivations built in parallel.
+    #[arg(long = "build-jobs", value_name = "N")]
+    build_jobs: Option<u32>,
+
+    /// (Nix passthru as --cores:) Number of cores each build job may use, 0 for all.
+    #[arg(long = "build-cores", value_name = "N")]
+    build_cores: Option<u32>,
+
+    /// Retry nix builds and evaluations up to N times when they fail to fetch something.
+    #[arg(long = "retries", value_name = "N", default_value_t = 0)]
+    retries: u32,
+}
+
+/// The network a container gets with `--network`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum NetworkMode {
+    /// Access to the host’s network through slirp4netns.
+    Slirp,
+    /// Only a loopback interface.
+    None,
+}
+
+#[derive(Args, Debug)]
+struct RunArgs {
+    /// Read more arguments from a JSON object, or from stdin with -. Keys are long flag names like
+    /// "volume", values are strings, numbers, true for switches, lists for repeated flags or objects
+    /// for NAME=VALUE flags like "env". "args" are passed to the entry point.
+    #[arg(long = "config", value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Nix flake container
+    #[arg(short = 'f', long = "flake", value_name = "NIX FLAKE")]
+    flake: ContainixFlake,
+
+    /// Merge the closure of another flake into the container, e.g. debugging tools. Its binaries are added to PATH after the main flake’s.
+    #[arg(long = "with", value_name = "NIX FLAKE")]
+    with_flakes: Vec<ContainixFlake>,
+
+    /// Arguments to pass to the container entry point.
+    #[arg(trailing_var_arg = true)]
+    args: Vec<String>,
+
+    /// Run this command instead of the flake’s containix-entry-point. Positional arguments are passed to it.
+    #[arg(long = "entrypoint", value_name = "COMMAND")]
+    entrypoint: Option<String>,
+
+    /// Pass this as argv[0] to the command instead of its path, e.g. to pick the applet of a multi-call binary like busybox.
+    #[arg(long = "argv0", value_name = "NAME")]
+    argv0: Option<String>,
+
+    /// Environment variables to set in the container.
+    #[arg(short = 'e', long = "env", value_name = "KEY=VALUE")]
+    env: Vec<EnvVariable>,
+
+    /// Whether variables from -e (explicit) or those inherited from the host, like TERM, win when both set the same variable. PATH and TZ can be overridden by either.
+    #[arg(
+        long = "env-precedence",
+        value_name = "explicit|host",
+        default_value = "explicit"
+    )]
+    env_precedence: EnvPrecedence,
+
+    /// Don’t set PATH to the flake’s bin directory. The container then only has a PATH if one is passed with -e.
+    #[arg(long = "no-default-path")]
+    no_default_path: bool,
+
+    /// Set the environment variables the flake declares in passthru.containix.env. -e overrides them.
+    #[arg(long = "env-from-flake")]
+    env_from_flake: bool,
+
+    // /// Set the uid of the user running the container.
     // #[arg(long = "set-uid", value_name = "UID")]
     // set_uid: Option<u32>,
 
-    /// Set the gid of the user running the container.
+    // /// Set the gid of the user running the container.
     // #[arg(long = "set-gid", value_name = "GID")]
     // set_gid: Option<u32>,
+    /// User (and group) to run the container command as. Names are looked up in the container’s /etc/passwd and /etc/group.
+    #[arg(short = 'u', long = "user", value_name = "USER[:GROUP]")]
+    user: Option<UserSpec>,
+
+    /// Directory inside the container the entry point starts in. Defaults to /.
+    #[arg(short = 'w', long = "workdir", value_name = "CONTAINER_PATH")]
+    workdir: Option<PathBuf>,
+
+    /// Mount the current directory at CONTAINER_PATH, /workspace by default, and start the entry point there unless --workdir is given. The path must be given with =, like --cwd=/src.
+    #[arg(
+        short = 'C',
+        long = "cwd",
+        value_name = "CONTAINER_PATH",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "/workspace"
+    )]
+    cwd: Option<PathBuf>,
 
-    /// Volumes to mount into the container.
-    #[arg(short = 'v', long = "volume", value_name = "HOST_PATH:CONTAINER_PATH")]
+    /// Volumes to mount into the container. Options: ro (read-only) or copy (copy instead of mounting).
+    #[arg(
+        short = 'v',
+        long = "volume",
+        value_name = "HOST_PATH:CONTAINER_PATH[:OPTIONS]"
+    )]
     volumes: Vec<VolumeMount>,
 
+    /// Mount with explicit options: `type=bind,source=PATH,target=PATH[,ro]`, `type=tmpfs,target=PATH[,size=64m][,mode=1777]` or `type=overlay,source=PATH,target=PATH`. Overlays make a host directory writable in the container without changing it.
+    #[arg(long = "mount", value_name = "type=TYPE,OPTIONS")]
+    mounts: Vec<Mount>,
+
+    /// Network for the container.
+    #[arg(long = "network", value_name = "MODE", value_enum, default_value_t = NetworkMode::Slirp)]
+    network: NetworkMode,
+
+    /// Run the container without network (and a warning) if slirp4netns isn’t available, instead of failing.
+    #[arg(long = "network-optional")]
+    network_optional: bool,
+
+    /// Start the container command only once its network is up, and fail with a network error if it doesn’t come up. Ignored without network.
+    #[arg(long = "wait-net")]
+    wait_net: bool,
+
     /// Ports to expose to the host.
-    #[arg(short = 'p', long = "port", value_name = "HOST_PORT:CONTAINER_PORT")]
+    #[arg(
+        short = 'p',
+        long = "port",
+        value_name = "[HOST_IP:]HOST_PORT:CONTAINER_PORT[/PROTO]"
+    )]
     ports: Vec<PortMapping>,
 
+    /// Make a store path from the container’s closure writable through an overlay. Changes are discarded with the container.
+    #[arg(long = "rw-component", value_name = "STORE_PATH")]
+    rw_components: Vec<PathBuf>,
+
+    /// Leave store paths whose name (without the hash) matches this pattern out of the container’s closure. `*` and `?` are wildcards. Dropping a path the container needs at runtime breaks it.
+    #[arg(long = "exclude-path", value_name = "PATTERN")]
+    exclude_paths: Vec<String>,
+
+    /// Fail if the container’s closure is larger than this, e.g. 500M or 2G. Excluded paths don’t count.
+    #[arg(long = "max-size", value_name = "SIZE")]
+    max_size: Option<ByteSize>,
+
+    /// Create an empty, writable directory in the container. Useful for scratch space on a read-only root.
+    #[arg(long = "mkdir", value_name = "CONTAINER_PATH")]
+    directories: Vec<PathBuf>,
+
+    /// Don’t mount a minimal /etc/passwd and /etc/group with root and the --user into the container.
+    /// They are skipped anyway if a volume provides them.
+    #[arg(long = "no-passwd")]
+    no_passwd: bool,
+
+    /// Additional entries for the container’s /etc/hosts.
+    #[arg(long = "add-host", value_name = "NAME:IP")]
+    add_hosts: Vec<HostEntry>,
+
+    /// DNS server for the container’s /etc/resolv.conf. Can be given multiple times. With slirp,
+    /// 10.0.2.2 reaches a resolver listening on the host’s loopback.
+    #[arg(long = "dns", value_name = "IP")]
+    dns: Vec<IpAddr>,
+
+    /// Log how long each phase of starting the container took, like building the flake and
+    /// mounting its closure. The phases are also logged with --verbose.
+    #[arg(long = "timings")]
+    timings: bool,
+
+    /// Write lifecycle events as JSON lines to stderr: building, closure-computed, fs-assembled, started and exited.
+    #[arg(long = "events", conflicts_with = "events_fd")]
+    events: bool,
+
+    /// Write the lifecycle events of --events to this inherited file descriptor instead, apart from the logs.
+    #[arg(long = "events-fd", value_name = "FD")]
+    events_fd: Option<i32>,
+
     /// Keep the container root directory after the command has run.
     #[arg(short = 'k', long = "keep")]
     keep_container: bool,
 
-    /// Path to host tools.
+    /// Comma-separated list of store paths or flakes providing host tools. Earlier entries take precedence.
     #[arg(
         long = "host-tools",
         value_name = "PATH or FLAKE",
@@ -56,114 +480,780 @@ struct Cli {
     )]
     host_tools: String,
 
+    /// Nixpkgs to build the host tools flakes with instead of their own pin, e.g. to reuse the system’s.
+    /// Defaults to $CONTAINIX_NIXPKGS.
+    #[arg(long = "host-tools-nixpkgs", value_name = "FLAKE")]
+    host_tools_nixpkgs: Option<String>,
+
+    /// Look for host tools in $PATH if they aren’t provided by --host-tools.
+    #[arg(long = "host-tools-path-fallback")]
+    host_tools_path_fallback: bool,
+
+    /// Make the container’s root filesystem read-only. Volumes can still be writable.
+    #[arg(long = "read-only")]
+    read_only: bool,
+
     /// Mount the entire Nix store into the container, rather than just the transitive closure.
     #[arg(long = "full-nix-store")]
     full_nix_store: bool,
 
+    /// Restart slirp if it crashes while the container is running.
+    #[arg(long = "slirp-restart")]
+    slirp_restart: bool,
+
+    /// Keep the network namespace and slirp when the container is restarted with --restart, so forwarded ports stay open.
+    #[arg(long = "keep-network")]
+    keep_network: bool,
+
+    /// Let SIGTERM, SIGINT, SIGHUP and SIGQUIT terminate containix right away instead of forwarding them to the container and cleaning up after it exits. The container’s tempdir is then left for `containix prune`.
+    #[arg(long = "no-cleanup-on-signal")]
+    no_cleanup_on_signal: bool,
+
+    /// Command to periodically run inside the container to check its health. It is split into words like a shell would, with quotes, but not run through one.
+    #[arg(long = "health-cmd", value_name = "COMMAND")]
+    health_cmd: Option<String>,
+
+    /// Time between health checks.
+    #[arg(
+        long = "health-interval",
+        value_name = "DURATION",
+        default_value = "30s"
+    )]
+    health_interval: HumanDuration,
+
+    /// Consecutive failed health checks before the container is considered unhealthy.
+    #[arg(long = "health-retries", value_name = "N", default_value_t = 3)]
+    health_retries: u32,
+
+    /// Run a minimal init process as PID 1 that forwards signals and reaps orphaned processes.
+    #[arg(long = "init")]
+    init: bool,
+
+    /// Restart the container when it exits: no, always or on-failure[:<MAX RETRIES>].
+    #[arg(long = "restart", value_name = "POLICY", default_value = "no")]
+    restart: RestartPolicy,
+
+    /// Stop the container once it has run this long in total, including restarts, e.g. 300s or 5m. containix then exits with status 124.
+    #[arg(long = "timeout", value_name = "DURATION")]
+    timeout: Option<HumanDuration>,
+
+    /// Capabilities to remove from the container process, e.g. NET_RAW or ALL.
+    #[arg(long = "cap-drop", value_name = "CAPABILITY")]
+    cap_drop: Vec<CapabilityArg>,
+
+    /// Capabilities to keep even if they are dropped with --cap-drop.
+    #[arg(long = "cap-add", value_name = "CAPABILITY")]
+    cap_add: Vec<CapabilityArg>,
+
+    /// Print the capabilities the entry point will have to stderr before it starts, after --cap-drop, --cap-add and --user are applied. They are namespaced to the container’s user namespace.
+    #[arg(long = "print-caps")]
+    print_caps: bool,
+
+    /// Allow processes in the container to gain privileges through setuid binaries. Without
+    /// PR_SET_NO_NEW_PRIVS, seccomp filters require CAP_SYS_ADMIN to be kept.
+    #[arg(long = "allow-new-privs")]
+    allow_new_privs: bool,
+
+    /// Seccomp profile for the container: default, unconfined or the path to a JSON profile in Docker’s format, without argument conditions, includes or excludes. Unlike Docker’s, the default profile only blocks a list of dangerous syscalls and allows everything else.
+    #[arg(long = "seccomp", value_name = "PROFILE", default_value = "default")]
+    seccomp: SeccompProfile,
+
+    /// Timezone for the container: host to mount the host’s /etc/localtime, a name like Europe/London to set TZ, or none.
+    #[arg(long = "timezone", value_name = "TIMEZONE", default_value = "none")]
+    timezone: Timezone,
+
+    /// Map container uids to host uids instead of mapping only the current user to root. Ranges
+    /// other than the current user need to be assigned in /etc/subuid and require newuidmap.
+    #[arg(long = "uid-map", value_name = "INNER:OUTER:COUNT")]
+    uid_maps: Vec<IdRangeMap>,
+
+    /// Map container gids to host gids, like --uid-map. Requires newgidmap and /etc/subgid.
+    #[arg(long = "gid-map", value_name = "INNER:OUTER:COUNT")]
+    gid_maps: Vec<IdRangeMap>,
+
+    /// Resource limit for the container command, like nofile=1024:2048 or nproc=512. Without a hard
+    /// limit, both are set to the same value. Can be given multiple times.
+    #[arg(long = "rlimit", value_name = "NAME=SOFT[:HARD]")]
+    rlimits: Vec<Rlimit>,
+
+    /// Maximum number of processes in the container, to contain fork bombs. Needs a delegated cgroup v2 and is skipped with a warning otherwise.
+    #[arg(long = "pids-limit", value_name = "N")]
+    pids_limit: Option<u64>,
+
+    /// Supplementary group of the container command, e.g. to access a volume. Can be given multiple
+    /// times. The gid has to be mapped with --gid-map, as setgroups is denied in the default
+    /// single-id namespace, and it therefore can’t be combined with --user.
+    #[arg(long = "add-group", value_name = "GID")]
+    add_groups: Vec<u32>,
+
+    /// Namespaces to share with the host instead of isolating them, e.g. ipc,uts,pid.
+    #[arg(long = "share", value_name = "NAMESPACE", value_delimiter = ',')]
+    share: Vec<UnshareNamespaces>,
+
+    /// Append the container command’s stdout and stderr to this file. Use - to inherit containix’s output.
+    #[arg(long = "log-file", value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Write the container’s PID to this file while it is running, e.g. for an external supervisor.
+    #[arg(long = "pid-file", value_name = "PATH")]
+    pid_file: Option<PathBuf>,
+
+    /// Shell command to run on the host before the container is started. The run is aborted if it fails.
+    #[arg(long = "pre-start", value_name = "COMMAND")]
+    pre_start: Option<String>,
+
+    /// Shell command to run on the host after the container has exited, regardless of its exit code.
+    /// Its output is logged. If it fails, the run fails, unless the container already failed.
+    #[arg(long = "post-stop", value_name = "COMMAND")]
+    post_stop: Option<String>,
+
+    /// (Nix passthru:) Allow the flake to access mutable paths and environment variables during evaluation.
+    #[arg(long = "impure")]
+    impure: bool,
+
     /// (Nix passthru:) Consider all previously downloaded files out-of-date.
     #[arg(long = "refresh", default_value_t = false)]
     refresh: bool,
+
+    /// (Nix passthru as --max-jobs:) Maximum number of derivations built in parallel.
+    #[arg(long = "build-jobs", value_name = "N")]
+    build_jobs: Option<u32>,
+
+    /// (Nix passthru as --cores:) Number of cores each build job may use, 0 for all.
+    #[arg(long = "build-cores", value_name = "N")]
+    build_cores: Option<u32>,
+
+    /// Argument appended verbatim to `nix build`, e.g. --nix-arg=--accept-flake-config. Repeat it for more arguments. Arguments that change nix’s output, like --print-build-logs or --log-format, can break containix reading it.
+    #[arg(long = "nix-arg", value_name = "ARG", allow_hyphen_values = true)]
+    nix_args: Vec<String>,
+
+    /// Retry nix builds and evaluations up to N times when they fail to fetch something.
+    #[arg(long = "retries", value_name = "N", default_value_t = 0)]
+    retries: u32,
 }
 
-#[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
-fn enter_root_ns() -> Result<()> {
-    let mut builder = UnshareEnvironmentBuilder::default();
-    builder
-        .namespace(UnshareNamespaces::User)
-        .namespace(UnshareNamespaces::Mount)
-        .map_current_user_to_root();
-    builder.enter()?;
+/// Keeps the container’s PID in a file while it is running and removes it when dropped.
+struct PidFile(PathBuf);
+
+impl PidFile {
+    fn create(path: &Path, pid: u32) -> Result<Self> {
+        let pid_file = PidFile(path.to_path_buf());
+        pid_file.update(pid)?;
+        Ok(pid_file)
+    }
+
+    /// Replaces the PID through a rename, so readers never see a partially written file.
+    fn update(&self, pid: u32) -> Result<()> {
+        let mut tmp = self.0.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, format!("{pid}\n"))
+            .with_context(|| format!("Writing PID file {}", self.0.display()))?;
+        std::fs::rename(&tmp, &self.0)
+            .with_context(|| format!("Writing PID file {}", self.0.display()))?;
+        Ok(())
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            warn!("Failed to remove PID file {}: {e}", self.0.display());
+        }
+    }
+}
+
+/// Runs a lifecycle hook through `sh` on the host.
+///
+/// The hook learns about the container through `CONTAINIX_FLAKE` and, once the
+/// container filesystem exists, `CONTAINIX_ROOT`.
+#[instrument(level = "trace", skip(flake, root), err(level = Level::TRACE))]
+fn run_hook(name: &str, hook: &str, flake: &ContainixFlake, root: Option<&Path>) -> Result<()> {
+    debug!("Running {name} hook: {hook}");
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(hook)
+        .env("CONTAINIX_FLAKE", flake.to_string());
+    if let Some(root) = root {
+        cmd.env("CONTAINIX_ROOT", root);
+    }
+    let output = run_command(cmd).with_context(|| format!("Running {name} hook"))?;
+    for stream in [&output.stdout, &output.stderr] {
+        for line in String::from_utf8_lossy(stream).lines() {
+            info!("{name} hook: {line}");
+        }
+    }
     Ok(())
 }
 
 #[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
-fn containix_run(args: Cli) -> Result<()> {
-    setup_host_tools(&args.host_tools, args.refresh)?;
+fn containix_build(args: BuildArgs) -> Result<()> {
+    set_retries(args.retries);
     info!("Building container {}", args.flake);
     let store_item = args
         .flake
         .build(|cmd_args| {
-            cmd_args.refresh(args.refresh);
+            cmd_args
+                .refresh(args.refresh)
+                .impure(args.impure)
+                .max_jobs(args.build_jobs)
+                .cores(args.build_cores)
+                .extra_args(&args.nix_args);
+            if let Some(out_link) = &args.out_link {
+                cmd_args.symlink(out_link.clone());
+            }
         })
         .context("Building container flake")?;
-    let closure = store_item
-        .closure()
-        .context("Computing transitive closure")?;
-    debug!(
-        "Dependency closure: {}",
-        closure
+    println!("{}", store_item.path().display());
+    Ok(())
+}
+
+#[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+fn containix_config(args: ConfigArgs) -> Result<()> {
+    set_retries(args.retries);
+    let spec = RunSpecBuilder::default()
+        .flake(args.flake)
+        .with_flakes(args.with_flakes)
+        .args(args.args)
+        .entrypoint(args.entrypoint)
+        .argv0(args.argv0)
+        .env(args.env)
+        .default_path(!args.no_default_path)
+        .env_from_flake(args.env_from_flake)
+        .timezone(args.timezone)
+        .refresh(args.refresh)
+        .impure(args.impure)
+        .build_jobs(args.build_jobs)
+        .build_cores(args.build_cores)
+        .build()?;
+    let config = launch_config(&spec)?;
+    if args.json {
+        // Later variables override earlier ones, like they do in the container.
+        let env: serde_json::Map<_, _> = config
+            .env
             .iter()
-            .map(|c| c.name())
+            .map(|var| {
+                (
+                    var.key.to_string_lossy().into_owned(),
+                    var.value.to_string_lossy().into(),
+                )
+            })
+            .collect();
+        let json = serde_json::json!({
+            "command": config.command,
+            "argv": config.argv,
+            "env": env,
+        });
+        println!("{}", serde_json::to_string_pretty(&json)?);
+        return Ok(());
+    }
+    for var in &config.env {
+        println!(
+            "export {}={}",
+            var.key.to_string_lossy(),
+            shell_quote(&var.value.to_string_lossy())
+        );
+    }
+    println!("CONTAINIX_COMMAND={}", shell_quote(&config.command));
+    println!(
+        "set -- {}",
+        config
+            .argv
+            .iter()
+            .map(|arg| shell_quote(arg))
             .collect::<Vec<_>>()
-            .join(", ")
+            .join(" ")
     );
+    Ok(())
+}
+
+/// Quotes `s` for a POSIX shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
 
-    let mut container_fs = ContainerFsBuilder::default();
-    if args.full_nix_store {
-        container_fs.volume(VolumeMount::read_only("/nix/store", "/nix/store"));
+#[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+fn containix_eval(args: EvalArgs) -> Result<()> {
+    set_retries(args.retries);
+    let output = args
+        .flake
+        .eval(args.attr.as_deref(), args.json, args.impure)
+        .context("Evaluating flake")?;
+    print!("{output}");
+    Ok(())
+}
+
+#[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+fn containix_export(args: ExportArgs) -> Result<()> {
+    set_retries(args.retries);
+    let spec = RunSpecBuilder::default()
+        .flake(args.flake)
+        .exclude_paths(args.exclude_paths)
+        .refresh(args.refresh)
+        .impure(args.impure)
+        .build_jobs(args.build_jobs)
+        .build_cores(args.build_cores)
+        .build()?;
+    // Opened before entering the namespaces, where the user might not be able to create it.
+    if args.output == Path::new("-") {
+        export_rootfs(&spec, std::io::stdout().lock())
+    } else {
+        let file = std::fs::File::create(&args.output)
+            .with_context(|| format!("Creating {}", args.output.display()))?;
+        export_rootfs(&spec, std::io::BufWriter::new(file))
+    }
+}
+
+#[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+fn containix_oci_export(args: OciExportArgs) -> Result<()> {
+    set_retries(args.retries);
+    let spec = RunSpecBuilder::default()
+        .flake(args.flake)
+        .args(args.args)
+        .entrypoint(args.entrypoint)
+        .env(args.env)
+        .labels(
+            args.labels
+                .into_iter()
+                .map(|label| (label.key, label.value))
+                .collect::<HashMap<_, _>>(),
+        )
+        .user(args.user)
+        .exclude_paths(args.exclude_paths)
+        .refresh(args.refresh)
+        .impure(args.impure)
+        .build_jobs(args.build_jobs)
+        .build_cores(args.build_cores)
+        .build()?;
+    let tag = args.tag.as_deref();
+    if args.output == Path::new("-") {
+        export_oci_image(&spec, tag, std::io::stdout().lock())
     } else {
-        for component in &closure {
-            container_fs.nix_component(component.path());
+        let file = std::fs::File::create(&args.output)
+            .with_context(|| format!("Creating {}", args.output.display()))?;
+        export_oci_image(&spec, tag, std::io::BufWriter::new(file))
+    }
+}
+
+#[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+fn containix_prune(args: PruneArgs) -> Result<()> {
+    let leftovers = find_leftovers()?;
+    if leftovers.is_empty() {
+        info!("Nothing to prune");
+        return Ok(());
+    }
+    let mut failed = 0;
+    for leftover in &leftovers {
+        if args.dry_run {
+            println!("{}", leftover.path.display());
+            continue;
         }
+        match remove_leftover(leftover) {
+            Ok(()) => info!(
+                "Removed {} (left behind by PID {})",
+                leftover.path.display(),
+                leftover.pid
+            ),
+            Err(e) => {
+                warn!("Could not remove {}: {e:#}", leftover.path.display());
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{failed} of {} leftovers could not be removed",
+            leftovers.len()
+        );
     }
+    Ok(())
+}
 
-    for volume in &args.volumes {
-        container_fs.volume(volume.clone());
+/// Exit status of `containix run` when the container was stopped by `--timeout`.
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+#[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+fn containix_run(args: RunArgs) -> Result<()> {
+    set_retries(args.retries);
+    if args.timings {
+        timings::collect();
+    }
+    if let Some(fd) = args.events_fd {
+        events::emit_to_fd(fd)?;
+    } else if args.events {
+        let stderr = std::io::stderr()
+            .as_fd()
+            .try_clone_to_owned()
+            .context("Duplicating stderr for events")?;
+        events::emit_to(stderr.into());
+    }
+    let host_tools = timings::measure("Setting up host tools", || {
+        HostTools::resolve(
+            &args.host_tools,
+            args.refresh,
+            args.host_tools_path_fallback,
+            args.host_tools_nixpkgs.as_deref(),
+        )
+    })?;
+    // Checked before the build, so a missing slirp doesn’t fail the container halfway through starting.
+    let mut network = args.network == NetworkMode::Slirp;
+    if network && host_tools.find("slirp4netns").is_none() {
+        if !args.network_optional {
+            bail!(
+                "slirp4netns, which provides the container’s network, isn’t part of the host tools ({}). \
+                Pass host tools that include it with --host-tools, install it and pass --host-tools-path-fallback, \
+                or run without network using --network none or --network-optional.",
+                args.host_tools
+            );
+        }
+        if !args.ports.is_empty() {
+            bail!("slirp4netns isn’t available, so ports can’t be published");
+        }
+        warn!("slirp4netns isn’t available, running the container without network");
+        network = false;
+    }
+    if let Some(hook) = &args.pre_start {
+        run_hook("pre-start", hook, &args.flake, None)?;
     }
 
-    enter_root_ns()?;
-    let container_fs = container_fs.build().context("Building container fs")?;
-    let root = container_fs.as_ref().to_path_buf();
-    info!("Container root: {}", root.display());
+    let mut volumes = args.volumes;
+    let mut workdir = args.workdir;
+    if let Some(target) = args.cwd {
+        let cwd = std::env::current_dir().context("Reading the current directory")?;
+        let target = target.normalize_container_path()?;
+        volumes.push(VolumeMount {
+            host_path: cwd,
+            container_path: target.clone(),
+            read_only: false,
+            copy: false,
+        });
+        workdir.get_or_insert(target);
+    }
 
-    let mut container_builder = ContainerBuilder::default()
-        .root(container_fs)
+    let mut host_env = Vec::new();
+    // The container shares our terminal, so programs in it need to know what it is.
+    if std::io::stdin().is_terminal() {
+        if let Some(term) = std::env::var_os("TERM") {
+            host_env.push(EnvVariable::new("TERM", term));
+        }
+    }
+    let ports: Vec<_> = args.ports.iter().map(ToString::to_string).collect();
+    let spec = RunSpecBuilder::default()
+        .flake(args.flake.clone())
+        .with_flakes(args.with_flakes)
+        .args(args.args)
+        .entrypoint(args.entrypoint)
+        .argv0(args.argv0)
+        .env(args.env)
+        .host_env(host_env)
+        .env_precedence(args.env_precedence)
+        .default_path(!args.no_default_path)
+        .env_from_flake(args.env_from_flake)
+        .volumes(volumes)
+        .mounts(args.mounts)
+        .directories(args.directories)
         .ports(args.ports)
-        .env("PATH", store_item.path().join("bin"))
-        .envs(args.env);
+        .network(network)
+        .wait_net(args.wait_net)
+        .rw_components(args.rw_components)
+        .exclude_paths(args.exclude_paths)
+        .max_size(args.max_size)
+        .passwd(!args.no_passwd)
+        .hosts(args.add_hosts)
+        .nameservers(args.dns)
+        .read_only(args.read_only)
+        .full_nix_store(args.full_nix_store)
+        .timezone(args.timezone)
+        .user(args.user)
+        .workdir(workdir)
+        .uid_maps(args.uid_maps)
+        .gid_maps(args.gid_maps)
+        .groups(args.add_groups)
+        .rlimits(args.rlimits)
+        .pids_limit(args.pids_limit)
+        .shared_namespaces(args.share)
+        .dropped_capabilities(capabilities_to_drop(args.cap_drop, args.cap_add))
+        .print_capabilities(args.print_caps)
+        .seccomp(args.seccomp)
+        .no_new_privs(!args.allow_new_privs)
+        .init(args.init)
+        .slirp_restart(args.slirp_restart)
+        .keep_network(args.keep_network)
+        .log_file(args.log_file.filter(|path| path != Path::new("-")))
+        .host_tools(host_tools)
+        .refresh(args.refresh)
+        .impure(args.impure)
+        .build_jobs(args.build_jobs)
+        .build_cores(args.build_cores)
+        .nix_args(args.nix_args)
+        .build()?;
+    let mut container_handle = run_container(spec)?;
+    trace!("Container started with PID {}", container_handle.pid());
+    events::emit(Event::Started {
+        pid: container_handle.pid(),
+        ports: ports.clone(),
+    });
+    // Termination signals are forwarded to the container, which lets this process unmount
+    // and remove the container once it exits, rather than being killed and leaving it behind.
+    if !args.no_cleanup_on_signal {
+        init::supervise_signals(container_handle.pid());
+    }
+    if args.timings {
+        info!("Startup timings: {}", timings::summary());
+    }
+    let pid_file = args
+        .pid_file
+        .as_deref()
+        .map(|path| PidFile::create(path, container_handle.pid()))
+        .transpose()?;
 
-    let cmd = store_item.path().join("bin").join("containix-entry-point");
-    let Some(cmd) = cmd.to_str() else {
-        bail!("Container flake name contains invalid utf-8");
-    };
-    container_builder = container_builder.command(cmd).args(args.args);
+    let health_check = args
+        .health_cmd
+        .map(|command| {
+            let command = shell_words::split(&command).context("Parsing --health-cmd")?;
+            if command.is_empty() {
+                bail!("--health-cmd is empty");
+            }
+            Ok(HealthCheck {
+                command,
+                interval: args.health_interval.into(),
+                retries: args.health_retries,
+            })
+        })
+        .transpose()?;
 
-    // if let Some(uid) = args.set_uid {
-    //     container_builder = container_builder.uid(uid);
-    // }
-    // if let Some(gid) = args.set_gid {
-    //     container_builder = container_builder.gid(gid);
-    // }
+    let deadline = args
+        .timeout
+        .map(|timeout| Deadline::start(timeout.into(), container_handle.pid()));
 
-    let mut container_handle = container_builder.spawn().context("Spawning container")?;
-    trace!("Container started with PID {}", container_handle.pid());
+    let mut restarts = 0;
+    let wait_result = loop {
+        match container_handle.ip_addresses() {
+            Ok(addresses) => info!(
+                "Container addresses: {}",
+                addresses
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Err(e) => warn!("Could not determine container addresses: {e:#}"),
+        }
+        let health_monitor = health_check
+            .clone()
+            .map(|check| HealthMonitor::start(check, container_handle.exec_handle()));
+        let status = container_handle.wait();
+        init::child_exited();
+        if health_monitor.is_some_and(|monitor| monitor.status() == HealthStatus::Unhealthy) {
+            warn!("Container was unhealthy when it exited");
+        }
+        let status = match status.context("Waiting for container to exit") {
+            Ok(status) => status,
+            Err(e) => break Err(e),
+        };
+        if let Some(deadline) = &deadline {
+            deadline.clear_pid();
+        }
+        events::emit(Event::Exited { code: status });
+        if init::terminating() || !args.restart.should_restart(status, restarts) {
+            break Ok(status);
+        }
+
+        let backoff = args.restart.backoff(restarts);
+        restarts += 1;
+        info!("Container exited with status {status:?}, restarting in {backoff:?} (restart #{restarts})");
+        std::thread::sleep(backoff);
+        // A timeout during the backoff has nothing to stop, so the restart is skipped instead.
+        if init::terminating() || deadline.as_ref().is_some_and(Deadline::expired) {
+            break Ok(status);
+        }
+        if let Err(e) = container_handle.restart() {
+            break Err(e.context("Restarting container"));
+        }
+        events::emit(Event::Started {
+            pid: container_handle.pid(),
+            ports: ports.clone(),
+        });
+        if !args.no_cleanup_on_signal {
+            init::supervise_signals(container_handle.pid());
+        }
+        if let Some(deadline) = &deadline {
+            deadline.set_pid(container_handle.pid());
+        }
+        if let Some(pid_file) = &pid_file {
+            if let Err(e) = pid_file.update(container_handle.pid()) {
+                break Err(e);
+            }
+        }
+    };
+    // The container is gone, so its PID is no longer valid.
+    drop(pid_file);
+    let timed_out = deadline.is_some_and(|deadline| deadline.expired());
 
-    container_handle
-        .wait()
-        .context("Waiting for container to exit")?;
+    // Reported after the container’s own result, which it must not hide.
+    let post_stop_error = args.post_stop.as_ref().and_then(|hook| {
+        run_hook(
+            "post-stop",
+            hook,
+            &args.flake,
+            Some(container_handle.root()),
+        )
+        .err()
+    });
+    if let Err(e) = wait_result {
+        if let Some(hook_error) = post_stop_error {
+            error!("{hook_error:#}");
+        }
+        return Err(e);
+    }
+
+    if !container_handle.network_healthy() {
+        warn!("Container network went down while the container was running");
+    }
+    let exit_status = container_handle.exit_status().unwrap_or(0);
 
     if args.keep_container {
         warn!("Not cleaning up {}", container_handle.root().display());
         _ = ManuallyDrop::new(container_handle);
+    } else {
+        drop(container_handle);
     }
 
-    Ok(())
+    if timed_out || exit_status != 0 {
+        if let Some(hook_error) = post_stop_error {
+            error!("{hook_error:#}");
+        }
+        // Exits like timeout(1) does, so scripts can tell a timeout from the container failing.
+        std::process::exit(if timed_out {
+            TIMEOUT_EXIT_CODE
+        } else {
+            exit_status
+        });
+    }
+    post_stop_error.map_or(Ok(()), Err)
 }
 
-fn main() -> Result<()> {
-    fmt()
+/// Replaces `--config FILE` with the arguments the file describes. Entry point arguments are
+/// appended after `--`.
+fn expand_config(args: Vec<OsString>) -> Result<Vec<OsString>> {
+    let end = args
+        .iter()
+        .position(|arg| arg == "--")
+        .unwrap_or(args.len());
+    let Some((index, path)) = args[..end].iter().enumerate().find_map(|(index, arg)| {
+        let arg = arg.to_str()?;
+        if arg == "--config" {
+            Some((index, args.get(index + 1)?.clone()))
+        } else {
+            arg.strip_prefix("--config=")
+                .map(|path| (index, path.into()))
+        }
+    }) else {
+        return Ok(args);
+    };
+    debug!(
+        "Reading arguments from config {}",
+        Path::new(&path).display()
+    );
+    let config = if path == "-" {
+        std::io::read_to_string(std::io::stdin()).context("Reading config from stdin")?
+    } else {
+        std::fs::read_to_string(&path)
+            .with_context(|| format!("Reading config {}", Path::new(&path).display()))?
+    };
+    let config: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(&config).context("Parsing config, expected a JSON object")?;
+
+    let mut flags = Vec::new();
+    let mut entry_point_args = Vec::new();
+    for (key, value) in config {
+        if key == "config" {
+            bail!("A config can’t include another config");
+        }
+        let values = match value {
+            serde_json::Value::Array(values) => values,
+            serde_json::Value::Object(entries) => entries
+                .into_iter()
+                .map(|(name, value)| Ok(format!("{name}={}", config_value(&key, value)?).into()))
+                .collect::<Result<_>>()?,
+            value => vec![value],
+        };
+        for value in values {
+            if key == "args" {
+                entry_point_args.push(config_value(&key, value)?);
+                continue;
+            }
+            match value {
+                serde_json::Value::Bool(true) => flags.push(format!("--{key}")),
+                serde_json::Value::Bool(false) | serde_json::Value::Null => {}
+                // Joined with `=`, so values starting with `-` aren’t taken for flags and
+                // flags with `require_equals` accept them.
+                value => flags.push(format!("--{key}={}", config_value(&key, value)?)),
+            }
+        }
+    }
+
+    // The config’s arguments take the place of `--config FILE`.
+    let consumed = if args[index] == "--config" { 2 } else { 1 };
+    let mut expanded = args[..index].to_vec();
+    expanded.extend(flags.into_iter().map(OsString::from));
+    expanded.extend(args[index + consumed..].iter().cloned());
+    if !entry_point_args.is_empty() {
+        if end < args.len() {
+            bail!("Entry point arguments can’t be given both in the config and after --");
+        }
+        expanded.push("--".into());
+        expanded.extend(entry_point_args.into_iter().map(OsString::from));
+    }
+    Ok(expanded)
+}
+
+fn config_value(key: &str, value: serde_json::Value) -> Result<String> {
+    match value {
+        serde_json::Value::String(value) => Ok(value),
+        serde_json::Value::Number(value) => Ok(value.to_string()),
+        value => bail!("Invalid value for {key} in config: {value}"),
+    }
+}
+
+fn subscriber(level: Level) -> Result<impl Subscriber + Send + Sync> {
+    Ok(fmt()
         .with_span_events(FmtSpan::ENTER | FmtSpan::EXIT)
         .with_target(true)
         .with_env_filter(
             EnvFilter::builder()
-                .with_default_directive(Level::INFO.into())
+                .with_default_directive(level.into())
                 .with_env_var("CONTAINIX_LOG")
                 .from_env()
                 .context("Parsing CONTAINIX_LOG")?,
         )
         .with_writer(std::io::stderr)
-        .init();
+        .finish())
+}
 
-    let cli = Cli::parse();
-    containix_run(cli)
+fn main() -> Result<()> {
+    // Must come first, see its docs. Only CONTAINIX_LOG applies to the helper.
+    tracing::subscriber::with_default(
+        subscriber(Level::INFO)?,
+        containix::container::run_isolated_helper,
+    );
+    // The config can contain --verbose, so until it’s expanded only CONTAINIX_LOG applies.
+    let args = tracing::subscriber::with_default(subscriber(Level::INFO)?, || {
+        expand_config(std::env::args_os().collect())
+    })?;
+    let cli = Cli::parse_from(args);
+    subscriber(cli.log_level())?.init();
+
+    match cli.command {
+        Some(Commands::Run(args)) => containix_run(*args),
+        Some(Commands::Build(args)) => containix_build(args),
+        Some(Commands::Config(args)) => containix_config(args),
+        Some(Commands::Eval(args)) => containix_eval(args),
+        Some(Commands::Export(args)) => containix_export(args),
+        Some(Commands::OciExport(args)) => containix_oci_export(args),
+        Some(Commands::Prune(args)) => containix_prune(args),
+        None => containix_run(
+            cli.run
+                .expect("clap requires run arguments without a subcommand"),
+        ),
+    }
 }
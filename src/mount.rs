@@ -1,8 +1,12 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use derive_builder::Builder;
 use derive_more::derive::Deref;
-use std::path::{Path, PathBuf};
-use tracing::{error, instrument, trace};
+use std::{
+    ffi::OsString,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+use tracing::{error, instrument, trace, warn};
 
 #[derive(Debug, Deref, PartialEq)]
 pub struct MountGuard(Option<PathBuf>);
@@ -25,6 +29,10 @@ pub struct BindMountOptions {
     dest: PathBuf,
     #[builder(default)]
     read_only: bool,
+    /// Keep the mount writable, with a warning, if it can’t be remounted read-only. Some
+    /// filesystems, like certain FUSE and network filesystems, reject the remount.
+    #[builder(default)]
+    best_effort_read_only: bool,
     #[builder(default = "true")]
     cleanup: bool,
 }
@@ -40,18 +48,22 @@ impl BindMount {
             Some(&opts.src),
             &opts.dest,
             Option::<&str>::None,
-            MsFlags::MS_BIND.union(if opts.read_only {
-                MsFlags::MS_RDONLY
-            } else {
-                MsFlags::empty()
-            }),
+            MsFlags::MS_BIND,
             Option::<&str>::None,
         )?;
-        Ok(MountGuard(if opts.cleanup {
-            Some(opts.dest)
-        } else {
-            None
-        }))
+        let guard = MountGuard(opts.cleanup.then(|| opts.dest.clone()));
+        // The kernel ignores MS_RDONLY when creating a bind mount, it only applies on remount.
+        if opts.read_only {
+            match remount_read_only(&opts.dest) {
+                Err(e) if opts.best_effort_read_only => warn!(
+                    "Could not make {} read-only, it stays writable: {e}",
+                    opts.dest.display()
+                ),
+                // Dropping the guard undoes the bind mount.
+                result => result?,
+            }
+        }
+        Ok(guard)
     }
 }
 
@@ -63,8 +75,140 @@ impl BindMount {
 // ) -> Result<MountGuard> {
 // }
 
+/// Remounts the bind mount at `path` read-only.
+///
+/// Flags like `nosuid` that are already set on the mount are kept, as the kernel refuses
+/// to clear them when they were inherited from a more privileged mount namespace.
+#[instrument(level = "trace", skip_all, fields(path = %path.as_ref().display()), err(level = "trace"))]
+pub fn remount_read_only(path: impl AsRef<Path>) -> Result<()> {
+    use nix::mount::MsFlags;
+    use nix::sys::statvfs::FsFlags;
+
+    let path = path.as_ref();
+    let current = nix::sys::statvfs::statvfs(path)?.flags();
+    let mut flags = MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY;
+    for (fs_flag, ms_flag) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if current.contains(fs_flag) {
+            flags |= ms_flag;
+        }
+    }
+    nix::mount::mount(
+        Option::<&str>::None,
+        path,
+        Option::<&str>::None,
+        flags,
+        Option::<&str>::None,
+    )?;
+    Ok(())
+}
+
+/// Mounts an empty tmpfs at `path`.
+pub fn mount_tmpfs(path: impl AsRef<Path>) -> Result<MountGuard> {
+    mount_tmpfs_with(path, None, None)
+}
+
+/// Mounts an empty tmpfs at `path`, limited to `size` (in tmpfs’s syntax, like `64m`). The
+/// mode defaults to 0755.
+#[instrument(level = "trace", skip_all, fields(path = %path.as_ref().display()), err(level = "trace"))]
+pub fn mount_tmpfs_with(
+    path: impl AsRef<Path>,
+    size: Option<&str>,
+    mode: Option<u32>,
+) -> Result<MountGuard> {
+    use nix::mount::MsFlags;
+
+    let path = path.as_ref();
+    let mut options = format!("mode={:04o}", mode.unwrap_or(0o755));
+    if let Some(size) = size {
+        options.push_str(&format!(",size={size}"));
+    }
+    nix::mount::mount(
+        Some("tmpfs"),
+        path,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some(options.as_str()),
+    )?;
+    Ok(MountGuard(Some(path.to_path_buf())))
+}
+
+/// Mounts an overlay of the read-only `lower` directory at `path`. Changes are written to
+/// `upper`, `work` is overlayfs’s scratch space. Both have to be on the same filesystem.
+///
+/// The paths can’t contain `,`, `:` or `\`, which overlayfs would take for separators or
+/// escapes in its options.
+#[instrument(level = "trace", skip_all, fields(path = %path.as_ref().display()), err(level = "trace"))]
+pub fn mount_overlay(
+    lower: impl AsRef<Path>,
+    upper: impl AsRef<Path>,
+    work: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+) -> Result<MountGuard> {
+    use nix::mount::MsFlags;
+
+    let path = path.as_ref();
+    let mut options = OsString::new();
+    for (name, dir) in [
+        ("lowerdir", lower.as_ref()),
+        ("upperdir", upper.as_ref()),
+        ("workdir", work.as_ref()),
+    ] {
+        if dir
+            .as_os_str()
+            .as_bytes()
+            .iter()
+            .any(|b| b",:\\".contains(b))
+        {
+            bail!(
+                "Overlay {name} {} can’t contain `,`, `:` or `\\`",
+                dir.display()
+            );
+        }
+        if !options.is_empty() {
+            options.push(",");
+        }
+        options.push(format!("{name}="));
+        options.push(dir);
+    }
+    nix::mount::mount(
+        Some("overlay"),
+        path,
+        Some("overlay"),
+        MsFlags::empty(),
+        Some(options.as_os_str()),
+    )?;
+    Ok(MountGuard(Some(path.to_path_buf())))
+}
+
 #[instrument(level = "trace", skip_all, fields(path = %path.as_ref().display()), err(level = "trace"))]
 pub fn unmount(path: impl AsRef<Path>) -> Result<()> {
     nix::mount::umount(path.as_ref())?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_overlay_separators() {
+        for dir in ["/a,b", "/a:b", "/a\\b"] {
+            let cases = [
+                (dir, "/upper", "/work", "lowerdir"),
+                ("/lower", dir, "/work", "upperdir"),
+                ("/lower", "/upper", dir, "workdir"),
+            ];
+            for (lower, upper, work, name) in cases {
+                let err = mount_overlay(lower, upper, work, "/target").unwrap_err();
+                assert!(err.to_string().contains(name), "{dir}: {err}");
+            }
+        }
+    }
+}
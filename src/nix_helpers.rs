@@ -2,15 +2,19 @@ use anyhow::{bail, Context, Result};
 use derive_more::derive::{Deref, DerefMut};
 use serde::{Deserialize, Deserializer, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::Display,
     path::{Path, PathBuf},
     process::Command,
     str::FromStr,
+    sync::OnceLock,
 };
-use tracing::{debug, error, instrument, Level};
+use tracing::{debug, error, instrument, warn, Level};
 
 use crate::cli_wrappers::nix::{FlakeOutputSymlink, NixBuild, NixEval};
+use crate::command::{run_command, CommandFailed};
+use crate::env::EnvVariable;
+use crate::error::{ContainixError, ResultExt};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
 pub struct NixStoreItem(String);
@@ -37,13 +41,16 @@ impl Display for NixStoreItem {
 impl TryFrom<&str> for NixStoreItem {
     type Error = anyhow::Error;
     fn try_from(value: &str) -> Result<Self> {
-        if !value.starts_with("/nix/store/") && !value.contains('/') {
-            return Ok(NixStoreItem(value.to_string()));
-        }
-        let components: Vec<_> = value.split('/').collect();
-        let &["", "nix", "store", item] = components.as_slice() else {
-            bail!("{} is not a nix store item", value);
+        let item = if !value.starts_with("/nix/store/") && !value.contains('/') {
+            value
+        } else {
+            let components: Vec<_> = value.split('/').collect();
+            let &["", "nix", "store", item] = components.as_slice() else {
+                bail!("{} is not a nix store item", value);
+            };
+            item
         };
+        NixStoreItem::validate(item).with_context(|| format!("{value} is not a nix store item"))?;
         Ok(NixStoreItem(item.to_string()))
     }
 }
@@ -59,44 +66,84 @@ impl TryFrom<&Path> for NixStoreItem {
 }
 
 impl NixStoreItem {
+    const HASH_LENGTH: usize = 32;
+    /// Nix’s base-32 alphabet, which omits e, o, u and t.
+    const HASH_ALPHABET: &str = "0123456789abcdfghijklmnpqrsvwxyz";
+
+    /// Checks that `item` is a hash followed by a dash and a name.
+    fn validate(item: &str) -> Result<()> {
+        let Some((hash, name)) = item.split_at_checked(Self::HASH_LENGTH) else {
+            bail!("Store item name is too short");
+        };
+        if !hash.chars().all(|c| Self::HASH_ALPHABET.contains(c)) {
+            bail!("Invalid store hash {hash}");
+        }
+        match name.strip_prefix('-') {
+            Some(name) if !name.is_empty() => Ok(()),
+            _ => bail!("Store item has no name after its hash"),
+        }
+    }
+
     pub fn path(&self) -> PathBuf {
         PathBuf::from("/nix/store").join(&self.0)
     }
 
+    /// Splits the item into its hash and name. Items are validated on construction, so this can’t fail.
     pub fn components(&self) -> (&str, &str) {
-        self.0
-            .split_once('-')
-            .unwrap_or_else(|| panic!("Invalid nix store path"))
+        let (hash, name) = self.0.split_at(Self::HASH_LENGTH);
+        (hash, &name[1..])
     }
 
     pub fn name(&self) -> &str {
         self.components().1
     }
 
+    /// Whether the name matches `pattern`, in which `*` matches any run of characters and `?`
+    /// a single one.
+    pub fn name_matches(&self, pattern: &str) -> bool {
+        glob_match(pattern.as_bytes(), self.name().as_bytes())
+    }
+
     #[instrument(level = "trace", skip_all, fields(path = %self.path().display()))]
     pub fn closure(&self) -> Result<HashSet<NixStoreItem>> {
-        let output = Command::new("nix-store")
-            .args(["--query", "--requisites"])
-            .arg(self.path())
-            .output()
-            .context("Running nix-store query for closure")?;
-
-        if !output.status.success() {
-            anyhow::bail!(
-                "Failed to get nix store closure: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
-        }
+        let mut cmd = Command::new("nix-store");
+        cmd.args(["--query", "--requisites"]).arg(self.path());
+        let output = run_command(cmd).context("Running nix-store query for closure")?;
 
+        // Anything that isn’t a valid store item can’t be mounted, but one odd entry shouldn’t
+        // keep the container from starting.
         let closure = String::from_utf8(output.stdout)?
             .lines()
-            .map(NixStoreItem::try_from)
-            .collect::<Result<_>>()?;
+            .filter_map(|line| match NixStoreItem::try_from(line) {
+                Ok(item) => Some(item),
+                Err(e) => {
+                    warn!("Skipping closure entry: {e:#}");
+                    None
+                }
+            })
+            .collect();
 
         Ok(closure)
     }
 }
 
+/// Combined size of `items` in the Nix store.
+#[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+pub fn store_size<'a>(items: impl IntoIterator<Item = &'a NixStoreItem>) -> Result<u64> {
+    let mut cmd = Command::new("nix-store");
+    cmd.args(["--query", "--size"])
+        .args(items.into_iter().map(NixStoreItem::path));
+    let output = run_command(cmd).context("Running nix-store query for sizes")?;
+    String::from_utf8(output.stdout)?
+        .lines()
+        .map(|size| {
+            size.trim()
+                .parse::<u64>()
+                .with_context(|| format!("Invalid store path size {size}"))
+        })
+        .sum()
+}
+
 #[derive(Debug, Clone, Deref, DerefMut)]
 pub struct ContainixFlake(NixFlake);
 
@@ -114,15 +161,14 @@ impl FromStr for ContainixFlake {
 }
 
 impl ContainixFlake {
-    pub fn build<F>(&self, f: F) -> Result<NixStoreItem>
-    where
-        F: FnOnce(&mut NixBuild),
-    {
+    /// Picks the output to use if none was given: the first of `containix` and `default`
+    /// among the packages for the current system.
+    pub fn resolve(&self) -> Result<ContainixFlake> {
         static DEFAULT_OUTPUT_NAMES: &[&str] = &["containix", "default"];
 
-        let c = if self.output().is_none() {
+        if self.output().is_none() {
             let system = get_nix_system()?;
-            let info = self.info()?;
+            let info = self.info().error_kind(ContainixError::FlakeEval)?;
             let Some(packages) = info.packages.as_ref().and_then(|p| p.get(&system)) else {
                 bail!("Container flake has no packages for {}", system);
             };
@@ -141,17 +187,28 @@ impl ContainixFlake {
                 );
                 bail!("Container flake does not provide expected output");
             };
-            ContainixFlake(self.with_output(format!("packages.{system}.{output}")))
+            Ok(ContainixFlake(
+                self.with_output(format!("packages.{system}.{output}")),
+            ))
         } else {
-            self.clone()
-        };
+            Ok(self.clone())
+        }
+    }
 
-        let build = c.0.build(|nix_cmd: &mut NixBuild| {
-            nix_cmd
-                .lock_file("containix.lock")
-                .symlink(FlakeOutputSymlink::None);
-            f(nix_cmd);
-        })?;
+    pub fn build<F>(&self, f: F) -> Result<NixStoreItem>
+    where
+        F: FnOnce(&mut NixBuild),
+    {
+        let c = self.resolve()?;
+        let build =
+            c.0.build(|nix_cmd: &mut NixBuild| {
+                nix_cmd
+                    .lock_file("containix.lock")
+                    .symlink(FlakeOutputSymlink::None)
+                    .progress("Building container");
+                f(nix_cmd);
+            })
+            .error_kind(ContainixError::FlakeBuild)?;
 
         let Some(path) = build.get_bin() else {
             bail!("Container flake did not provide a bin or out");
@@ -159,6 +216,102 @@ impl ContainixFlake {
 
         Ok(path.clone())
     }
+
+    /// Reads the entry point the flake declares in `passthru.containix.entrypoint`, if any.
+    pub fn entrypoint<F>(&self, f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(&mut NixBuild),
+    {
+        let c = self.resolve()?;
+        let mut nix_cmd = NixBuild::default();
+        nix_cmd
+            .arg("eval")
+            .arg(&c)
+            .arg("--apply")
+            .arg("p: p.passthru.containix.entrypoint or p.containix.entrypoint or null")
+            .json(true)
+            .lock_file("containix.lock");
+        f(&mut nix_cmd);
+        nix_cmd.run().error_kind(ContainixError::FlakeEval)
+    }
+
+    /// Reads the environment the flake declares in `passthru.containix.env`, an attribute set
+    /// of variable names to values. Without one, the environment is empty.
+    pub fn env<F>(&self, f: F) -> Result<Vec<EnvVariable>>
+    where
+        F: FnOnce(&mut NixBuild),
+    {
+        let c = self.resolve()?;
+        let mut nix_cmd = NixBuild::default();
+        nix_cmd
+            .arg("eval")
+            .arg(&c)
+            .arg("--apply")
+            .arg("p: p.passthru.containix.env or p.containix.env or {}")
+            .json(true)
+            .lock_file("containix.lock");
+        f(&mut nix_cmd);
+        let env: BTreeMap<String, serde_json::Value> =
+            nix_cmd.run().error_kind(ContainixError::FlakeEval)?;
+        env.into_iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    serde_json::Value::String(value) => value,
+                    serde_json::Value::Number(value) => value.to_string(),
+                    serde_json::Value::Bool(value) => value.to_string(),
+                    value => bail!("Flake env variable {key} must be a string, not {value}"),
+                };
+                Ok(EnvVariable::new(key, value))
+            })
+            .collect()
+    }
+
+    /// Evaluates `attr`, an attribute path into the flake’s outputs, and returns what nix
+    /// prints for it. Without an attribute, the flake’s output is evaluated, which is the
+    /// container package unless the reference names another one.
+    pub fn eval(&self, attr: Option<&str>, json: bool, impure: bool) -> Result<String> {
+        let flake = match attr {
+            Some(_) if self.output().is_some() => {
+                bail!("{self} already names an output, so no attribute can be given")
+            }
+            Some(attr) => self.with_output(attr),
+            None => self.resolve()?.0,
+        };
+        let mut nix_cmd = NixEval::default();
+        nix_cmd
+            .installable(flake.to_string())
+            .json(json)
+            .impure(impure)
+            .lock_file("containix.lock");
+        nix_cmd.output().error_kind(ContainixError::FlakeEval)
+    }
+
+    /// Reads the program of the flake’s default app (`apps.<system>.default.program`), which
+    /// `nix run` would start, if the flake has one. Needs the reference as given, not one
+    /// [`resolve`](Self::resolve)d to a package.
+    pub fn default_app_program<F>(&self, f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(&mut NixBuild),
+    {
+        let system = get_nix_system()?;
+        let mut nix_cmd = NixBuild::default();
+        nix_cmd
+            .arg("eval")
+            .arg(self.with_output(format!("apps.{system}.default.program")))
+            .json(true)
+            .lock_file("containix.lock");
+        f(&mut nix_cmd);
+        match nix_cmd.run() {
+            Ok(program) => Ok(Some(program)),
+            Err(e)
+                if e.downcast_ref::<CommandFailed>()
+                    .is_some_and(|failed| failed.stderr.contains("does not provide attribute")) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e).error_kind(ContainixError::FlakeEval),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -181,18 +334,138 @@ impl FromStr for NixFlake {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        if let Some((flake, output)) = s.split_once('#') {
-            Ok(Self {
-                flake: flake.to_string(),
-                output: Some(output.to_string()),
-            })
-        } else {
-            Ok(Self {
-                flake: s.to_string(),
-                output: None,
-            })
+        let (flake, output) = match s.split_once('#') {
+            Some((flake, output)) => (flake, Some(output.to_string())),
+            None => (s, None),
+        };
+        Ok(Self {
+            flake: canonicalize_flake_path(flake)?,
+            output,
+        })
+    }
+}
+
+/// Turns flake references that are filesystem paths (starting with `.` or `/`, or existing
+/// on disk) into absolute paths. Nix could otherwise look a relative path like
+/// `subdir/flake` up in the flake registry. The path stays a plain path rather than a `path:`
+/// URL, so nix still notices git repositories and only copies tracked files. Other
+/// references are returned as they are.
+fn canonicalize_flake_path(flake: &str) -> Result<String> {
+    let (path, query) = match flake.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (flake, None),
+    };
+    let is_path = path.starts_with('.') || path.starts_with('/') || Path::new(path).exists();
+    if !is_path {
+        validate_flake_ref(flake)?;
+        return Ok(flake.to_string());
+    }
+    // Parse errors only show their outermost message, so the cause is part of it.
+    let path = match std::fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(e) => bail!("Flake directory {path} can’t be resolved: {e}"),
+    };
+    let Some(path) = path.to_str() else {
+        bail!("Flake directory {} is not valid utf-8", path.display());
+    };
+    Ok(match query {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    })
+}
+
+/// Flake reference schemes that are URLs, with the URL schemes they accept after a `+`.
+const URL_FLAKE_SCHEMES: &[(&str, &[&str])] = &[
+    ("git", &["http", "https", "ssh", "git", "file"]),
+    ("hg", &["http", "https", "ssh", "file"]),
+    ("tarball", &["http", "https", "file"]),
+    ("file", &["http", "https", "file"]),
+];
+
+/// Catches obviously malformed flake references early, so a typo doesn’t surface as a
+/// confusing nix error later. Anything that passes is still up to nix to interpret.
+fn validate_flake_ref(flake: &str) -> Result<()> {
+    let reference = flake
+        .split_once('?')
+        .map_or(flake, |(reference, _)| reference);
+    if reference.is_empty() {
+        bail!("The flake reference is empty");
+    }
+    let Some((scheme, rest)) = reference.split_once(':') else {
+        // An indirect reference to the flake registry, like `nixpkgs` or
+        // `nixpkgs/nixos-24.05`, optionally followed by a branch and a revision.
+        let (id, branch) = reference.split_once('/').unwrap_or((reference, ""));
+        let valid_id = !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        let valid_branch = branch
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c));
+        if !valid_id || !valid_branch {
+            bail!("Flake reference {flake} is neither a path, a URL nor a flake registry name");
+        }
+        return Ok(());
+    };
+    match scheme {
+        "github" | "gitlab" | "sourcehut" => {
+            let mut components = rest.split('/');
+            let (Some(owner), Some(repo)) = (components.next(), components.next()) else {
+                bail!("Flake reference {flake} needs a repository, like {scheme}:owner/repo");
+            };
+            if owner.is_empty() || repo.is_empty() {
+                bail!("Flake reference {flake} needs a repository, like {scheme}:owner/repo");
+            }
+        }
+        "path" | "flake" => {
+            if rest.is_empty() {
+                bail!("Flake reference {flake} is missing what comes after {scheme}:");
+            }
+        }
+        "http" | "https" | "git" | "file" => validate_flake_url(flake, scheme, rest)?,
+        _ => {
+            let Some((kind, url_scheme)) = scheme.split_once('+') else {
+                bail!(
+                    "Flake reference {flake} has unknown scheme {scheme}, expected e.g. github:, \
+                    git+https:, tarball+https:, path: or a local path"
+                );
+            };
+            let Some((_, url_schemes)) = URL_FLAKE_SCHEMES.iter().find(|(k, _)| *k == kind) else {
+                bail!("Flake reference {flake} has unknown type {kind}, expected git, hg, tarball or file");
+            };
+            if !url_schemes.contains(&url_scheme) {
+                bail!(
+                    "Flake reference {flake} has unsupported URL scheme {url_scheme}, expected one of {}",
+                    url_schemes.join(", ")
+                );
+            }
+            validate_flake_url(flake, scheme, rest)?;
         }
     }
+    Ok(())
+}
+
+/// Checks that `rest`, what follows `scheme:` in `flake`, is `//host/path`. `file` URLs can
+/// also leave out the `//` and take a path right away.
+fn validate_flake_url(flake: &str, scheme: &str, rest: &str) -> Result<()> {
+    if scheme == "file" || scheme.ends_with("+file") {
+        // After `//` comes an optional host, like `localhost`, and then the absolute path.
+        let path = match rest.strip_prefix("//") {
+            Some(rest) => rest.find('/').map_or("", |index| &rest[index..]),
+            None => rest,
+        };
+        if path.is_empty() {
+            bail!("Flake reference {flake} needs a path, like {scheme}:///path");
+        }
+        return Ok(());
+    }
+    let Some(rest) = rest.strip_prefix("//") else {
+        bail!("Flake reference {flake} is missing the // after {scheme}:");
+    };
+    if rest.split('/').next().unwrap_or_default().is_empty() {
+        bail!("Flake reference {flake} is missing a host after {scheme}://");
+    }
+    Ok(())
 }
 
 impl NixFlake {
@@ -233,7 +506,12 @@ impl NixFlake {
     #[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
     pub fn info(&self) -> Result<NixFlakeShowOutput> {
         let mut nix_cmd = NixBuild::default();
-        nix_cmd.arg("flake").arg("show").arg(self).json(true);
+        nix_cmd
+            .arg("flake")
+            .arg("show")
+            .arg(self)
+            .json(true)
+            .progress("Evaluating flake");
         let output: NixFlakeShowOutput = nix_cmd.run()?;
         Ok(output)
     }
@@ -266,6 +544,8 @@ impl NixBuildResult {
 pub struct NixFlakeShowOutput {
     pub packages: Option<NixFlakePackages>,
     pub legacy_packages: Option<NixFlakePackages>,
+    /// Apps have the same shape as packages in `nix flake show`.
+    pub apps: Option<NixFlakePackages>,
     // Other items emitted
 }
 
@@ -316,11 +596,188 @@ pub struct NixFlakeBuildOutput {
     outputs: HashMap<String, NixStoreItem>,
 }
 
+/// Returns the system nix builds for. Nix is only asked once per process.
 #[instrument(level = "trace", ret)]
 pub fn get_nix_system() -> Result<NixSystem> {
+    static NIX_SYSTEM: OnceLock<NixSystem> = OnceLock::new();
+    if let Some(system) = NIX_SYSTEM.get() {
+        return Ok(system.clone());
+    }
+
     let mut nix_cmd = NixEval::default();
     nix_cmd.impure(true).expression("builtins.currentSystem");
 
     let system: NixSystem = nix_cmd.run()?;
-    Ok(system)
+    Ok(NIX_SYSTEM.get_or_init(|| system).clone())
+}
+
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, _) => name.is_empty(),
+        (Some((b'*', rest)), _) => {
+            glob_match(rest, name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((_, name_rest))) => glob_match(rest, name_rest),
+        (Some((p, rest)), Some((n, name_rest))) if p == n => glob_match(rest, name_rest),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        os::unix::process::ExitStatusExt,
+        process::{ExitStatus, Output},
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::command::set_command_runner;
+
+    const HELLO: &str = "/nix/store/0c0ah3gnvw5bdl4pnc4wa6qzhbm6n6xv-hello-2.12";
+    const HELLO_BIN: &str = "/nix/store/1c0ah3gnvw5bdl4pnc4wa6qzhbm6n6xv-hello-2.12-bin";
+
+    /// Makes nix print `stdout` and returns the arguments it was called with.
+    fn fake_nix(stdout: &str) -> Arc<Mutex<Vec<String>>> {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let recorded = calls.clone();
+        let stdout = stdout.to_string();
+        set_command_runner(Some(Arc::new(move |command: Command| {
+            recorded.lock().unwrap().extend(
+                command
+                    .get_args()
+                    .map(|arg| arg.to_string_lossy().into_owned()),
+            );
+            Ok(Output {
+                status: ExitStatus::from_raw(0),
+                stdout: stdout.clone().into_bytes(),
+                stderr: vec![],
+            })
+        })));
+        calls
+    }
+
+    #[test]
+    fn accepts_flake_urls() {
+        let cases = [
+            "https://example.com/flake.tar.gz",
+            "git://example.com/repo",
+            "git+https://example.com/repo?ref=main",
+            "git+ssh://git@example.com/repo",
+            "git+git://example.com/repo",
+            "git+file:///src/repo",
+            "git+file://localhost/src/repo",
+            "hg+https://example.com/repo",
+            "tarball+https://example.com/flake.tar.gz",
+            "file:///src/flake.tar.gz",
+            "file://localhost/src/flake.tar.gz",
+            "file:/src/flake.tar.gz",
+            "file+https://example.com/flake.nix",
+        ];
+        for flake in cases {
+            validate_flake_ref(flake).unwrap_or_else(|e| panic!("{flake}: {e:#}"));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_flake_urls() {
+        let cases = [
+            (
+                "https:example.com/flake.tar.gz",
+                "missing the // after https:",
+            ),
+            ("https:///flake.tar.gz", "missing a host after https://"),
+            ("git+git:///repo", "missing a host after git+git://"),
+            ("git+ftp://example.com/repo", "unsupported URL scheme ftp"),
+            (
+                "tarball+git://example.com/repo",
+                "unsupported URL scheme git",
+            ),
+            ("hg+git://example.com/repo", "unsupported URL scheme git"),
+            ("svn+https://example.com/repo", "unknown type svn"),
+            ("file:", "needs a path"),
+            ("file://", "needs a path"),
+            ("file://localhost", "needs a path"),
+            ("git+file://localhost", "needs a path"),
+        ];
+        for (flake, expected) in cases {
+            let err = validate_flake_ref(flake).unwrap_err().to_string();
+            assert!(err.contains(expected), "{flake}: {err}");
+        }
+    }
+
+    /// Makes nix answer the system query and fail everything else with `stderr`.
+    fn failing_nix(stderr: &str) {
+        let stderr = stderr.to_string();
+        set_command_runner(Some(Arc::new(move |command: Command| {
+            if command
+                .get_args()
+                .any(|arg| arg == "builtins.currentSystem")
+            {
+                return Ok(Output {
+                    status: ExitStatus::from_raw(0),
+                    stdout: br#""x86_64-linux""#.to_vec(),
+                    stderr: vec![],
+                });
+            }
+            Err(CommandFailed {
+                command: format!("{command:?}"),
+                stderr: stderr.clone(),
+            }
+            .into())
+        })));
+    }
+
+    #[test]
+    fn default_app_program_is_optional() {
+        let flake: ContainixFlake = "github:owner/repo".parse().unwrap();
+        failing_nix(
+            "error: flake 'github:owner/repo' does not provide attribute \
+            'apps.x86_64-linux.default.program'",
+        );
+        assert_eq!(flake.default_app_program(|_| {}).unwrap(), None);
+
+        failing_nix("error: unable to download 'https://github.com/owner/repo'");
+        assert!(flake.default_app_program(|_| {}).is_err());
+    }
+
+    #[test]
+    fn build_parses_outputs() {
+        let calls = fake_nix(&format!(
+            r#"[{{"drvPath": "/nix/store/x.drv", "outputs": {{"out": "{HELLO}", "bin": "{HELLO_BIN}"}}}}]"#
+        ));
+        let flake: NixFlake = "nixpkgs#hello".parse().unwrap();
+        let result = flake
+            .build(|cmd| {
+                cmd.extra_arg("--accept-flake-config");
+            })
+            .unwrap();
+        assert_eq!(result.get_out().unwrap().path(), Path::new(HELLO));
+        assert_eq!(result.get_bin().unwrap().path(), Path::new(HELLO_BIN));
+        assert_eq!(result.get_or_out("man").unwrap().path(), Path::new(HELLO));
+
+        let args = calls.lock().unwrap();
+        assert_eq!(args[..2], ["build", "nixpkgs#hello"]);
+        assert!(args.contains(&"--json".to_string()));
+        assert!(args.contains(&"--no-link".to_string()));
+        assert_eq!(args.last().unwrap(), "--accept-flake-config");
+    }
+
+    #[test]
+    fn build_rejects_multiple_derivations() {
+        fake_nix(&format!(
+            r#"[{{"drvPath": "/nix/store/x.drv", "outputs": {{"out": "{HELLO}"}}}},
+                {{"drvPath": "/nix/store/y.drv", "outputs": {{"out": "{HELLO_BIN}"}}}}]"#
+        ));
+        let flake: NixFlake = "nixpkgs#hello".parse().unwrap();
+        let err = flake.build(|_| {}).unwrap_err();
+        assert!(err.to_string().contains("more than one output derivation"));
+    }
+
+    #[test]
+    fn build_rejects_invalid_store_paths() {
+        fake_nix(r#"[{"drvPath": "/nix/store/x.drv", "outputs": {"out": "/tmp/hello"}}]"#);
+        let flake: NixFlake = "nixpkgs#hello".parse().unwrap();
+        assert!(flake.build(|_| {}).is_err());
+    }
 }
@@ -1,11 +1,149 @@
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
 
 pub trait PathExt {
     fn rootless(&self) -> &Path;
+
+    /// Checks that this is an absolute path without `..` components and returns it
+    /// with `.` components and redundant separators removed.
+    fn normalize_container_path(&self) -> Result<PathBuf>;
 }
 
 impl PathExt for Path {
     fn rootless(&self) -> &Path {
         self.strip_prefix("/").unwrap_or(self)
     }
+
+    fn normalize_container_path(&self) -> Result<PathBuf> {
+        if !self.is_absolute() {
+            bail!("Container path {} must be absolute", self.display());
+        }
+        let mut normalized = PathBuf::from("/");
+        for component in self.components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::Normal(c) => normalized.push(c),
+                Component::ParentDir => {
+                    bail!("Container path {} must not contain `..`", self.display())
+                }
+                Component::Prefix(_) => unreachable!(),
+            }
+        }
+        Ok(normalized)
+    }
+}
+
+/// Joins the container path `path` onto `root`.
+///
+/// Fails if any part of the path that already exists below `root` is a symlink, as it
+/// could point outside of the root and make a mount escape the container.
+pub fn join_within_root(root: &Path, path: &Path) -> Result<PathBuf> {
+    let path = path.normalize_container_path()?;
+    let mut current = root.to_path_buf();
+    for component in path.rootless().components() {
+        current.push(component);
+        match std::fs::symlink_metadata(&current) {
+            Ok(metadata) if metadata.file_type().is_symlink() => bail!(
+                "Container path {} traverses the symlink {}",
+                path.display(),
+                current.display()
+            ),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(root.join(path.rootless()));
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Inspecting {}", current.display()));
+            }
+        }
+    }
+    Ok(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn normalizes_container_paths() {
+        let cases = [
+            ("/", "/"),
+            ("/data", "/data"),
+            ("/data/", "/data"),
+            ("//data//./sub/.", "/data/sub"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                Path::new(input).normalize_container_path().unwrap(),
+                Path::new(expected),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_container_paths() {
+        let cases = [
+            ("data", "must be absolute"),
+            ("./data", "must be absolute"),
+            ("", "must be absolute"),
+            ("/..", "must not contain `..`"),
+            ("/../etc", "must not contain `..`"),
+            ("/data/../../etc", "must not contain `..`"),
+        ];
+        for (input, expected) in cases {
+            let err = Path::new(input)
+                .normalize_container_path()
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains(expected), "{input}: {err}");
+        }
+    }
+
+    #[test]
+    fn joins_within_root() {
+        let tempdir = TempDir::new().unwrap();
+        tempdir.create().unwrap();
+        let root = tempdir.join("root");
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+
+        let cases = [
+            ("/etc", root.join("etc")),
+            ("/etc/./hosts", root.join("etc/hosts")),
+            ("/missing/dir", root.join("missing/dir")),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                join_within_root(&root, Path::new(input)).unwrap(),
+                expected,
+                "{input}"
+            );
+        }
+        for input in ["/../outside", "relative", "/etc/../../outside"] {
+            assert!(
+                join_within_root(&root, Path::new(input)).is_err(),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_symlinks_out_of_root() {
+        let tempdir = TempDir::new().unwrap();
+        tempdir.create().unwrap();
+        let root = tempdir.join("root");
+        let outside = tempdir.join("outside");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("escape")).unwrap();
+
+        for input in ["/escape", "/escape/file", "/escape/missing/dir"] {
+            let err = join_within_root(&root, Path::new(input))
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("traverses the symlink"), "{input}: {err}");
+        }
+    }
 }
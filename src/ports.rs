@@ -1,36 +1,315 @@
 use std::fmt;
+use std::net::IpAddr;
 use std::str::FromStr;
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            _ => bail!("Invalid protocol `{s}`, expected tcp or udp"),
+        }
+    }
+}
+
+/// An inclusive range of ports, written as `PORT` or `START-END`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    /// Number of ports in the range.
+    pub fn count(&self) -> u32 {
+        u32::from(self.end - self.start) + 1
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> {
+        self.start..=self.end
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let start: u16 = start
+            .parse()
+            .with_context(|| format!("Invalid port `{start}`"))?;
+        let end: u16 = end
+            .parse()
+            .with_context(|| format!("Invalid port `{end}`"))?;
+        if start > end {
+            bail!("Port range {s} ends before it starts");
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+/// Ports forwarded from the host into the container.
+///
+/// The grammar is `[HOST_IP:]HOST_PORT[-END]:CONTAINER_PORT[-END][/PROTO[,PROTO]]`, e.g.:
+///
+/// | Mapping                      | Forwards                                          |
+/// |------------------------------|---------------------------------------------------|
+/// | `8080`                       | host 8080 to container 8080, TCP                  |
+/// | `8080:80`                    | host 8080 to container 80, TCP                    |
+/// | `8080:80/udp`                | host 8080 to container 80, UDP                    |
+/// | `53:53/tcp,udp`              | host 53 to container 53, TCP and UDP              |
+/// | `8000-8009:9000-9009`        | host 8000–8009 to container 9000–9009, TCP        |
+/// | `127.0.0.1:8080:80`          | host 127.0.0.1:8080 to container 80, TCP          |
+/// | `[::1]:8080-8081:80-81/udp`  | host [::1]:8080–8081 to container 80–81, UDP      |
+///
+/// Host and container ranges must have the same length. Without a host IP, the ports are
+/// forwarded on all host addresses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PortMapping {
-    pub host_port: u16,
-    pub container_port: u16,
+    pub host_ip: Option<IpAddr>,
+    pub host_ports: PortRange,
+    pub container_ports: PortRange,
+    pub protocols: Vec<Protocol>,
+}
+
+impl PortMapping {
+    /// Every single forward described by this mapping as `(protocol, host port, container port)`.
+    pub fn forwards(&self) -> impl Iterator<Item = (Protocol, u16, u16)> + '_ {
+        self.protocols.iter().flat_map(|&protocol| {
+            self.host_ports
+                .iter()
+                .zip(self.container_ports.iter())
+                .map(move |(host, container)| (protocol, host, container))
+        })
+    }
 }
 
 impl fmt::Display for PortMapping {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}:{}", self.host_port, self.container_port)
+        match self.host_ip {
+            Some(IpAddr::V4(ip)) => write!(f, "{ip}:")?,
+            Some(IpAddr::V6(ip)) => write!(f, "[{ip}]:")?,
+            None => {}
+        }
+        write!(f, "{}:{}", self.host_ports, self.container_ports)?;
+        if self.protocols != [Protocol::Tcp] {
+            let protocols: Vec<_> = self.protocols.iter().map(ToString::to_string).collect();
+            write!(f, "/{}", protocols.join(","))?;
+        }
+        Ok(())
     }
 }
 
 impl FromStr for PortMapping {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> Result<Self> {
-        if !s.contains(":") {
-            let port: u16 = s.parse()?;
-            return Ok(PortMapping {
-                host_port: port,
-                container_port: port,
-            });
-        }
-        let Some((host_port, container_port)) = s.split_once(':') else {
-            bail!("Invalid port mapping: {s}");
+        let (ports, protocols) = match s.rsplit_once('/') {
+            Some((ports, protocols)) => (
+                ports,
+                protocols
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<Result<Vec<_>>>()
+                    .with_context(|| format!("Invalid protocols in port mapping {s}"))?,
+            ),
+            None => (s, vec![Protocol::Tcp]),
         };
+
+        let (host_ip, ports) = match ports.strip_prefix('[') {
+            Some(rest) => {
+                let Some((ip, ports)) = rest
+                    .split_once(']')
+                    .and_then(|(ip, rest)| Some((ip, rest.strip_prefix(':')?)))
+                else {
+                    bail!("Invalid port mapping {s}, expected [IPV6]:HOST_PORT:CONTAINER_PORT");
+                };
+                (Some(ip), ports)
+            }
+            None => match ports.split(':').count() {
+                3 => {
+                    let (ip, ports) = ports.split_once(':').unwrap();
+                    (Some(ip), ports)
+                }
+                n if n > 3 => {
+                    bail!("Invalid port mapping {s}, IPv6 host IPs must be in brackets like [::1]:8080:80")
+                }
+                _ => (None, ports),
+            },
+        };
+        let host_ip = host_ip
+            .map(|ip| {
+                ip.parse::<IpAddr>()
+                    .with_context(|| format!("Invalid host IP `{ip}` in port mapping {s}"))
+            })
+            .transpose()?;
+        if let Some(IpAddr::V4(ip)) = host_ip.filter(|_| s.starts_with('[')) {
+            bail!("Only IPv6 host IPs are put in brackets, but port mapping {s} has {ip}");
+        }
+
+        let (host_ports, container_ports) = match ports.split_once(':') {
+            Some((host_ports, container_ports)) => (
+                host_ports
+                    .parse::<PortRange>()
+                    .with_context(|| format!("Invalid host port in port mapping {s}"))?,
+                container_ports
+                    .parse::<PortRange>()
+                    .with_context(|| format!("Invalid container port in port mapping {s}"))?,
+            ),
+            None => {
+                if host_ip.is_some() {
+                    bail!("Port mapping {s} with a host IP needs both a host and a container port");
+                }
+                let ports = ports
+                    .parse::<PortRange>()
+                    .with_context(|| format!("Invalid port in port mapping {s}"))?;
+                (ports, ports)
+            }
+        };
+        if host_ports.count() != container_ports.count() {
+            bail!("Host ports {host_ports} and container ports {container_ports} in port mapping {s} differ in length");
+        }
+
         Ok(PortMapping {
-            host_port: host_port.parse()?,
-            container_port: container_port.parse()?,
+            host_ip,
+            host_ports,
+            container_ports,
+            protocols,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(
+        host_ip: Option<&str>,
+        host_ports: (u16, u16),
+        container_ports: (u16, u16),
+        protocols: &[Protocol],
+    ) -> PortMapping {
+        PortMapping {
+            host_ip: host_ip.map(|ip| ip.parse().unwrap()),
+            host_ports: PortRange {
+                start: host_ports.0,
+                end: host_ports.1,
+            },
+            container_ports: PortRange {
+                start: container_ports.0,
+                end: container_ports.1,
+            },
+            protocols: protocols.to_vec(),
+        }
+    }
+
+    #[test]
+    fn parses_valid_mappings() {
+        use Protocol::*;
+        let cases = [
+            ("8080", mapping(None, (8080, 8080), (8080, 8080), &[Tcp])),
+            ("8080:80", mapping(None, (8080, 8080), (80, 80), &[Tcp])),
+            ("8080:80/udp", mapping(None, (8080, 8080), (80, 80), &[Udp])),
+            (
+                "53:53/tcp,udp",
+                mapping(None, (53, 53), (53, 53), &[Tcp, Udp]),
+            ),
+            (
+                "8000-8009:9000-9009",
+                mapping(None, (8000, 8009), (9000, 9009), &[Tcp]),
+            ),
+            (
+                "8000-8009",
+                mapping(None, (8000, 8009), (8000, 8009), &[Tcp]),
+            ),
+            (
+                "127.0.0.1:8080:80",
+                mapping(Some("127.0.0.1"), (8080, 8080), (80, 80), &[Tcp]),
+            ),
+            (
+                "[::1]:8080:80",
+                mapping(Some("::1"), (8080, 8080), (80, 80), &[Tcp]),
+            ),
+            (
+                "[::1]:8080-8081:80-81/udp",
+                mapping(Some("::1"), (8080, 8081), (80, 81), &[Udp]),
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(input.parse::<PortMapping>().unwrap(), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_mappings() {
+        let cases = [
+            ("8080:80/sctp", "Invalid protocols"),
+            ("8080:80/", "Invalid protocols"),
+            ("[::1:8080:80", "expected [IPV6]:HOST_PORT:CONTAINER_PORT"),
+            ("[::1]8080:80", "expected [IPV6]:HOST_PORT:CONTAINER_PORT"),
+            ("::1:8080:80", "must be in brackets"),
+            ("localhost:8080:80", "Invalid host IP"),
+            (
+                "[127.0.0.1]:8080:80",
+                "Only IPv6 host IPs are put in brackets",
+            ),
+            ("http:80", "Invalid host port"),
+            ("8080:http", "Invalid container port"),
+            ("70000:80", "Invalid host port"),
+            ("8009-8000:80", "Invalid host port"),
+            ("[::1]:8080", "needs both a host and a container port"),
+            ("127.0.0.1:8080", "Invalid host port"),
+            ("http", "Invalid port"),
+            ("", "Invalid port"),
+            ("8000-8009:80", "differ in length"),
+            ("8000-8001:80-82", "differ in length"),
+        ];
+        for (input, expected) in cases {
+            let err = input.parse::<PortMapping>().unwrap_err().to_string();
+            assert!(err.contains(expected), "{input}: {err}");
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for input in [
+            "8080:8080",
+            "8080:80",
+            "53:53/tcp,udp",
+            "8000-8009:9000-9009/udp",
+            "127.0.0.1:8080:80",
+            "[::1]:8080-8081:80-81/udp",
+        ] {
+            let mapping: PortMapping = input.parse().unwrap();
+            assert_eq!(mapping.to_string(), input);
+            assert_eq!(mapping.to_string().parse::<PortMapping>().unwrap(), mapping);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use nix::{
+    errno::Errno,
+    mount::MntFlags,
+    sys::signal::kill,
+    unistd::{getuid, Pid},
+};
+use tracing::{debug, instrument, warn, Level};
+
+use crate::tempdir::OWNER_FILE;
+
+/// Prefixes of the tempdirs containix creates.
+const TEMPDIR_PREFIXES: &[&str] = &["containix-container-", "containix-oci-"];
+
+/// A tempdir left behind by a containix process that is no longer running.
+#[derive(Debug, Clone)]
+pub struct Leftover {
+    pub path: PathBuf,
+    /// PID of the process that created the tempdir.
+    pub pid: u32,
+}
+
+/// Finds the current user’s tempdirs of containix processes that exited without cleaning
+/// up. Tempdirs without an owner file are skipped, as there’s no telling whether they are
+/// still in use.
+#[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+pub fn find_leftovers() -> Result<Vec<Leftover>> {
+    let temp_dir = std::env::temp_dir();
+    let uid = getuid().as_raw();
+    let mut leftovers = Vec::new();
+    for entry in
+        std::fs::read_dir(&temp_dir).with_context(|| format!("Reading {}", temp_dir.display()))?
+    {
+        let entry = entry.with_context(|| format!("Reading {}", temp_dir.display()))?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !TEMPDIR_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+        {
+            continue;
+        }
+        let path = entry.path();
+        let Ok(metadata) = std::fs::symlink_metadata(&path) else {
+            continue;
+        };
+        if !metadata.is_dir() || metadata.uid() != uid {
+            continue;
+        }
+        let Some(pid) = std::fs::read_to_string(path.join(OWNER_FILE))
+            .ok()
+            .and_then(|pid| pid.trim().parse().ok())
+        else {
+            debug!("Skipping {}, it has no owner", path.display());
+            continue;
+        };
+        if is_running(pid) {
+            debug!("Skipping {}, PID {pid} is still running", path.display());
+            continue;
+        }
+        leftovers.push(Leftover { path, pid });
+    }
+    Ok(leftovers)
+}
+
+/// Removes a leftover tempdir.
+///
+/// Mounts below the tempdir are unmounted first, lazily if they are busy. If any remain,
+/// the tempdir is kept, as deleting it would delete the mounted files as well.
+#[instrument(level = "trace", skip_all, fields(path = %leftover.path.display()), err(level = Level::TRACE))]
+pub fn remove_leftover(leftover: &Leftover) -> Result<()> {
+    let path = std::fs::canonicalize(&leftover.path)
+        .with_context(|| format!("Resolving {}", leftover.path.display()))?;
+    for mount in mounts_below(&path)? {
+        debug!("Unmounting {}", mount.display());
+        if let Err(e) = nix::mount::umount(&mount) {
+            warn!("Unmounting {} failed ({e}), detaching it", mount.display());
+            if let Err(e) = nix::mount::umount2(&mount, MntFlags::MNT_DETACH) {
+                warn!("Detaching {} failed: {e}", mount.display());
+            }
+        }
+    }
+    let remaining = mounts_below(&path)?;
+    if !remaining.is_empty() {
+        bail!(
+            "{} is still mounted, not removing it",
+            remaining
+                .iter()
+                .map(|mount| mount.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    if std::fs::remove_dir_all(&path).is_err() {
+        // Volumes copied from the Nix store keep its read-only permissions.
+        make_directories_writable(&path)?;
+        std::fs::remove_dir_all(&path).with_context(|| format!("Removing {}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = i32::try_from(pid) else {
+        return false;
+    };
+    // EPERM means the process exists, but belongs to another user.
+    !matches!(kill(Pid::from_raw(pid), None), Err(Errno::ESRCH))
+}
+
+/// Mount points below (or at) `dir` in the current mount namespace, deepest first.
+fn mounts_below(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").context("Reading mountinfo")?;
+    let mut mounts: Vec<_> = mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(unescape_mountinfo)
+        .filter(|mount| mount.starts_with(dir))
+        .collect();
+    mounts.sort_by_key(|mount| std::cmp::Reverse(mount.components().count()));
+    mounts.dedup();
+    Ok(mounts)
+}
+
+/// Decodes the octal escapes mountinfo uses for whitespace and backslashes in paths,
+/// like `\040` for a space.
+fn unescape_mountinfo(field: &str) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'\\')
+            .then(|| field.get(i + 1..i + 4))
+            .flatten()
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match escaped {
+            Some(byte) => {
+                unescaped.push(byte);
+                i += 4;
+            }
+            None => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(std::ffi::OsString::from_vec(unescaped))
+}
+
+fn make_directories_writable(path: &Path) -> Result<()> {
+    let metadata =
+        std::fs::symlink_metadata(path).with_context(|| format!("Reading {}", path.display()))?;
+    if !metadata.is_dir() {
+        return Ok(());
+    }
+    let mut permissions = metadata.permissions();
+    permissions.set_mode(permissions.mode() | 0o700);
+    std::fs::set_permissions(path, permissions)
+        .with_context(|| format!("Making {} writable", path.display()))?;
+    for entry in std::fs::read_dir(path).with_context(|| format!("Reading {}", path.display()))? {
+        let entry = entry.with_context(|| format!("Reading {}", path.display()))?;
+        make_directories_writable(&entry.path())?;
+    }
+    Ok(())
+}
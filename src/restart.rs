@@ -0,0 +1,75 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use anyhow::{bail, Context, Result};
+
+/// When to restart a container after its process exited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RestartPolicy {
+    #[default]
+    No,
+    /// Restart when the process exited with a non-zero status, up to `max_retries` times.
+    OnFailure {
+        max_retries: Option<u32>,
+    },
+    Always,
+}
+
+impl RestartPolicy {
+    const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    /// Decides whether to restart after the container exited with `status` (`None` if it
+    /// was killed by a signal) and has been restarted `restarts` times already.
+    pub fn should_restart(&self, status: Option<i32>, restarts: u32) -> bool {
+        match self {
+            RestartPolicy::No => false,
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure { max_retries } => {
+                status != Some(0) && max_retries.is_none_or(|max| restarts < max)
+            }
+        }
+    }
+
+    /// Time to wait before the given restart, doubling with every attempt.
+    pub fn backoff(&self, restarts: u32) -> Duration {
+        Self::INITIAL_BACKOFF
+            .saturating_mul(2u32.saturating_pow(restarts))
+            .min(Self::MAX_BACKOFF)
+    }
+}
+
+impl FromStr for RestartPolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (policy, max_retries) = match s.split_once(':') {
+            Some((policy, max_retries)) => (policy, Some(max_retries)),
+            None => (s, None),
+        };
+        match (policy, max_retries) {
+            ("no", None) => Ok(RestartPolicy::No),
+            ("always", None) => Ok(RestartPolicy::Always),
+            ("on-failure", max_retries) => Ok(RestartPolicy::OnFailure {
+                max_retries: max_retries
+                    .map(|v| v.parse())
+                    .transpose()
+                    .with_context(|| format!("Invalid maximum retry count in {s}"))?,
+            }),
+            _ => bail!(
+                "Invalid restart policy {s}, expected no, always or on-failure[:<MAX RETRIES>]"
+            ),
+        }
+    }
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestartPolicy::No => write!(f, "no"),
+            RestartPolicy::Always => write!(f, "always"),
+            RestartPolicy::OnFailure { max_retries: None } => write!(f, "on-failure"),
+            RestartPolicy::OnFailure {
+                max_retries: Some(max),
+            } => write!(f, "on-failure:{max}"),
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use nix::sys::resource::{rlim_t, setrlimit, Resource, RLIM_INFINITY};
+use serde::{Deserialize, Serialize};
+
+/// Limit names as `ulimit` and Docker call them.
+static RESOURCES: &[(&str, Resource)] = &[
+    ("as", Resource::RLIMIT_AS),
+    ("core", Resource::RLIMIT_CORE),
+    ("cpu", Resource::RLIMIT_CPU),
+    ("data", Resource::RLIMIT_DATA),
+    ("fsize", Resource::RLIMIT_FSIZE),
+    ("locks", Resource::RLIMIT_LOCKS),
+    ("memlock", Resource::RLIMIT_MEMLOCK),
+    ("msgqueue", Resource::RLIMIT_MSGQUEUE),
+    ("nice", Resource::RLIMIT_NICE),
+    ("nofile", Resource::RLIMIT_NOFILE),
+    ("nproc", Resource::RLIMIT_NPROC),
+    ("rss", Resource::RLIMIT_RSS),
+    ("rtprio", Resource::RLIMIT_RTPRIO),
+    ("sigpending", Resource::RLIMIT_SIGPENDING),
+    ("stack", Resource::RLIMIT_STACK),
+];
+
+/// A resource limit for the container process, written as `NAME=SOFT[:HARD]`, like
+/// `nofile=1024:2048`. Without a hard limit, both are set to the same value. `unlimited`
+/// lifts a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Rlimit {
+    pub resource: Resource,
+    pub soft: rlim_t,
+    pub hard: rlim_t,
+}
+
+impl Rlimit {
+    pub fn name(&self) -> &'static str {
+        RESOURCES
+            .iter()
+            .find(|(_, resource)| *resource == self.resource)
+            .map(|(name, _)| *name)
+            .unwrap_or("unknown")
+    }
+
+    /// Applies the limit to the current process. Raising a hard limit needs `CAP_SYS_RESOURCE`
+    /// in the initial user namespace, so it usually can only be lowered.
+    pub fn apply(&self) -> nix::Result<()> {
+        setrlimit(self.resource, self.soft, self.hard)
+    }
+}
+
+impl FromStr for Rlimit {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((name, values)) = s.split_once('=') else {
+            bail!("Invalid rlimit {s}, expected NAME=SOFT[:HARD]");
+        };
+        let Some(&(_, resource)) = RESOURCES.iter().find(|(known, _)| *known == name) else {
+            bail!(
+                "Unknown rlimit {name}, expected one of {}",
+                RESOURCES
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        };
+        let (soft, hard) = match values.split_once(':') {
+            Some((soft, hard)) => (parse_value(soft)?, parse_value(hard)?),
+            None => {
+                let value = parse_value(values)?;
+                (value, value)
+            }
+        };
+        if soft > hard {
+            bail!("The soft limit of {s} is higher than its hard limit");
+        }
+        Ok(Rlimit {
+            resource,
+            soft,
+            hard,
+        })
+    }
+}
+
+impl TryFrom<String> for Rlimit {
+    type Error = anyhow::Error;
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Rlimit> for String {
+    fn from(rlimit: Rlimit) -> Self {
+        rlimit.to_string()
+    }
+}
+
+fn parse_value(value: &str) -> Result<rlim_t> {
+    if value == "unlimited" {
+        return Ok(RLIM_INFINITY);
+    }
+    value
+        .parse()
+        .with_context(|| format!("Invalid rlimit value {value}"))
+}
+
+impl fmt::Display for Rlimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = |value: rlim_t| {
+            if value == RLIM_INFINITY {
+                "unlimited".to_string()
+            } else {
+                value.to_string()
+            }
+        };
+        write!(
+            f,
+            "{}={}:{}",
+            self.name(),
+            value(self.soft),
+            value(self.hard)
+        )
+    }
+}
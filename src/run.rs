@@ -0,0 +1,582 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use derive_builder::Builder;
+use nix::unistd::AccessFlags;
+use tracing::{debug, info, instrument, warn, Level};
+
+use crate::{
+    capabilities::CapabilitySet,
+    cli_wrappers::{nix::NixBuild, slirp::SlirpHandle},
+    command::NixUnistdChild,
+    container::{enter_root_ns, ContainerBuilder, ContainerFsBuilder, ContainerGuard},
+    env::{EnvPrecedence, EnvVariable},
+    events::{self, Event},
+    host_tools::HostTools,
+    hosts::HostEntry,
+    nix_helpers::{store_size, ContainixFlake, NixStoreItem},
+    ports::PortMapping,
+    rlimit::Rlimit,
+    seccomp::SeccompProfile,
+    size::ByteSize,
+    timezone::Timezone,
+    timings,
+    unshare::{IdRangeMap, UnshareNamespaces},
+    user::UserSpec,
+    volume_mount::{Mount, VolumeMount},
+};
+
+/// Everything needed to build a container flake and start it.
+#[derive(Debug, Builder)]
+#[builder(pattern = "owned")]
+pub struct RunSpec {
+    flake: ContainixFlake,
+    /// More flakes whose closures are merged into the container, e.g. debugging tools. Their
+    /// binaries are added to `PATH` after the main flake’s.
+    #[builder(default, setter(into))]
+    with_flakes: Vec<ContainixFlake>,
+    /// Arguments passed to the entry point.
+    #[builder(default, setter(into))]
+    pub(crate) args: Vec<String>,
+    /// Command to run instead of the flake’s entry point.
+    #[builder(default, setter(into))]
+    entrypoint: Option<String>,
+    #[builder(default, setter(into))]
+    argv0: Option<String>,
+    /// Environment variables, set after `PATH` and `TZ` so they can override them.
+    #[builder(default, setter(into))]
+    env: Vec<EnvVariable>,
+    /// Variables inherited from the host, like `TERM`.
+    #[builder(default, setter(into))]
+    host_env: Vec<EnvVariable>,
+    #[builder(default)]
+    env_precedence: EnvPrecedence,
+    /// Set `PATH` to the flakes’ binaries.
+    #[builder(default = "true")]
+    default_path: bool,
+    /// Import the environment the flake declares in `passthru.containix.env`.
+    #[builder(default)]
+    env_from_flake: bool,
+    /// Metadata without effect on the container. Exported images carry them as labels.
+    #[builder(default, setter(into))]
+    pub(crate) labels: HashMap<String, String>,
+    #[builder(default, setter(into))]
+    volumes: Vec<VolumeMount>,
+    #[builder(default, setter(into))]
+    mounts: Vec<Mount>,
+    /// Empty, writable directories to create in the container.
+    #[builder(default, setter(into))]
+    directories: Vec<PathBuf>,
+    #[builder(default, setter(into))]
+    ports: Vec<PortMapping>,
+    /// Give the container network access through slirp.
+    #[builder(default = "true")]
+    network: bool,
+    /// Start the command only once the network is up.
+    #[builder(default)]
+    wait_net: bool,
+    /// Store paths of the closure to make writable through an overlay.
+    #[builder(default, setter(into))]
+    rw_components: Vec<PathBuf>,
+    /// Patterns of store path names to leave out of the closure.
+    #[builder(default, setter(into))]
+    exclude_paths: Vec<String>,
+    /// Refuse to start the container if its closure is larger than this.
+    #[builder(default)]
+    max_size: Option<ByteSize>,
+    #[builder(default, setter(into))]
+    hosts: Vec<HostEntry>,
+    /// DNS servers written to the container’s `/etc/resolv.conf`.
+    #[builder(default, setter(into))]
+    nameservers: Vec<IpAddr>,
+    #[builder(default)]
+    read_only: bool,
+    /// Mount a minimal `/etc/passwd` and `/etc/group` unless volumes provide them.
+    #[builder(default = "true")]
+    passwd: bool,
+    /// Mount the whole Nix store instead of the closure.
+    #[builder(default)]
+    full_nix_store: bool,
+    #[builder(default)]
+    timezone: Timezone,
+    #[builder(default, setter(into))]
+    pub(crate) user: Option<UserSpec>,
+    /// Directory inside the container the entry point starts in.
+    #[builder(default, setter(into))]
+    workdir: Option<PathBuf>,
+    /// Supplementary group ids of the container process.
+    #[builder(default, setter(into))]
+    groups: Vec<u32>,
+    #[builder(default, setter(into))]
+    rlimits: Vec<Rlimit>,
+    #[builder(default)]
+    pids_limit: Option<u64>,
+    /// Custom id maps. They replace the default mapping for their kind of id only.
+    #[builder(default, setter(into))]
+    uid_maps: Vec<IdRangeMap>,
+    #[builder(default, setter(into))]
+    gid_maps: Vec<IdRangeMap>,
+    #[builder(default, setter(into))]
+    shared_namespaces: Vec<UnshareNamespaces>,
+    #[builder(default)]
+    dropped_capabilities: CapabilitySet,
+    /// Print the capabilities the entry point gets before it starts.
+    #[builder(default)]
+    print_capabilities: bool,
+    #[builder(default)]
+    seccomp: SeccompProfile,
+    #[builder(default = "true")]
+    no_new_privs: bool,
+    #[builder(default)]
+    init: bool,
+    #[builder(default)]
+    slirp_restart: bool,
+    /// Keep the network namespace and slirp when the container restarts.
+    #[builder(default)]
+    keep_network: bool,
+    #[builder(default, setter(into))]
+    log_file: Option<PathBuf>,
+    /// Tools to run on the host, like slirp. If unset, the default host tools are used.
+    #[builder(default, setter(into))]
+    host_tools: Option<HostTools>,
+    /// Nix options for building and evaluating the flake.
+    #[builder(default)]
+    refresh: bool,
+    #[builder(default)]
+    impure: bool,
+    #[builder(default)]
+    build_jobs: Option<u32>,
+    #[builder(default)]
+    build_cores: Option<u32>,
+    /// Arguments appended verbatim to `nix build`.
+    #[builder(default)]
+    nix_args: Vec<String>,
+}
+
+/// The flakes of a [`RunSpec`], built.
+#[derive(Debug)]
+pub(crate) struct BuiltFlakes {
+    /// The main flake, resolved to its output.
+    pub(crate) flake: ContainixFlake,
+    pub(crate) store_item: NixStoreItem,
+    pub(crate) with: Vec<NixStoreItem>,
+}
+
+impl BuiltFlakes {
+    /// The build results of all flakes, the main one first.
+    fn store_items(&self) -> impl Iterator<Item = &NixStoreItem> {
+        std::iter::once(&self.store_item).chain(&self.with)
+    }
+}
+
+impl RunSpec {
+    fn nix_options(&self, cmd: &mut NixBuild) {
+        cmd.refresh(self.refresh).impure(self.impure);
+    }
+
+    /// Builds the flakes. The main flake is returned resolved, so reading the entry point
+    /// doesn’t evaluate it again.
+    pub(crate) fn build_flakes(&self) -> Result<BuiltFlakes> {
+        let build = |flake: &ContainixFlake| {
+            flake.build(|cmd| {
+                self.nix_options(cmd);
+                cmd.max_jobs(self.build_jobs)
+                    .cores(self.build_cores)
+                    .extra_args(&self.nix_args);
+            })
+        };
+        info!("Building container {}", self.flake);
+        // Resolved once, so building and reading the entry point don’t both evaluate the flake.
+        let flake = self.flake.resolve().context("Resolving container flake")?;
+        let store_item = build(&flake).context("Building container flake")?;
+        let with = self
+            .with_flakes
+            .iter()
+            .map(|with| {
+                info!("Building {with}");
+                with.resolve()
+                    .and_then(|with| build(&with))
+                    .with_context(|| format!("Building flake {with}"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(BuiltFlakes {
+            flake,
+            store_item,
+            with,
+        })
+    }
+
+    /// Sets up the container filesystem for the merged closures of the flakes.
+    pub(crate) fn container_fs(&self, built: &BuiltFlakes) -> Result<ContainerFsBuilder> {
+        let mut closure = HashSet::new();
+        for store_item in built.store_items() {
+            closure.extend(
+                store_item
+                    .closure()
+                    .with_context(|| format!("Computing transitive closure of {store_item}"))?,
+            );
+        }
+        if !self.exclude_paths.is_empty() {
+            if self.full_nix_store {
+                bail!("Store paths can’t be excluded when mounting the full Nix store");
+            }
+            warn!("Excluding store paths from the closure, the container might not work");
+            closure.retain(|item| {
+                // The flakes’ own outputs are always kept.
+                let excluded = !built.store_items().any(|store_item| store_item == item)
+                    && self
+                        .exclude_paths
+                        .iter()
+                        .any(|pattern| item.name_matches(pattern));
+                if excluded {
+                    warn!("Excluding {}", item.path().display());
+                }
+                !excluded
+            });
+        }
+        if let Some(max_size) = self.max_size {
+            let size = ByteSize::new(store_size(&closure).context("Computing closure size")?);
+            debug!("Closure size: {size}");
+            if size > max_size {
+                bail!("The container’s closure is {size}, more than the allowed {max_size}");
+            }
+        }
+        events::emit(Event::ClosureComputed {
+            store_paths: closure.len(),
+        });
+        debug!(
+            "Dependency closure: {}",
+            closure
+                .iter()
+                .map(|c| c.name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        let mut container_fs = ContainerFsBuilder::default();
+        container_fs
+            .read_only(self.read_only)
+            .host_localtime(self.timezone == Timezone::Host)
+            .passwd(self.passwd)
+            .passwd_user(self.user.as_ref().and_then(UserSpec::ids));
+        if self.full_nix_store {
+            container_fs.volume(VolumeMount::read_only("/nix/store", "/nix/store"));
+        } else {
+            for component in &closure {
+                container_fs.nix_component(component.path());
+            }
+        }
+        for component in &self.rw_components {
+            if self.full_nix_store {
+                bail!("Store paths can’t be made writable when mounting the full Nix store");
+            }
+            let item = NixStoreItem::try_from(component.as_path())?;
+            container_fs.writable_component(item.path());
+        }
+        for volume in &self.volumes {
+            container_fs.volume(volume.clone());
+        }
+        for mount in &self.mounts {
+            container_fs.mount(mount.clone());
+        }
+        for directory in &self.directories {
+            container_fs.directory(directory);
+        }
+        for host in &self.hosts {
+            container_fs.host(host.clone());
+        }
+        for nameserver in &self.nameservers {
+            container_fs.nameserver(*nameserver);
+        }
+        Ok(container_fs)
+    }
+
+    /// Warns about writable volumes and bind mounts the container process won’t be able to
+    /// write to. Without custom id maps, it runs as the invoking user on the host, whatever
+    /// uid it has in the container, so it can only write where that user can. As root, it can
+    /// also write to anything that user owns. Checked on the host, before namespaces change
+    /// what access reports.
+    fn check_volume_access(&self) {
+        if !self.uid_maps.is_empty() {
+            return;
+        }
+        let host_uid = nix::unistd::getuid().as_raw();
+        let root = self
+            .user
+            .as_ref()
+            .is_none_or(|user| user.ids().is_some_and(|(uid, _)| uid == 0));
+        let bind_mounts = self.mounts.iter().filter_map(|mount| match mount {
+            Mount::Bind(volume) => Some(volume),
+            _ => None,
+        });
+        for volume in self
+            .volumes
+            .iter()
+            .chain(bind_mounts)
+            .filter(|v| !v.read_only && !v.copy)
+        {
+            // Missing paths fail with a better error when the volume is mounted.
+            let Ok(metadata) = std::fs::metadata(&volume.host_path) else {
+                continue;
+            };
+            if (root && metadata.uid() == host_uid)
+                || nix::unistd::access(&volume.host_path, AccessFlags::W_OK).is_ok()
+            {
+                continue;
+            }
+            let who = match &self.user {
+                Some(user) if !root => format!("user {user}"),
+                _ => "root".to_string(),
+            };
+            warn!(
+                "Volume {} is owned by uid {} and not writable by uid {host_uid}, which {who} in \
+                the container runs as on the host, so the container can’t write to it. Mount it \
+                with :ro or give uid {host_uid} write access.",
+                volume.host_path.display(),
+                metadata.uid()
+            );
+        }
+    }
+
+    /// The container’s environment, with each variable set once. From lowest to highest
+    /// precedence: `PATH` with the flakes’ binaries (unless disabled) and `TZ`, the flake’s
+    /// declared environment if requested, then the variables inherited from the host and the
+    /// explicitly set ones, in the order [`EnvPrecedence`] picks.
+    pub(crate) fn environment(&self, built: &BuiltFlakes) -> Result<Vec<EnvVariable>> {
+        let mut defaults = vec![];
+        if self.default_path {
+            let path = std::env::join_paths(
+                built
+                    .store_items()
+                    .map(|store_item| store_item.path().join("bin")),
+            )
+            .context("Joining PATH")?;
+            defaults.push(EnvVariable::new("PATH", path));
+        }
+        if let Timezone::Named(tz) = &self.timezone {
+            defaults.push(EnvVariable::new("TZ", tz));
+        }
+        let flake_env = if self.env_from_flake {
+            built
+                .flake
+                .env(|cmd| self.nix_options(cmd))
+                .context("Reading the flake’s environment")?
+        } else {
+            vec![]
+        };
+        Ok(self
+            .env_precedence
+            .merge(&defaults, &flake_env, &self.host_env, &self.env))
+    }
+
+    /// The command to run: the entry point if one is set, otherwise the main flake’s.
+    pub(crate) fn command(&self, built: &BuiltFlakes) -> Result<String> {
+        let entrypoint = match &self.entrypoint {
+            Some(entrypoint) => Some(entrypoint.clone()),
+            None => built
+                .flake
+                .entrypoint(|cmd| self.nix_options(cmd))
+                .context("Reading the flake’s entry point")?,
+        };
+        if let Some(entrypoint) = entrypoint {
+            return Ok(entrypoint);
+        }
+        if let Some(program) = self.default_app_program(built)? {
+            return Ok(program);
+        }
+
+        let bin = built.store_item.path().join("bin");
+        let name = package_name(built.store_item.name());
+        if let Some(cmd) = ["containix-entry-point", name]
+            .into_iter()
+            .map(|candidate| bin.join(candidate))
+            .find(|cmd| cmd.is_file())
+        {
+            let Some(cmd) = cmd.to_str() else {
+                bail!("Container flake name contains invalid utf-8");
+            };
+            return Ok(cmd.to_string());
+        }
+        if !bin.is_dir() {
+            bail!(
+                "The output of {} ({}) has no bin directory, so there is no entry point to run. \
+                Pass a command with --entrypoint, declare passthru.containix.entrypoint or a default \
+                app, or point the flake at a package that provides bin/containix-entry-point.",
+                built.flake,
+                built.store_item
+            );
+        }
+        let mut binaries = std::fs::read_dir(&bin)
+            .with_context(|| format!("Reading {}", bin.display()))?
+            .filter_map(|entry| Some(entry.ok()?.file_name().to_string_lossy().into_owned()))
+            .collect::<Vec<_>>();
+        binaries.sort();
+        bail!(
+            "The output of {} ({}) has neither bin/containix-entry-point nor bin/{name}. Pass a \
+            command with --entrypoint or declare passthru.containix.entrypoint or a default app. \
+            Its bin directory contains: {}",
+            built.flake,
+            built.store_item,
+            if binaries.is_empty() {
+                "nothing".to_string()
+            } else {
+                binaries.join(", ")
+            }
+        );
+    }
+
+    /// The program of the flake’s default app, like `nix run` would start it. Only consulted
+    /// if no output was picked, and only used if the program is part of the container. If it
+    /// can’t be read, the other entry points are tried instead.
+    fn default_app_program(&self, built: &BuiltFlakes) -> Result<Option<String>> {
+        if self.flake.output().is_some() {
+            return Ok(None);
+        }
+        let program = match self.flake.default_app_program(|cmd| self.nix_options(cmd)) {
+            Ok(Some(program)) => program,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                warn!("Could not read the flake’s default app, ignoring it: {e:#}");
+                return Ok(None);
+            }
+        };
+        let item: PathBuf = Path::new(&program).components().take(4).collect();
+        let item = NixStoreItem::try_from(item.as_path())
+            .with_context(|| format!("Default app program {program} is not in the Nix store"))?;
+        let mut available = self.full_nix_store && item.path().exists();
+        for store_item in built.store_items() {
+            if available {
+                break;
+            }
+            available = store_item
+                .closure()
+                .with_context(|| format!("Computing transitive closure of {store_item}"))?
+                .contains(&item);
+        }
+        if !available {
+            warn!("The default app’s program {program} isn’t part of the container, ignoring it");
+            return Ok(None);
+        }
+        Ok(Some(program))
+    }
+}
+
+/// The name of a package without its version. Like `builtins.parseDrvName`, the version
+/// starts at the first dash that isn’t followed by a letter.
+fn package_name(name: &str) -> &str {
+    name.char_indices()
+        .find(|&(index, c)| {
+            c == '-' && !name[index + 1..].starts_with(|c: char| c.is_ascii_alphabetic())
+        })
+        .map_or(name, |(index, _)| &name[..index])
+}
+
+/// What a container started from a [`RunSpec`] would run.
+#[derive(Debug)]
+pub struct LaunchConfig {
+    pub command: String,
+    /// The full argument vector, starting with `argv[0]`.
+    pub argv: Vec<String>,
+    pub env: Vec<EnvVariable>,
+}
+
+/// Builds the flakes and resolves the command, arguments and environment of a container,
+/// without entering any namespaces.
+#[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+pub fn launch_config(spec: &RunSpec) -> Result<LaunchConfig> {
+    let built = spec.build_flakes()?;
+    let command = spec.command(&built)?;
+    let env = spec.environment(&built)?;
+    let argv = std::iter::once(spec.argv0.clone().unwrap_or_else(|| command.clone()))
+        .chain(spec.args.iter().cloned())
+        .collect();
+    Ok(LaunchConfig { command, argv, env })
+}
+
+/// Builds the flake, assembles the container filesystem from its closure and starts it.
+///
+/// Like the CLI, this enters a new user and mount namespace in the calling process first.
+/// The host tools have to be passed in the spec or set up with
+/// [`setup_host_tools`](crate::host_tools::setup_host_tools).
+#[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+pub fn run_container(spec: RunSpec) -> Result<ContainerGuard<NixUnistdChild, SlirpHandle>> {
+    events::emit(Event::Building {
+        flake: spec.flake.to_string(),
+    });
+    let built = timings::measure("Building flakes", || spec.build_flakes())?;
+    let container_fs = timings::measure("Computing closure", || spec.container_fs(&built))?;
+    let seccomp = spec
+        .seccomp
+        .compile(spec.dropped_capabilities)
+        .context("Compiling seccomp profile")?;
+    let cmd = timings::measure("Reading entry point", || spec.command(&built))?;
+    let env = spec.environment(&built)?;
+
+    spec.check_volume_access();
+
+    // Custom maps replace the default mapping for their kind of id only.
+    let (uid_maps, gid_maps) = if spec.uid_maps.is_empty() && spec.gid_maps.is_empty() {
+        (vec![], vec![])
+    } else {
+        (
+            non_empty_or(
+                spec.uid_maps,
+                IdRangeMap::new(0, nix::unistd::getuid().as_raw(), 1),
+            ),
+            non_empty_or(
+                spec.gid_maps,
+                IdRangeMap::new(0, nix::unistd::getgid().as_raw(), 1),
+            ),
+        )
+    };
+    timings::measure("Entering namespaces", || {
+        enter_root_ns(&uid_maps, &gid_maps)
+    })?;
+    let container_fs = container_fs.build().context("Building container fs")?;
+    info!("Container root: {}", container_fs.as_ref().display());
+    events::emit(Event::FsAssembled {
+        root: container_fs.as_ref().to_path_buf(),
+    });
+
+    ContainerBuilder::default()
+        .root(container_fs)
+        .uid_maps(uid_maps)
+        .gid_maps(gid_maps)
+        .share_namespaces(spec.shared_namespaces)
+        .user(spec.user)
+        .workdir(spec.workdir)
+        .groups(spec.groups)
+        .rlimits(spec.rlimits)
+        .pids_limit(spec.pids_limit)
+        .ports(spec.ports)
+        .network(spec.network)
+        .wait_net(spec.wait_net)
+        .slirp_restart(spec.slirp_restart)
+        .keep_network(spec.keep_network)
+        .dropped_capabilities(spec.dropped_capabilities)
+        .print_capabilities(spec.print_capabilities)
+        .no_new_privs(spec.no_new_privs)
+        .init(spec.init)
+        .seccomp(seccomp)
+        .log_file(spec.log_file)
+        .host_tools(spec.host_tools)
+        .envs(env)
+        .command(cmd)
+        .argv0(spec.argv0)
+        .args(spec.args)
+        .spawn()
+        .context("Spawning container")
+}
+
+fn non_empty_or<T>(items: Vec<T>, default: T) -> Vec<T> {
+    if items.is_empty() {
+        vec![default]
+    } else {
+        items
+    }
+}
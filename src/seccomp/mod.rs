@@ -0,0 +1,434 @@
+use std::{collections::HashMap, fmt, path::PathBuf, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use nix::{
+    errno::Errno,
+    libc::{self, sock_filter},
+};
+use serde::{de::IgnoredAny, Deserialize, Deserializer, Serialize, Serializer};
+use tracing::{instrument, warn, Level};
+
+use crate::capabilities::{Capability, CapabilitySet};
+
+mod syscalls;
+
+pub use syscalls::syscall_number;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xC000_003E;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+/// Syscalls on x86_64 with this bit set use the x32 ABI, which would bypass the filter.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Offsets into `struct seccomp_data`.
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+/// Syscalls the default profile always blocks because they are not namespaced or are
+/// obsolete, following Docker’s default profile.
+///
+/// Unlike Docker’s, the default profile is a denylist: everything not listed here or gated
+/// by a dropped capability is allowed, including syscalls newer than this list.
+static DEFAULT_BLOCKED: &[&str] = &[
+    "add_key",
+    "keyctl",
+    "request_key",
+    "kexec_load",
+    "kexec_file_load",
+    "create_module",
+    "get_kernel_syms",
+    "query_module",
+    "nfsservctl",
+    "uselib",
+    "ustat",
+    "sysfs",
+    "_sysctl",
+    "swapon",
+    "swapoff",
+    "userfaultfd",
+    "clock_adjtime",
+    "lookup_dcookie",
+];
+
+/// Syscalls the default profile only allows if the container keeps the given capability.
+static DEFAULT_CAPABILITY_GATED: &[(&str, &[&str])] = &[
+    (
+        "CAP_SYS_ADMIN",
+        &[
+            "bpf",
+            "fanotify_init",
+            "mount",
+            "name_to_handle_at",
+            "perf_event_open",
+            "quotactl",
+            "setdomainname",
+            "sethostname",
+            "setns",
+            "umount2",
+            "unshare",
+        ],
+    ),
+    ("CAP_DAC_READ_SEARCH", &["open_by_handle_at"]),
+    ("CAP_SYS_BOOT", &["reboot"]),
+    ("CAP_SYS_CHROOT", &["chroot"]),
+    (
+        "CAP_SYS_MODULE",
+        &["init_module", "finit_module", "delete_module"],
+    ),
+    ("CAP_SYS_PACCT", &["acct"]),
+    (
+        "CAP_SYS_PTRACE",
+        &["kcmp", "process_vm_readv", "process_vm_writev", "ptrace"],
+    ),
+    ("CAP_SYS_RAWIO", &["iopl", "ioperm"]),
+    ("CAP_SYS_TIME", &["settimeofday", "clock_settime"]),
+    ("CAP_SYS_TTY_CONFIG", &["vhangup"]),
+    ("CAP_SYS_NICE", &["get_mempolicy", "mbind", "set_mempolicy"]),
+    ("CAP_SYSLOG", &["syslog"]),
+];
+
+/// Which seccomp filter to apply to the container.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SeccompProfile {
+    /// Blocks syscalls that are dangerous in a container, similar to Docker’s default profile,
+    /// but as a denylist rather than an allowlist.
+    #[default]
+    Default,
+    /// No filter at all.
+    Unconfined,
+    /// A JSON profile in (a subset of) Docker’s seccomp profile format. Profiles using parts
+    /// of the format containix doesn’t support, like argument conditions, are rejected.
+    File(PathBuf),
+}
+
+impl FromStr for SeccompProfile {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "default" => SeccompProfile::Default,
+            "unconfined" => SeccompProfile::Unconfined,
+            path => SeccompProfile::File(path.into()),
+        })
+    }
+}
+
+impl fmt::Display for SeccompProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SeccompProfile::Default => write!(f, "default"),
+            SeccompProfile::Unconfined => write!(f, "unconfined"),
+            SeccompProfile::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl SeccompProfile {
+    /// Compiles the profile into a BPF filter. Capabilities that will be dropped from the
+    /// container additionally block the syscalls they guard in the default profile.
+    #[instrument(level = "trace", skip(dropped_capabilities), err(level = Level::TRACE))]
+    pub fn compile(&self, dropped_capabilities: CapabilitySet) -> Result<Option<SeccompFilter>> {
+        let profile = match self {
+            SeccompProfile::Unconfined => return Ok(None),
+            SeccompProfile::Default => default_profile(dropped_capabilities),
+            SeccompProfile::File(path) => {
+                let file = std::fs::read_to_string(path)
+                    .with_context(|| format!("Reading seccomp profile {}", path.display()))?;
+                serde_json::from_str(&file)
+                    .with_context(|| format!("Parsing seccomp profile {}", path.display()))?
+            }
+        };
+        profile.compile().map(Some)
+    }
+}
+
+fn default_profile(dropped_capabilities: CapabilitySet) -> ProfileFile {
+    let mut names: Vec<String> = DEFAULT_BLOCKED.iter().map(|v| v.to_string()).collect();
+    for (cap, syscalls) in DEFAULT_CAPABILITY_GATED {
+        let cap: Capability = cap.parse().expect("Capability table contains invalid name");
+        if dropped_capabilities.contains(cap) {
+            names.extend(syscalls.iter().map(|v| v.to_string()));
+        }
+    }
+    ProfileFile {
+        default_action: Action::Allow,
+        default_errno_ret: None,
+        _architectures: IgnoredAny,
+        _arch_map: IgnoredAny,
+        syscalls: vec![SyscallRule {
+            names,
+            action: Action::Errno,
+            errno_ret: None,
+            args: vec![],
+            includes: Default::default(),
+            excludes: Default::default(),
+            _comment: IgnoredAny,
+        }],
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+enum Action {
+    #[serde(rename = "SCMP_ACT_ALLOW")]
+    Allow,
+    #[serde(rename = "SCMP_ACT_ERRNO")]
+    Errno,
+    #[serde(rename = "SCMP_ACT_KILL", alias = "SCMP_ACT_KILL_THREAD")]
+    KillThread,
+    #[serde(rename = "SCMP_ACT_KILL_PROCESS")]
+    KillProcess,
+    #[serde(rename = "SCMP_ACT_TRAP")]
+    Trap,
+    #[serde(rename = "SCMP_ACT_LOG")]
+    Log,
+}
+
+impl Action {
+    fn ret(&self, errno: Option<u32>) -> u32 {
+        match self {
+            Action::Allow => libc::SECCOMP_RET_ALLOW,
+            Action::Errno => {
+                libc::SECCOMP_RET_ERRNO
+                    | (errno.unwrap_or(libc::EPERM as u32) & libc::SECCOMP_RET_DATA)
+            }
+            Action::KillThread => libc::SECCOMP_RET_KILL_THREAD,
+            Action::KillProcess => libc::SECCOMP_RET_KILL_PROCESS,
+            Action::Trap => libc::SECCOMP_RET_TRAP,
+            Action::Log => libc::SECCOMP_RET_LOG,
+        }
+    }
+}
+
+/// Fields the filter doesn’t implement are either known to be harmless to ignore or make
+/// the profile fail to load, so a profile is never enforced more loosely than written.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct ProfileFile {
+    default_action: Action,
+    default_errno_ret: Option<u32>,
+    /// Ignored, as the filter kills any syscall not made with the native architecture.
+    #[serde(default, rename = "architectures")]
+    _architectures: IgnoredAny,
+    #[serde(default, rename = "archMap")]
+    _arch_map: IgnoredAny,
+    #[serde(default)]
+    syscalls: Vec<SyscallRule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct SyscallRule {
+    names: Vec<String>,
+    action: Action,
+    errno_ret: Option<u32>,
+    /// Argument conditions, which aren’t supported.
+    #[serde(default)]
+    args: Vec<serde_json::Value>,
+    /// Conditions on the architecture, capabilities or kernel, which aren’t supported.
+    #[serde(default)]
+    includes: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    excludes: serde_json::Map<String, serde_json::Value>,
+    #[serde(default, rename = "comment")]
+    _comment: IgnoredAny,
+}
+
+impl ProfileFile {
+    fn compile(&self) -> Result<SeccompFilter> {
+        if cfg!(not(any(target_arch = "x86_64", target_arch = "aarch64"))) {
+            bail!("Seccomp filters are only supported on x86_64 and aarch64");
+        }
+
+        // Later rules for the same syscall override earlier ones.
+        let mut actions = HashMap::new();
+        for rule in &self.syscalls {
+            if !rule.args.is_empty() || !rule.includes.is_empty() || !rule.excludes.is_empty() {
+                bail!(
+                    "The seccomp rule for {} has conditions (args, includes or excludes), which \
+                    containix doesn’t support",
+                    rule.names.join(", ")
+                );
+            }
+            for name in &rule.names {
+                let Some(nr) = syscall_number(name) else {
+                    warn!("Ignoring unknown syscall `{name}` in seccomp profile");
+                    continue;
+                };
+                actions.insert(nr, rule.action.ret(rule.errno_ret));
+            }
+        }
+        let default = self.default_action.ret(self.default_errno_ret);
+        let mut actions: Vec<_> = actions
+            .into_iter()
+            .filter(|(_, action)| *action != default)
+            .collect();
+        actions.sort();
+
+        let mut program = vec![
+            stmt(
+                libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+                SECCOMP_DATA_ARCH,
+            ),
+            jump(libc::BPF_JEQ, AUDIT_ARCH, 1, 0),
+            stmt(libc::BPF_RET, libc::SECCOMP_RET_KILL_PROCESS),
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_NR),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.extend([
+            jump(libc::BPF_JGE, X32_SYSCALL_BIT, 0, 1),
+            stmt(libc::BPF_RET, libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32),
+        ]);
+        for (nr, action) in actions {
+            program.push(jump(libc::BPF_JEQ, nr as u32, 0, 1));
+            program.push(stmt(libc::BPF_RET, action));
+        }
+        program.push(stmt(libc::BPF_RET, default));
+
+        if program.len() > libc::BPF_MAXINSNS as usize {
+            bail!(
+                "Seccomp profile is too large ({} instructions)",
+                program.len()
+            );
+        }
+        Ok(SeccompFilter(program))
+    }
+}
+
+fn stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter {
+        code: (code | libc::BPF_K) as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(op: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: (libc::BPF_JMP | op | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// A compiled seccomp BPF program.
+#[derive(Clone)]
+pub struct SeccompFilter(Vec<sock_filter>);
+
+impl fmt::Debug for SeccompFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SeccompFilter({} instructions)", self.0.len())
+    }
+}
+
+/// Serialized as `(code, jt, jf, k)` tuples, to pass the filter to another process.
+impl Serialize for SeccompFilter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(|i| (i.code, i.jt, i.jf, i.k)))
+    }
+}
+
+impl<'de> Deserialize<'de> for SeccompFilter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let instructions = Vec::<(u16, u8, u8, u32)>::deserialize(deserializer)?;
+        Ok(Self(
+            instructions
+                .into_iter()
+                .map(|(code, jt, jf, k)| sock_filter { code, jt, jf, k })
+                .collect(),
+        ))
+    }
+}
+
+impl SeccompFilter {
+    /// Installs the filter for the calling thread and all its future children.
+    ///
+    /// The kernel only allows this if `PR_SET_NO_NEW_PRIVS` is set or the caller has
+    /// `CAP_SYS_ADMIN` in its user namespace.
+    pub fn install(&self) -> nix::Result<()> {
+        let program = libc::sock_fprog {
+            len: self.0.len() as u16,
+            filter: self.0.as_ptr() as *mut sock_filter,
+        };
+        // SAFETY: `program` points to a valid BPF program that outlives the call.
+        let res = unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER as libc::c_ulong,
+                &program as *const libc::sock_fprog,
+            )
+        };
+        Errno::result(res)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(profile: &str) -> Result<SeccompFilter> {
+        serde_json::from_str::<ProfileFile>(profile)?.compile()
+    }
+
+    #[test]
+    fn accepts_supported_profiles() {
+        let cases = [
+            r#"{"defaultAction": "SCMP_ACT_ALLOW"}"#,
+            r#"{"defaultAction": "SCMP_ACT_ERRNO", "defaultErrnoRet": 1,
+                "architectures": ["SCMP_ARCH_X86_64"],
+                "syscalls": [{"names": ["read", "write"], "action": "SCMP_ACT_ALLOW"}]}"#,
+            r#"{"defaultAction": "SCMP_ACT_ALLOW", "archMap": [],
+                "syscalls": [{"names": ["ptrace"], "action": "SCMP_ACT_ERRNO", "errnoRet": 1,
+                    "args": [], "includes": {}, "excludes": {}, "comment": "no tracing"}]}"#,
+        ];
+        for profile in cases {
+            compile(profile).unwrap_or_else(|e| panic!("{profile}: {e:#}"));
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_profiles() {
+        let cases = [
+            (
+                r#"{"defaultAction": "SCMP_ACT_ERRNO", "syscalls": [{"names": ["personality"],
+                    "action": "SCMP_ACT_ALLOW", "args": [{"index": 0, "value": 0, "op": "SCMP_CMP_EQ"}]}]}"#,
+                "has conditions",
+            ),
+            (
+                r#"{"defaultAction": "SCMP_ACT_ERRNO", "syscalls": [{"names": ["mount"],
+                    "action": "SCMP_ACT_ALLOW", "includes": {"caps": ["CAP_SYS_ADMIN"]}}]}"#,
+                "has conditions",
+            ),
+            (
+                r#"{"defaultAction": "SCMP_ACT_ERRNO", "syscalls": [{"names": ["clone"],
+                    "action": "SCMP_ACT_ALLOW", "excludes": {"arches": ["s390x"]}}]}"#,
+                "has conditions",
+            ),
+            (
+                r#"{"defaultAction": "SCMP_ACT_ERRNO", "listenerPath": "/run/seccomp.sock"}"#,
+                "unknown field `listenerPath`",
+            ),
+            (
+                r#"{"defaultAction": "SCMP_ACT_ERRNO", "syscalls": [{"name": "read",
+                    "action": "SCMP_ACT_ALLOW"}]}"#,
+                "unknown field `name`",
+            ),
+        ];
+        for (profile, expected) in cases {
+            let err = format!("{:#}", compile(profile).unwrap_err());
+            assert!(err.contains(expected), "{profile}: {err}");
+        }
+    }
+    #[test]
+    fn filter_survives_serialization() {
+        let filter = default_profile(CapabilitySet::default()).compile().unwrap();
+        let json = serde_json::to_string(&filter).unwrap();
+        let restored: SeccompFilter = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.0.len(), filter.0.len());
+        assert_eq!(serde_json::to_string(&restored).unwrap(), json);
+    }
+}
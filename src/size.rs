@@ -0,0 +1,69 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use derive_more::derive::Deref;
+
+/// A size given on the command line, like `512M`, `2G` or `1.5GiB`.
+///
+/// Units are powers of 1024, with or without a trailing `B` or `iB`. A bare number is
+/// interpreted as bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deref)]
+pub struct ByteSize(u64);
+
+impl ByteSize {
+    pub fn new(bytes: u64) -> Self {
+        ByteSize(bytes)
+    }
+}
+
+impl From<ByteSize> for u64 {
+    fn from(value: ByteSize) -> Self {
+        value.0
+    }
+}
+
+const UNITS: &[&str] = &["K", "M", "G", "T"];
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let split = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let (value, unit) = s.split_at(split);
+        let value: f64 = value
+            .parse()
+            .with_context(|| format!("Invalid size: {s}"))?;
+        let unit = unit.trim().to_ascii_uppercase();
+        let unit = unit
+            .strip_suffix("IB")
+            .or_else(|| unit.strip_suffix('B'))
+            .unwrap_or(&unit);
+        let exponent = match unit {
+            "" => 0,
+            unit => match UNITS.iter().position(|u| *u == unit) {
+                Some(index) => index as i32 + 1,
+                None => bail!("Invalid size unit in {s}, expected one of B, K, M, G, T"),
+            },
+        };
+        Ok(ByteSize((value * 1024f64.powi(exponent)).round() as u64))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut value = self.0 as f64;
+        let mut unit = None;
+        for next in UNITS {
+            if value < 1024.0 {
+                break;
+            }
+            value /= 1024.0;
+            unit = Some(next);
+        }
+        match unit {
+            Some(unit) => write!(f, "{value:.1} {unit}iB"),
+            None => write!(f, "{} B", self.0),
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::{
+    io::{IsTerminal, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+const FRAMES: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// Shows a spinner with the elapsed time on stderr until dropped.
+///
+/// Does nothing if stderr isn’t a terminal.
+#[derive(Debug)]
+pub struct Spinner {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Spinner {
+    pub fn start(phase: impl Into<String>) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        if !std::io::stderr().is_terminal() {
+            return Spinner { stop, thread: None };
+        }
+
+        let phase = phase.into();
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                let start = Instant::now();
+                for frame in FRAMES.iter().cycle() {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let mut stderr = std::io::stderr().lock();
+                    _ = write!(
+                        stderr,
+                        "\r\x1b[2K{frame} {phase} ({}s)",
+                        start.elapsed().as_secs()
+                    );
+                    _ = stderr.flush();
+                    drop(stderr);
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                _ = write!(std::io::stderr(), "\r\x1b[2K");
+            }
+        });
+        Spinner {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
+        }
+    }
+}
@@ -3,9 +3,13 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use tracing::{error, instrument};
 
+/// File in a tempdir holding the PID of the process that created it, so leftovers of crashed
+/// processes can be told apart from tempdirs that are still in use.
+pub const OWNER_FILE: &str = "containix.pid";
+
 #[derive(Debug)]
 pub struct TempDir(PathBuf);
 
@@ -31,6 +35,15 @@ impl TempDir {
         let name = uuid::Uuid::new_v4().to_string();
         Self::with_name(Some(prefix), name)
     }
+
+    /// Creates the directory and records the current process as its owner.
+    pub fn create(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.0)
+            .with_context(|| format!("Creating tempdir {}", self.0.display()))?;
+        std::fs::write(self.0.join(OWNER_FILE), std::process::id().to_string())
+            .with_context(|| format!("Recording owner of tempdir {}", self.0.display()))?;
+        Ok(())
+    }
 }
 
 impl Deref for TempDir {
@@ -0,0 +1,27 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+
+/// Which timezone the container should see.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Timezone {
+    /// Bind-mount the host’s `/etc/localtime`.
+    Host,
+    /// Set `TZ` to the given timezone, e.g. `Europe/London`.
+    Named(String),
+    /// Leave the container without timezone information, which usually means UTC.
+    #[default]
+    None,
+}
+
+impl FromStr for Timezone {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "" => bail!("Timezone must not be empty"),
+            "host" => Ok(Timezone::Host),
+            "none" => Ok(Timezone::None),
+            tz => Ok(Timezone::Named(tz.to_string())),
+        }
+    }
+}
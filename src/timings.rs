@@ -0,0 +1,49 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::debug;
+
+/// The phases recorded since [`collect`], or `None` if they aren’t being collected.
+static PHASES: Mutex<Option<Vec<(String, Duration)>>> = Mutex::new(None);
+
+/// Runs `f` and records how long it took as `phase`.
+pub fn measure<T>(phase: &str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    record(phase, start.elapsed());
+    result
+}
+
+/// Records how long a phase of starting a container took. Phases are logged at debug level,
+/// and collected for [`summary`] between [`collect`] and `summary`.
+pub fn record(phase: impl Into<String>, duration: Duration) {
+    let phase = phase.into();
+    debug!("{phase} took {duration:.2?}");
+    if let Some(phases) = PHASES.lock().expect("Timings lock poisoned").as_mut() {
+        phases.push((phase, duration));
+    }
+}
+
+/// Starts collecting phases for [`summary`], dropping any collected before.
+pub fn collect() {
+    *PHASES.lock().expect("Timings lock poisoned") = Some(Vec::new());
+}
+
+/// The phases recorded since [`collect`] in the order they finished, followed by their total.
+/// Collecting stops, so later phases, like those of restarts, are only logged.
+pub fn summary() -> String {
+    let phases = PHASES
+        .lock()
+        .expect("Timings lock poisoned")
+        .take()
+        .unwrap_or_default();
+    let total: Duration = phases.iter().map(|(_, duration)| *duration).sum();
+    phases
+        .iter()
+        .map(|(phase, duration)| format!("{phase}: {duration:.2?}"))
+        .chain(std::iter::once(format!("total: {total:.2?}")))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
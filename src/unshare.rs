@@ -1,18 +1,36 @@
 use std::{
+    ffi::CString,
+    fs::File,
     io::Write,
+    os::{
+        fd::{AsRawFd, OwnedFd, RawFd},
+        unix::ffi::OsStrExt,
+    },
     path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use derive_builder::Builder;
 use derive_more::derive::{Deref, DerefMut};
-use nix::sched::CloneFlags;
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    sched::CloneFlags,
+    sys::wait::WaitStatus,
+    unistd::{ForkResult, Pid},
+};
+use serde::{Deserialize, Serialize};
 use tracing::{error, instrument, Level};
 
-use crate::command::{ChildProcess, NixUnistdChild};
+use crate::{
+    command::{resolve_command, NixUnistdChild},
+    error::ContainixError,
+};
 
 #[allow(dead_code)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum UnshareNamespaces {
     /// Mounting and unmounting filesystems will not affect the rest of the system.
     Mount,
@@ -47,7 +65,203 @@ impl From<UnshareNamespaces> for nix::sched::CloneFlags {
     }
 }
 
-#[derive(Debug, Clone)]
+impl UnshareNamespaces {
+    /// Name of the namespace’s entry in `/proc/<pid>/ns`.
+    pub fn proc_name(&self) -> &'static str {
+        match self {
+            UnshareNamespaces::Mount => "mnt",
+            UnshareNamespaces::Uts => "uts",
+            UnshareNamespaces::Ipc => "ipc",
+            UnshareNamespaces::Network => "net",
+            UnshareNamespaces::Pid => "pid",
+            UnshareNamespaces::Cgroup => "cgroup",
+            UnshareNamespaces::User => "user",
+            UnshareNamespaces::Time => "time",
+        }
+    }
+}
+
+impl FromStr for UnshareNamespaces {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mnt" | "mount" => Ok(UnshareNamespaces::Mount),
+            "uts" => Ok(UnshareNamespaces::Uts),
+            "ipc" => Ok(UnshareNamespaces::Ipc),
+            "net" | "network" => Ok(UnshareNamespaces::Network),
+            "pid" => Ok(UnshareNamespaces::Pid),
+            "cgroup" => Ok(UnshareNamespaces::Cgroup),
+            "user" => Ok(UnshareNamespaces::User),
+            "time" => Ok(UnshareNamespaces::Time),
+            _ => bail!("Unknown namespace {s}"),
+        }
+    }
+}
+
+/// The namespaces (and root directory) of a running process.
+///
+/// Used to run additional processes inside an existing container.
+#[derive(Debug)]
+pub struct ProcessNamespaces {
+    namespaces: Vec<File>,
+    root: Option<File>,
+}
+
+impl ProcessNamespaces {
+    /// Opens the given namespaces of `pid` and, with `enter_root`, its root directory. The user
+    /// namespace, if requested, is always entered first as it grants the privileges to enter the others.
+    #[instrument(level = "trace", skip(namespaces), err(level = Level::TRACE))]
+    pub fn open(
+        pid: u32,
+        namespaces: impl IntoIterator<Item = UnshareNamespaces>,
+        enter_root: bool,
+    ) -> Result<Self> {
+        let namespaces = open_namespaces(namespaces.into_iter().map(|ns| {
+            (
+                ns,
+                PathBuf::from(format!("/proc/{pid}/ns/{}", ns.proc_name())),
+            )
+        }))?;
+        // The process might have pivoted into its root, so it is only reachable through /proc.
+        let root = enter_root
+            .then(|| {
+                let path = format!("/proc/{pid}/root");
+                File::open(&path).with_context(|| format!("Opening {path}"))
+            })
+            .transpose()?;
+        Ok(Self { namespaces, root })
+    }
+
+    /// Moves the calling process into the namespaces and root directory.
+    ///
+    /// Entering a user namespace is only allowed for single-threaded processes, so this is meant
+    /// to be called in a freshly forked child (e.g. via `CommandExt::pre_exec`). It doesn’t allocate.
+    pub fn enter(&self) -> nix::Result<()> {
+        for ns in &self.namespaces {
+            nix::sched::setns(ns, CloneFlags::empty())?;
+        }
+        if let Some(root) = &self.root {
+            nix::unistd::fchdir(root.as_raw_fd())?;
+            nix::unistd::chroot(".")?;
+            nix::unistd::chdir("/")?;
+        }
+        Ok(())
+    }
+}
+
+/// Forks, so the program `pre_exec` is about to execute runs in the PID namespace entered with
+/// `setns`, which only applies to children of the caller. Like `nsenter`, the child returns and
+/// the parent waits for it and exits with its exit status.
+///
+/// Like [`ProcessNamespaces::enter`], it only makes async-signal-safe calls.
+pub fn fork_into_pid_namespace() -> nix::Result<()> {
+    // SAFETY: both sides only make async-signal-safe calls.
+    let child = match unsafe { nix::unistd::fork() }? {
+        ForkResult::Child => return Ok(()),
+        ForkResult::Parent { child } => child,
+    };
+    // `Command::spawn` waits for the pipe it uses to report exec failures to close, which the
+    // parent would otherwise hold open until the program exits. Stdio is set up already.
+    // SAFETY: only closes descriptors, the parent doesn’t use any of them.
+    if unsafe { nix::libc::syscall(nix::libc::SYS_close_range, 3, u32::MAX, 0) } != 0 {
+        for fd in 3..1024 {
+            _ = nix::unistd::close(fd);
+        }
+    }
+    let code = loop {
+        match nix::sys::wait::waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, code)) => break code,
+            Ok(WaitStatus::Signaled(_, signal, _)) => break 128 + signal as i32,
+            Ok(_) | Err(Errno::EINTR) => continue,
+            Err(_) => break 1,
+        }
+    };
+    // SAFETY: `_exit` is always safe to call.
+    unsafe { nix::libc::_exit(code) }
+}
+
+/// Opens namespace files, the user namespace first.
+fn open_namespaces(
+    namespaces: impl IntoIterator<Item = (UnshareNamespaces, PathBuf)>,
+) -> Result<Vec<File>> {
+    let mut namespaces: Vec<_> = namespaces.into_iter().collect();
+    namespaces.sort_by_key(|(ns, _)| !matches!(ns, UnshareNamespaces::User));
+    namespaces
+        .into_iter()
+        .map(|(_, path)| File::open(&path).with_context(|| format!("Opening {}", path.display())))
+        .collect()
+}
+
+/// Namespaces of a process, bind-mounted onto files in a directory so they outlive it.
+/// Processes started later can join them, e.g. to keep a container’s network when it restarts.
+#[derive(Debug)]
+pub struct PersistentNamespaces {
+    dir: PathBuf,
+    namespaces: Vec<UnshareNamespaces>,
+}
+
+impl PersistentNamespaces {
+    /// Bind-mounts the `namespaces` of `pid` onto files in `dir`.
+    #[instrument(level = "trace", skip(namespaces), err(level = Level::TRACE))]
+    pub fn persist(
+        pid: u32,
+        namespaces: impl IntoIterator<Item = UnshareNamespaces>,
+        dir: &Path,
+    ) -> Result<Self> {
+        use nix::mount::{mount, MsFlags};
+
+        std::fs::create_dir_all(dir).with_context(|| format!("Creating {}", dir.display()))?;
+        let mut persistent = Self {
+            dir: dir.to_path_buf(),
+            namespaces: vec![],
+        };
+        for ns in namespaces {
+            let source = PathBuf::from(format!("/proc/{pid}/ns/{}", ns.proc_name()));
+            let target = persistent.path(ns);
+            File::create(&target).with_context(|| format!("Creating {}", target.display()))?;
+            mount(
+                Some(&source),
+                &target,
+                None::<&str>,
+                MsFlags::MS_BIND,
+                None::<&str>,
+            )
+            .with_context(|| format!("Bind-mounting {}", source.display()))?;
+            // Only what was mounted is unmounted again.
+            persistent.namespaces.push(ns);
+        }
+        Ok(persistent)
+    }
+
+    /// The file the namespace is bind-mounted onto.
+    pub fn path(&self, ns: UnshareNamespaces) -> PathBuf {
+        self.dir.join(ns.proc_name())
+    }
+
+    /// Opens the namespaces, so a process can join them with [`ProcessNamespaces::enter`].
+    pub fn open(&self) -> Result<ProcessNamespaces> {
+        let namespaces = open_namespaces(self.namespaces.iter().map(|ns| (*ns, self.path(*ns))))?;
+        Ok(ProcessNamespaces {
+            namespaces,
+            root: None,
+        })
+    }
+}
+
+impl Drop for PersistentNamespaces {
+    fn drop(&mut self) {
+        for ns in &self.namespaces {
+            let path = self.path(*ns);
+            if let Err(e) = nix::mount::umount2(&path, nix::mount::MntFlags::MNT_DETACH) {
+                error!("Failed to unmount {}: {e}", path.display());
+                continue;
+            }
+            _ = std::fs::remove_file(&path);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IdRangeMap {
     outer_id_start: u32,
     inner_id_start: u32,
@@ -55,6 +269,26 @@ pub struct IdRangeMap {
 }
 
 impl IdRangeMap {
+    pub fn new(inner_id_start: u32, outer_id_start: u32, count: u32) -> Self {
+        Self {
+            outer_id_start,
+            inner_id_start,
+            count,
+        }
+    }
+
+    /// Maps the inner ids of this range onto themselves, e.g. to give a nested namespace
+    /// the same ids as this one.
+    pub fn identity(&self) -> Self {
+        Self::new(self.inner_id_start, self.inner_id_start, self.count)
+    }
+
+    /// Whether `id` is one of the ids this range maps to inside the namespace.
+    pub fn maps_inner(&self, id: u32) -> bool {
+        id >= self.inner_id_start
+            && u64::from(id) < u64::from(self.inner_id_start) + u64::from(self.count)
+    }
+
     pub fn serialize(&self) -> String {
         format!(
             "{} {} {}",
@@ -63,6 +297,24 @@ impl IdRangeMap {
     }
 }
 
+impl FromStr for IdRangeMap {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let &[inner, outer, count] = s.split(':').collect::<Vec<_>>().as_slice() else {
+            bail!("Invalid id map {s}, expected INNER:OUTER:COUNT");
+        };
+        let parse = |id: &str| {
+            id.parse::<u32>()
+                .with_context(|| format!("Invalid id `{id}` in id map {s}"))
+        };
+        let map = IdRangeMap::new(parse(inner)?, parse(outer)?, parse(count)?);
+        if map.count == 0 {
+            bail!("Id map {s} is empty");
+        }
+        Ok(map)
+    }
+}
+
 #[derive(Debug, Clone, Default, Deref, DerefMut)]
 pub struct IdRanges(Vec<IdRangeMap>);
 
@@ -72,6 +324,8 @@ impl IdRanges {
         Ok(())
     }
 
+    /// Serializes the ranges in the format of `/proc/<pid>/uid_map`: one range per line,
+    /// without a trailing newline.
     pub fn serialize(&self) -> String {
         self.0
             .iter()
@@ -90,8 +344,19 @@ pub struct UnshareEnvironment {
     uid_maps: IdRanges,
     #[builder(default, setter(custom, name = "gid_map"))]
     gid_maps: IdRanges,
+    /// Leave `setgroups` allowed in the namespace, so supplementary groups can be set. The
+    /// gid map then has to be written by a process with `CAP_SETGID` in the parent namespace.
+    #[builder(default)]
+    allow_setgroups: bool,
     #[builder(default, setter(strip_option, into))]
     root: Option<PathBuf>,
+    /// Existing namespaces to join once the root is entered, instead of creating them. The
+    /// new namespaces are created by the parent’s user namespace, so they are set up first.
+    #[builder(default, setter(strip_option))]
+    join: Option<Arc<ProcessNamespaces>>,
+    /// `cgroup.procs` of a cgroup the child is moved into before it continues.
+    #[builder(default, setter(strip_option, into))]
+    cgroup_procs: Option<PathBuf>,
 }
 
 impl UnshareEnvironment {
@@ -104,9 +369,53 @@ impl UnshareEnvironment {
     }
 
     pub fn write_id_maps(&self) -> Result<()> {
-        std::fs::write("/proc/self/setgroups", "deny").context("Disallowing setgroups")?;
-        write_mappings("/proc/self/uid_map", &self.uid_maps).context("Writing uid map")?;
-        write_mappings("/proc/self/gid_map", &self.gid_maps).context("Writing gid map")?;
+        self.write_id_maps_to(Path::new("/proc/self"))
+    }
+
+    /// Writes the id maps of the process whose `/proc` directory is `proc_dir`.
+    fn write_id_maps_to(&self, proc_dir: &Path) -> Result<()> {
+        // The kernel rejects empty maps, and each map can only be written once, so a
+        // missing map is left for later rather than written empty.
+        if !self.uid_maps.is_empty() {
+            write_mappings(proc_dir.join("uid_map"), &self.uid_maps).context("Writing uid map")?;
+        }
+        if !self.gid_maps.is_empty() {
+            if !self.allow_setgroups {
+                std::fs::write(proc_dir.join("setgroups"), "deny")
+                    .context("Disallowing setgroups")?;
+            }
+            write_mappings(proc_dir.join("gid_map"), &self.gid_maps).context("Writing gid map")?;
+        }
+        Ok(())
+    }
+
+    fn has_id_maps(&self) -> bool {
+        !self.uid_maps.is_empty() || !self.gid_maps.is_empty()
+    }
+
+    /// Whether an unprivileged process can write the id maps itself, which is only the
+    /// case if they consist of a single id.
+    fn has_single_id_maps(&self) -> bool {
+        [&self.uid_maps, &self.gid_maps]
+            .iter()
+            .all(|maps| maps.len() <= 1 && maps.iter().all(|map| map.count == 1))
+    }
+
+    /// Changes into the new root directory, if any.
+    ///
+    /// In a mount namespace of our own, the root is switched with `pivot_root` and the old
+    /// root is detached, so unlike with `chroot`, nothing of the host filesystem stays reachable.
+    fn enter_root(&self) -> Result<()> {
+        let Some(root) = &self.root else {
+            return Ok(());
+        };
+        if self.namespaces.contains(&UnshareNamespaces::Mount) {
+            pivot_root(root).with_context(|| format!("Pivoting root to {}", root.display()))?;
+        } else {
+            nix::unistd::chroot(root)
+                .with_context(|| format!("Chrooting to {}", root.display()))?;
+        }
+        nix::unistd::chdir("/").with_context(|| "Changing directory to /".to_string())?;
         Ok(())
     }
 }
@@ -145,58 +454,339 @@ impl UnshareEnvironmentBuilder {
         self
     }
 
+    /// Maps the current user to `uid` and `gid` inside the namespace. As this is the only mapped
+    /// id, processes in the namespace start out as that user.
+    pub fn map_current_user_to(&mut self, uid: u32, gid: u32) -> &mut Self {
+        self.uid_map(IdRangeMap {
+            outer_id_start: nix::unistd::getuid().into(),
+            inner_id_start: uid,
+            count: 1,
+        });
+        self.gid_map(IdRangeMap {
+            outer_id_start: nix::unistd::getgid().into(),
+            inner_id_start: gid,
+            count: 1,
+        });
+        self
+    }
+
     #[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
     pub fn enter(&mut self) -> Result<()> {
         let unshare = self.build().context("Building unshare options")?;
 
-        nix::sched::unshare(unshare.clone_flags()).context("Entering new namespace")?;
-        self.post_enter_setup(&unshare)?;
-        Ok(())
-    }
-
-    #[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
-    fn post_enter_setup(&self, unshare: &UnshareEnvironment) -> Result<()> {
-        if !unshare.uid_maps.is_empty() || !unshare.gid_maps.is_empty() {
-            unshare.write_id_maps().context("Writing id maps")?;
-        }
+        // Mapping more than our own id needs the setuid newuidmap/newgidmap, which has to
+        // run outside of the new namespace and is therefore started beforehand.
+        let mut helper = if unshare.has_single_id_maps() {
+            None
+        } else {
+            Some(IdMapHelper::spawn(&unshare.uid_maps, &unshare.gid_maps)?)
+        };
 
-        if let Some(root) = &unshare.root {
-            nix::unistd::chroot(root)
-                .with_context(|| format!("Chrooting to {}", root.display()))?;
-            nix::unistd::chdir("/").with_context(|| "Changing directory to /".to_string())?;
+        let clone_flags = unshare.clone_flags();
+        nix::sched::unshare(clone_flags)
+            .map_err(|e| explain_namespace_error(e, clone_flags))
+            .context("Entering new namespace")?;
+        match &mut helper {
+            Some(helper) => helper.finish()?,
+            None if unshare.has_id_maps() => unshare.write_id_maps().context("Writing id maps")?,
+            None => {}
         }
+        unshare.enter_root()?;
         Ok(())
     }
 
     #[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
-    pub fn execute(&mut self, mut f: impl FnMut() -> isize) -> Result<impl ChildProcess> {
+    pub fn execute(&mut self, mut f: impl FnMut() -> isize) -> Result<NixUnistdChild> {
         let unshare = self.build().context("Building unshare options")?;
+        // The id maps are written by us rather than the child, as only a process in the
+        // parent namespace can map more than its own id. The child waits on this pipe until
+        // the maps are in place.
+        let (maps_ready_rx, maps_ready_tx) =
+            nix::unistd::pipe2(OFlag::O_CLOEXEC).context("Creating id map pipe")?;
+        let unshare_ref = &unshare;
+        let (rx, tx) = (maps_ready_rx.as_raw_fd(), maps_ready_tx.as_raw_fd());
 
         let mut stack = vec![0u8; 1024 * 1024];
         let clone_flags = unshare.clone_flags();
         let pid = unsafe {
             nix::sched::clone(
                 Box::new(move || {
-                    if let Err(e) = self.post_enter_setup(&unshare) {
+                    _ = nix::unistd::close(tx);
+                    if let Err(e) = wait_for_byte(rx) {
+                        error!("Id maps were not set up: {e}");
+                        return -1000;
+                    }
+                    _ = nix::unistd::close(rx);
+                    if let Err(e) = unshare_ref.enter_root() {
                         error!("Post-enter setup failed: {e}");
                         return -1000;
                     }
+                    if let Some(join) = &unshare_ref.join {
+                        if let Err(e) = join.enter() {
+                            error!("Joining namespaces failed: {e}");
+                            return -1000;
+                        }
+                    }
                     f()
                 }),
                 stack.as_mut_slice(),
                 clone_flags,
-                None,
+                // Without an exit signal, the child is only waitable with __WCLONE until it calls exec.
+                Some(nix::sys::signal::Signal::SIGCHLD as i32),
             )
+            .map_err(|e| explain_namespace_error(e, clone_flags))
             .context("Entering new namespace")?
         };
+        drop(maps_ready_rx);
 
-        // Wait for 100ms to make sure any subsequent wait() calls succeed.
-        // Not sure why this is necessary.
-        std::thread::sleep(std::time::Duration::from_millis(100));
+        if unshare.has_id_maps() {
+            if let Err(e) = unshare.write_id_maps_to(&PathBuf::from(format!("/proc/{pid}"))) {
+                // Closing the pipe makes the child bail.
+                drop(maps_ready_tx);
+                _ = nix::sys::wait::waitpid(pid, None);
+                return Err(e).context("Writing id maps");
+            }
+        }
+        if let Some(cgroup_procs) = &unshare.cgroup_procs {
+            if let Err(e) = std::fs::write(cgroup_procs, pid.to_string()) {
+                drop(maps_ready_tx);
+                _ = nix::sys::wait::waitpid(pid, None);
+                return Err(e).context("Moving the child into its cgroup");
+            }
+        }
+        nix::unistd::write(&maps_ready_tx, &[1]).context("Signaling id maps to child")?;
+        drop(maps_ready_tx);
         Ok(NixUnistdChild::from(pid))
     }
 }
 
+/// Makes `root` the root mount of the current mount namespace and detaches the old one.
+fn pivot_root(root: &Path) -> Result<()> {
+    use nix::mount::{mount, umount2, MntFlags, MsFlags};
+
+    // pivot_root refuses to move mounts that propagate to another namespace.
+    mount(
+        Option::<&str>::None,
+        "/",
+        Option::<&str>::None,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        Option::<&str>::None,
+    )
+    .context("Making mounts private")?;
+    // The new root has to be a mount point. The recursive bind keeps all mounts below it
+    // and their flags, including a read-only root.
+    mount(
+        Some(root),
+        root,
+        Option::<&str>::None,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        Option::<&str>::None,
+    )
+    .context("Bind-mounting root onto itself")?;
+    nix::unistd::chdir(root).context("Changing into new root")?;
+    // Pivoting onto `.` stacks the old root on top of the new one, so the new root, which
+    // might be read-only, doesn’t need a directory to put it in.
+    nix::unistd::pivot_root(".", ".").context("Pivoting root")?;
+    umount2(".", MntFlags::MNT_DETACH).context("Detaching old root")?;
+    Ok(())
+}
+
+/// Blocks until a byte can be read from `fd`. Fails if the write end is closed first.
+pub(crate) fn wait_for_byte(fd: RawFd) -> nix::Result<()> {
+    let mut buf = [0u8; 1];
+    loop {
+        match nix::unistd::read(fd, &mut buf) {
+            Ok(1) => return Ok(()),
+            Ok(_) => return Err(Errno::EPIPE),
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A forked process that runs `newuidmap` and `newgidmap` for us once we’ve entered a
+/// new user namespace.
+///
+/// These setuid helpers map the subordinate ids from `/etc/subuid` and `/etc/subgid`. They
+/// must be started from outside the namespace, as setuid binaries have no effect inside it.
+struct IdMapHelper {
+    pid: Option<Pid>,
+    go: Option<OwnedFd>,
+}
+
+impl IdMapHelper {
+    #[instrument(level = "trace", skip_all, err(level = Level::TRACE))]
+    fn spawn(uid_maps: &IdRanges, gid_maps: &IdRanges) -> Result<Self> {
+        let uid = nix::unistd::getuid();
+        let user = nix::unistd::User::from_uid(uid)
+            .context("Looking up current user")?
+            .with_context(|| format!("User {uid} does not exist"))?;
+        check_subordinate_ids("uid", uid_maps, "/etc/subuid", uid.as_raw(), &user.name)?;
+        check_subordinate_ids(
+            "gid",
+            gid_maps,
+            "/etc/subgid",
+            nix::unistd::getgid().as_raw(),
+            &user.name,
+        )?;
+
+        let target = nix::unistd::getpid().to_string();
+        let commands = [("newuidmap", uid_maps), ("newgidmap", gid_maps)]
+            .into_iter()
+            .filter(|(_, maps)| !maps.is_empty())
+            .map(|(helper, maps)| {
+                let program = resolve_command(helper);
+                if !program.is_absolute() {
+                    bail!("{helper} not found in $PATH. It is usually provided by the uidmap or shadow package.");
+                }
+                let args = std::iter::once(helper.to_string())
+                    .chain(std::iter::once(target.clone()))
+                    .chain(maps.iter().flat_map(|map| {
+                        [map.inner_id_start, map.outer_id_start, map.count].map(|v| v.to_string())
+                    }))
+                    .map(CString::new)
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok((CString::new(program.as_os_str().as_bytes())?, args))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // Everything the forked process needs is prepared up front, so it doesn’t allocate.
+        let argvs: Vec<Vec<*const nix::libc::c_char>> = commands
+            .iter()
+            .map(|(_, args)| {
+                args.iter()
+                    .map(|arg| arg.as_ptr())
+                    .chain(std::iter::once(std::ptr::null()))
+                    .collect()
+            })
+            .collect();
+
+        let (rx, tx) = nix::unistd::pipe2(OFlag::O_CLOEXEC).context("Creating helper pipe")?;
+        // SAFETY: The child only calls async-signal-safe functions before exiting or exec’ing.
+        match unsafe { nix::unistd::fork() }.context("Forking id map helper")? {
+            ForkResult::Child => {
+                drop(tx);
+                if wait_for_byte(rx.as_raw_fd()).is_err() {
+                    unsafe { nix::libc::_exit(1) };
+                }
+                for ((program, _), argv) in commands.iter().zip(&argvs) {
+                    match unsafe { nix::unistd::fork() } {
+                        Ok(ForkResult::Child) => unsafe {
+                            nix::libc::execv(program.as_ptr(), argv.as_ptr());
+                            nix::libc::_exit(127);
+                        },
+                        Ok(ForkResult::Parent { child }) => {
+                            if !matches!(
+                                nix::sys::wait::waitpid(child, None),
+                                Ok(WaitStatus::Exited(_, 0))
+                            ) {
+                                unsafe { nix::libc::_exit(1) };
+                            }
+                        }
+                        Err(_) => unsafe { nix::libc::_exit(1) },
+                    }
+                }
+                unsafe { nix::libc::_exit(0) };
+            }
+            ForkResult::Parent { child } => Ok(IdMapHelper {
+                pid: Some(child),
+                go: Some(tx),
+            }),
+        }
+    }
+
+    /// Lets the helper write the id maps and waits for it to finish.
+    fn finish(&mut self) -> Result<()> {
+        let go = self.go.take().context("Id map helper already finished")?;
+        nix::unistd::write(&go, &[1]).context("Signaling id map helper")?;
+        drop(go);
+        let pid = self.pid.take().context("Id map helper already finished")?;
+        match nix::sys::wait::waitpid(pid, None).context("Waiting for id map helper")? {
+            WaitStatus::Exited(_, 0) => Ok(()),
+            status => bail!("Mapping ids with newuidmap/newgidmap failed: {status:?}"),
+        }
+    }
+}
+
+impl Drop for IdMapHelper {
+    fn drop(&mut self) {
+        // Without the signal, the helper exits without doing anything.
+        self.go.take();
+        if let Some(pid) = self.pid.take() {
+            _ = nix::sys::wait::waitpid(pid, None);
+        }
+    }
+}
+
+/// Makes sure every range of `maps` is either the current user’s own id or one of their
+/// subordinate ids, which are the only ids newuidmap/newgidmap will map.
+fn check_subordinate_ids(
+    kind: &str,
+    maps: &IdRanges,
+    database: &str,
+    own_id: u32,
+    user_name: &str,
+) -> Result<()> {
+    let contents = match std::fs::read_to_string(database) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Reading {database}")),
+    };
+    let own_id_str = own_id.to_string();
+    let ranges: Vec<(u64, u64)> = contents
+        .lines()
+        .filter_map(|line| {
+            let &[owner, start, count] = line.split(':').collect::<Vec<_>>().as_slice() else {
+                return None;
+            };
+            if owner != user_name && owner != own_id_str {
+                return None;
+            }
+            Some((start.parse().ok()?, count.parse().ok()?))
+        })
+        .collect();
+
+    for map in maps.iter() {
+        let (start, count) = (u64::from(map.outer_id_start), u64::from(map.count));
+        if map.outer_id_start == own_id && map.count == 1 {
+            continue;
+        }
+        if !ranges.iter().any(|&(sub_start, sub_count)| {
+            start >= sub_start && start + count <= sub_start + sub_count
+        }) {
+            bail!(
+                "The {kind} map {} maps host {kind}s {start}-{} which are not assigned to {user_name} in {database}",
+                map.serialize(),
+                start + count - 1
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Turns the errors the kernel returns when unprivileged user namespaces are restricted
+/// into something actionable.
+fn explain_namespace_error(err: Errno, flags: CloneFlags) -> anyhow::Error {
+    if !flags.contains(CloneFlags::CLONE_NEWUSER) {
+        return err.into();
+    }
+    match err {
+        Errno::EPERM => anyhow::anyhow!(
+            "{err}: creating a user namespace was denied. Unprivileged user namespaces are probably \
+            disabled on this system. On Debian, enable them with `sysctl kernel.unprivileged_userns_clone=1`. \
+            On Ubuntu 23.10 and later, AppArmor restricts them; either add an AppArmor profile for \
+            containix or run `sysctl kernel.apparmor_restrict_unprivileged_userns=0`."
+        )
+        .context(ContainixError::PermissionDenied),
+        // Not a permission problem, so it’s left to the caller to classify.
+        Errno::ENOSPC => anyhow::anyhow!(
+            "{err}: the limit on user namespaces has been reached. Check `sysctl user.max_user_namespaces`, \
+            it has to be greater than 0."
+        ),
+        err => err.into(),
+    }
+}
+
+/// Writes `mappings` to an id map file. The kernel only accepts a map in a single write.
 fn write_mappings(p: impl AsRef<Path>, mappings: &IdRanges) -> Result<()> {
     let mut file = std::fs::OpenOptions::new()
         .write(true)
@@ -207,3 +797,128 @@ fn write_mappings(p: impl AsRef<Path>, mappings: &IdRanges) -> Result<()> {
     mappings.write_to(&mut file).context("Writing mapping")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    /// Records each write separately.
+    #[derive(Default)]
+    struct Writes(Vec<Vec<u8>>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn serializes_id_ranges() {
+        let cases: [(&[IdRangeMap], &str); 2] = [
+            (&[IdRangeMap::new(0, 1000, 1)], "0 1000 1"),
+            (
+                &[
+                    IdRangeMap::new(0, 1000, 1),
+                    IdRangeMap::new(1, 100000, 65536),
+                ],
+                "0 1000 1\n1 100000 65536",
+            ),
+        ];
+        for (maps, expected) in cases {
+            let ranges = IdRanges(maps.to_vec());
+            assert_eq!(ranges.serialize(), expected);
+            let mut writes = Writes::default();
+            ranges.write_to(&mut writes).unwrap();
+            assert_eq!(writes.0, [expected.as_bytes()], "{expected}");
+        }
+    }
+
+    #[test]
+    fn skips_empty_id_maps() {
+        let uid_map = IdRangeMap::new(0, 1000, 1);
+        let gid_map = IdRangeMap::new(0, 100, 1);
+        let cases = [
+            (Some(&uid_map), None, "0 1000 1", "", false),
+            (None, Some(&gid_map), "", "0 100 1", true),
+            (Some(&uid_map), Some(&gid_map), "0 1000 1", "0 100 1", true),
+            (None, None, "", "", false),
+        ];
+        for (uid, gid, expected_uid_map, expected_gid_map, setgroups) in cases {
+            let proc_dir = TempDir::new().unwrap();
+            proc_dir.create().unwrap();
+            // Like in `/proc`, the map files exist and can’t be created.
+            std::fs::write(proc_dir.join("uid_map"), "").unwrap();
+            std::fs::write(proc_dir.join("gid_map"), "").unwrap();
+
+            let mut unshare = UnshareEnvironmentBuilder::default();
+            if let Some(uid) = uid {
+                unshare.uid_map(uid.clone());
+            }
+            if let Some(gid) = gid {
+                unshare.gid_map(gid.clone());
+            }
+            unshare
+                .build()
+                .unwrap()
+                .write_id_maps_to(&proc_dir)
+                .unwrap();
+
+            let read = |name| std::fs::read_to_string(proc_dir.join(name)).unwrap();
+            assert_eq!(read("uid_map"), expected_uid_map);
+            assert_eq!(read("gid_map"), expected_gid_map);
+            assert_eq!(proc_dir.join("setgroups").exists(), setgroups);
+        }
+    }
+
+    #[test]
+    fn forks_into_pid_namespace() {
+        use std::os::unix::process::CommandExt;
+        use std::process::Command;
+
+        let Ok(mut holder) = Command::new("unshare")
+            .args(["--user", "--pid", "--fork", "sleep", "10"])
+            .spawn()
+        else {
+            // Without unshare(1) there is no PID namespace to join, nothing to test.
+            return;
+        };
+        let children = format!("/proc/{0}/task/{0}/children", holder.id());
+        let pid = (0..100)
+            .find_map(|_| {
+                let pid = std::fs::read_to_string(&children).ok()?.trim().parse().ok();
+                if pid.is_none() {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                pid
+            })
+            .expect("unshare started no child");
+        let namespaces = ProcessNamespaces::open(
+            pid,
+            [UnshareNamespaces::User, UnshareNamespaces::Pid],
+            false,
+        )
+        .unwrap();
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo $$; exit 3"]);
+        // SAFETY: see `ProcessNamespaces::enter` and `fork_into_pid_namespace`.
+        unsafe {
+            cmd.pre_exec(move || {
+                namespaces.enter()?;
+                fork_into_pid_namespace()?;
+                Ok(())
+            });
+        }
+        let output = cmd.output().unwrap();
+        _ = holder.kill();
+        _ = holder.wait();
+        // `sleep` is PID 1 in the namespace.
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "2");
+        assert_eq!(output.status.code(), Some(3));
+    }
+}
@@ -0,0 +1,143 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// A user or group given either by numeric id or by name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IdOrName {
+    Id(u32),
+    Name(String),
+}
+
+impl FromStr for IdOrName {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            bail!("User and group must not be empty");
+        }
+        Ok(s.parse()
+            .map(IdOrName::Id)
+            .unwrap_or_else(|_| IdOrName::Name(s.to_string())))
+    }
+}
+
+impl fmt::Display for IdOrName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdOrName::Id(id) => write!(f, "{id}"),
+            IdOrName::Name(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// The user to run the container command as, in the form `USER[:GROUP]`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserSpec {
+    pub user: IdOrName,
+    pub group: Option<IdOrName>,
+}
+
+impl FromStr for UserSpec {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (user, group) = match s.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (s, None),
+        };
+        Ok(UserSpec {
+            user: user.parse()?,
+            group: group.map(str::parse).transpose()?,
+        })
+    }
+}
+
+impl fmt::Display for UserSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.user)?;
+        if let Some(group) = &self.group {
+            write!(f, ":{group}")?;
+        }
+        Ok(())
+    }
+}
+
+impl UserSpec {
+    /// The uid and gid if both are numeric and don’t need to be looked up, with the same
+    /// default group as [`resolve`](Self::resolve).
+    pub fn ids(&self) -> Option<(u32, u32)> {
+        let IdOrName::Id(uid) = self.user else {
+            return None;
+        };
+        match &self.group {
+            None => Some((uid, 0)),
+            Some(IdOrName::Id(gid)) => Some((uid, *gid)),
+            Some(IdOrName::Name(_)) => None,
+        }
+    }
+
+    /// Resolves names against `/etc/passwd` and `/etc/group` inside `root` and returns the uid and gid.
+    ///
+    /// Numeric ids are used as-is. Without a group, a user given by name gets its primary group
+    /// and a numeric user gets gid 0.
+    pub fn resolve(&self, root: &Path) -> Result<(u32, u32)> {
+        let (uid, primary_gid) = match &self.user {
+            IdOrName::Id(uid) => (*uid, 0),
+            IdOrName::Name(name) => {
+                let entry = find_entry(root, "etc/passwd", name)?
+                    .with_context(|| format!("User {name} not found in the container"))?;
+                (parse_id(&entry, 2)?, parse_id(&entry, 3)?)
+            }
+        };
+        let gid = match &self.group {
+            None => primary_gid,
+            Some(IdOrName::Id(gid)) => *gid,
+            Some(IdOrName::Name(name)) => {
+                let entry = find_entry(root, "etc/group", name)?
+                    .with_context(|| format!("Group {name} not found in the container"))?;
+                parse_id(&entry, 2)?
+            }
+        };
+        Ok((uid, gid))
+    }
+}
+
+/// Renders a minimal `/etc/passwd` with root and, if given, an entry for `user` (uid, gid).
+pub fn render_passwd(user: Option<(u32, u32)>) -> String {
+    let mut passwd = String::from("root:x:0:0:root:/root:/bin/sh\n");
+    if let Some((uid, gid)) = user.filter(|(uid, _)| *uid != 0) {
+        passwd.push_str(&format!("user:x:{uid}:{gid}:user:/:/bin/sh\n"));
+    }
+    passwd
+}
+
+/// Renders a minimal `/etc/group` with root’s group and, if given, the user’s group.
+pub fn render_group(user: Option<(u32, u32)>) -> String {
+    let mut group = String::from("root:x:0:\n");
+    if let Some((_, gid)) = user.filter(|(_, gid)| *gid != 0) {
+        group.push_str(&format!("user:x:{gid}:\n"));
+    }
+    group
+}
+
+/// Finds the line for `name` in a passwd-style database inside `root`.
+fn find_entry(root: &Path, database: &str, name: &str) -> Result<Option<Vec<String>>> {
+    let path = root.join(database);
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Reading /{database} in the container")),
+    };
+    Ok(contents
+        .lines()
+        .map(|line| line.split(':').map(str::to_string).collect::<Vec<_>>())
+        .find(|fields| fields.first().is_some_and(|field| field == name)))
+}
+
+fn parse_id(entry: &[String], index: usize) -> Result<u32> {
+    entry
+        .get(index)
+        .with_context(|| format!("Malformed entry for {}", entry[0]))?
+        .parse()
+        .with_context(|| format!("Invalid id in entry for {}", entry[0]))
+}
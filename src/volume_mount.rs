@@ -3,13 +3,18 @@ use std::{
     str::FromStr,
 };
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+use crate::path_ext::PathExt;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VolumeMount {
     pub host_path: PathBuf,
     pub container_path: PathBuf,
     pub read_only: bool,
+    /// Copy the host path into the container instead of bind-mounting it.
+    pub copy: bool,
 }
 
 impl VolumeMount {
@@ -18,6 +23,7 @@ impl VolumeMount {
             host_path: host_path.as_ref().to_path_buf(),
             container_path: container_path.as_ref().to_path_buf(),
             read_only: true,
+            copy: false,
         }
     }
 }
@@ -34,11 +40,228 @@ impl FromStr for VolumeMount {
             .split_once(':')
             .unwrap_or((container_path, ""));
         let options: Vec<_> = options.split(',').collect();
-        let read_only = options.iter().any(|option| *option == "ro");
+        let read_only = options.contains(&"ro");
+        let copy = options.contains(&"copy");
+        if read_only && copy {
+            anyhow::bail!("Volume mount options ro and copy can’t be combined, got: {s}");
+        }
         Ok(VolumeMount {
             host_path: host_path.into(),
-            container_path: container_path.into(),
+            container_path: Path::new(container_path).normalize_container_path()?,
             read_only,
+            copy,
         })
     }
 }
+
+/// A mount given as comma-separated options, e.g. `type=tmpfs,target=/tmp,size=64m` or
+/// `type=bind,source=/srv/data,target=/data,ro`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Mount {
+    /// A host path, like a [`VolumeMount`].
+    Bind(VolumeMount),
+    /// An empty tmpfs. `size` is passed on as-is, e.g. `64m` or `10%`.
+    Tmpfs {
+        target: PathBuf,
+        size: Option<String>,
+        mode: Option<u32>,
+    },
+    /// A host directory that is writable in the container. Changes are discarded with the container.
+    Overlay { source: PathBuf, target: PathBuf },
+}
+
+impl Mount {
+    pub fn target(&self) -> &Path {
+        match self {
+            Mount::Bind(volume) => &volume.container_path,
+            Mount::Tmpfs { target, .. } | Mount::Overlay { target, .. } => target,
+        }
+    }
+}
+
+impl FromStr for Mount {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let mut kind = None;
+        let mut source = None;
+        let mut target = None;
+        let mut read_only = false;
+        let mut size = None;
+        let mut mode = None;
+        for option in s.split(',') {
+            match option.split_once('=').unwrap_or((option, "")) {
+                ("type", value) => kind = Some(value),
+                ("source" | "src", "") => bail!("Mount {s} is invalid, its source is empty"),
+                ("source" | "src", value) => source = Some(PathBuf::from(value)),
+                ("target" | "destination" | "dst", value) => {
+                    target = Some(Path::new(value).normalize_container_path()?)
+                }
+                ("ro" | "readonly", "" | "true") => read_only = true,
+                ("ro" | "readonly", "false") => read_only = false,
+                ("size", value) => {
+                    let digits = value.trim_end_matches(['k', 'm', 'g', 'K', 'M', 'G', '%']);
+                    if digits.is_empty()
+                        || value.len() - digits.len() > 1
+                        || !digits.bytes().all(|b| b.is_ascii_digit())
+                    {
+                        bail!("Invalid tmpfs size `{value}`, expected e.g. 65536, 64m or 10%");
+                    }
+                    size = Some(value.to_string());
+                }
+                ("mode", value) => {
+                    let parsed = u32::from_str_radix(value, 8)
+                        .with_context(|| format!("Invalid octal mode `{value}`"))?;
+                    if parsed > 0o7777 {
+                        bail!("Invalid mode `{value}`, it can’t be more than 7777");
+                    }
+                    mode = Some(parsed);
+                }
+                _ => bail!("Unknown mount option `{option}` in {s}"),
+            }
+        }
+        let target = target.with_context(|| format!("Mount {s} has no target"))?;
+        let tmpfs_options = size.is_some() || mode.is_some();
+        match kind {
+            Some("bind") => {
+                if tmpfs_options {
+                    bail!("Bind mount {s} can’t have tmpfs options");
+                }
+                Ok(Mount::Bind(VolumeMount {
+                    host_path: source.with_context(|| format!("Bind mount {s} has no source"))?,
+                    container_path: target,
+                    read_only,
+                    copy: false,
+                }))
+            }
+            Some("tmpfs") => {
+                if source.is_some() || read_only {
+                    bail!("Tmpfs mount {s} can’t have a source or be read-only");
+                }
+                Ok(Mount::Tmpfs { target, size, mode })
+            }
+            Some("overlay") => {
+                if tmpfs_options || read_only {
+                    bail!("Overlay mount {s} can’t have tmpfs options or be read-only");
+                }
+                Ok(Mount::Overlay {
+                    source: source.with_context(|| format!("Overlay mount {s} has no source"))?,
+                    target,
+                })
+            }
+            Some(kind) => bail!("Unknown mount type {kind}, expected bind, tmpfs or overlay"),
+            None => bail!("Mount {s} has no type"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bind_mounts() {
+        let Mount::Bind(volume) = "type=bind,source=/srv/data,target=/data".parse().unwrap() else {
+            panic!("Expected a bind mount");
+        };
+        assert_eq!(volume.host_path, Path::new("/srv/data"));
+        assert_eq!(volume.container_path, Path::new("/data"));
+        assert!(!volume.read_only);
+        assert!(!volume.copy);
+
+        for options in ["ro", "readonly", "ro=true", "readonly=true"] {
+            let input = format!("type=bind,src=/srv/data,dst=/data/,{options}");
+            let Mount::Bind(volume) = input.parse().unwrap() else {
+                panic!("Expected a bind mount for {input}");
+            };
+            assert!(volume.read_only, "{input}");
+            assert_eq!(volume.container_path, Path::new("/data"));
+        }
+        let Mount::Bind(volume) = "type=bind,source=/a,destination=/b,ro=false"
+            .parse()
+            .unwrap()
+        else {
+            panic!("Expected a bind mount");
+        };
+        assert!(!volume.read_only);
+    }
+
+    #[test]
+    fn parses_tmpfs_mounts() {
+        let cases = [
+            ("type=tmpfs,target=/tmp", None, None),
+            ("type=tmpfs,target=/tmp,size=65536", Some("65536"), None),
+            ("type=tmpfs,target=/tmp,size=64m", Some("64m"), None),
+            ("type=tmpfs,target=/tmp,size=1G", Some("1G"), None),
+            ("type=tmpfs,target=/tmp,size=10%", Some("10%"), None),
+            ("type=tmpfs,target=/tmp,mode=1777", None, Some(0o1777)),
+            (
+                "type=tmpfs,target=/tmp,size=8k,mode=700",
+                Some("8k"),
+                Some(0o700),
+            ),
+        ];
+        for (input, expected_size, expected_mode) in cases {
+            let Mount::Tmpfs { target, size, mode } = input.parse().unwrap() else {
+                panic!("Expected a tmpfs mount for {input}");
+            };
+            assert_eq!(target, Path::new("/tmp"), "{input}");
+            assert_eq!(size.as_deref(), expected_size, "{input}");
+            assert_eq!(mode, expected_mode, "{input}");
+        }
+    }
+
+    #[test]
+    fn parses_overlay_mounts() {
+        let Mount::Overlay { source, target } =
+            "type=overlay,source=./data,target=/data".parse().unwrap()
+        else {
+            panic!("Expected an overlay mount");
+        };
+        assert_eq!(source, Path::new("./data"));
+        assert_eq!(target, Path::new("/data"));
+    }
+
+    #[test]
+    fn rejects_invalid_mounts() {
+        let cases = [
+            ("type=bind,source=/a", "has no target"),
+            (
+                "type=bind,target=/a",
+                "Bind mount type=bind,target=/a has no source",
+            ),
+            ("type=overlay,target=/a", "has no source"),
+            ("type=bind,source=,target=/a", "source is empty"),
+            (
+                "type=bind,source=/a,target=/b,size=64m",
+                "can’t have tmpfs options",
+            ),
+            (
+                "type=bind,source=/a,target=/b,mode=755",
+                "can’t have tmpfs options",
+            ),
+            (
+                "type=overlay,source=/a,target=/b,size=64m",
+                "can’t have tmpfs options",
+            ),
+            ("type=overlay,source=/a,target=/b,ro", "be read-only"),
+            ("type=tmpfs,source=/a,target=/b", "can’t have a source"),
+            ("type=tmpfs,target=/b,ro", "be read-only"),
+            ("type=tmpfs,target=/b,size=", "Invalid tmpfs size"),
+            ("type=tmpfs,target=/b,size=m", "Invalid tmpfs size"),
+            ("type=tmpfs,target=/b,size=64mb", "Invalid tmpfs size"),
+            ("type=tmpfs,target=/b,size=-1", "Invalid tmpfs size"),
+            ("type=tmpfs,target=/b,mode=888", "Invalid octal mode"),
+            ("type=tmpfs,target=/b,mode=rwx", "Invalid octal mode"),
+            ("type=tmpfs,target=/b,mode=10000", "can’t be more than 7777"),
+            ("type=tmpfs,target=/b,mode=40755", "can’t be more than 7777"),
+            ("type=volume,target=/a", "Unknown mount type volume"),
+            ("target=/a", "has no type"),
+            ("type=tmpfs,target=/a,uid=0", "Unknown mount option `uid=0`"),
+            ("type=tmpfs,target=relative", "must be absolute"),
+        ];
+        for (input, expected) in cases {
+            let err = input.parse::<Mount>().unwrap_err().to_string();
+            assert!(err.contains(expected), "{input}: {err}");
+        }
+    }
+}